@@ -7,4 +7,6 @@ fn main() {
     println!(
         "cargo:rustc-link-lib=dylib=pq\ncargo:rustc-link-search=native=/Users/Shared/Software/Postgresql13/lib/\n"
     );
+    // embedded migrations have to be rebuilt when a new migration appears:
+    println!("cargo:rerun-if-changed=migrations");
 }
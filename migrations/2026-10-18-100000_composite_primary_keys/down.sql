@@ -0,0 +1,19 @@
+-- This file should undo anything in `up.sql`
+-- NOTE: restoring the "time" primary keys fails once snapshots share the time
+ALTER TABLE net_stats DROP CONSTRAINT net_stats_pkey;
+ALTER TABLE net_stats ADD PRIMARY KEY (time);
+ALTER TABLE net_stats DROP IF EXISTS host_name;
+
+ALTER TABLE proc_stats DROP CONSTRAINT proc_stats_pkey;
+ALTER TABLE proc_stats ADD PRIMARY KEY (time);
+ALTER TABLE proc_stats ALTER COLUMN host_name DROP NOT NULL;
+ALTER TABLE proc_stats DROP IF EXISTS pid;
+
+ALTER TABLE disk_stats DROP CONSTRAINT disk_stats_pkey;
+ALTER TABLE disk_stats ADD PRIMARY KEY (time);
+ALTER TABLE disk_stats ALTER COLUMN name DROP NOT NULL;
+ALTER TABLE disk_stats ALTER COLUMN host_name DROP NOT NULL;
+
+ALTER TABLE sys_stats DROP CONSTRAINT sys_stats_pkey;
+ALTER TABLE sys_stats ADD PRIMARY KEY (time);
+ALTER TABLE sys_stats ALTER COLUMN host_name DROP NOT NULL;
@@ -0,0 +1,25 @@
+-- Composite primary keys: rows of a single snapshot share one collection timestamp
+
+UPDATE sys_stats SET host_name = '' WHERE host_name IS NULL;
+ALTER TABLE sys_stats ALTER COLUMN host_name SET NOT NULL;
+ALTER TABLE sys_stats DROP CONSTRAINT sys_stats_pkey;
+ALTER TABLE sys_stats ADD PRIMARY KEY (time, host_name);
+
+UPDATE disk_stats SET host_name = '' WHERE host_name IS NULL;
+UPDATE disk_stats SET name = '' WHERE name IS NULL;
+ALTER TABLE disk_stats ALTER COLUMN host_name SET NOT NULL;
+ALTER TABLE disk_stats ALTER COLUMN name SET NOT NULL;
+ALTER TABLE disk_stats DROP CONSTRAINT disk_stats_pkey;
+ALTER TABLE disk_stats ADD PRIMARY KEY (time, host_name, name);
+
+ALTER TABLE proc_stats ADD COLUMN pid INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE proc_stats ALTER COLUMN pid DROP DEFAULT;
+UPDATE proc_stats SET host_name = '' WHERE host_name IS NULL;
+ALTER TABLE proc_stats ALTER COLUMN host_name SET NOT NULL;
+ALTER TABLE proc_stats DROP CONSTRAINT proc_stats_pkey;
+ALTER TABLE proc_stats ADD PRIMARY KEY (time, host_name, pid);
+
+ALTER TABLE net_stats ADD COLUMN host_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE net_stats ALTER COLUMN host_name DROP DEFAULT;
+ALTER TABLE net_stats DROP CONSTRAINT net_stats_pkey;
+ALTER TABLE net_stats ADD PRIMARY KEY (time, host_name, netdev);
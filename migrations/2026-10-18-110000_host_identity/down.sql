@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS net_stats_host_name_time_idx;
+DROP INDEX IF EXISTS proc_stats_host_name_time_idx;
+DROP INDEX IF EXISTS disk_stats_host_name_time_idx;
+DROP INDEX IF EXISTS ups_stats_host_name_time_idx;
+DROP INDEX IF EXISTS sys_stats_host_name_time_idx;
+
+ALTER TABLE ups_stats DROP CONSTRAINT ups_stats_pkey;
+ALTER TABLE ups_stats ADD PRIMARY KEY (time);
+ALTER TABLE ups_stats DROP IF EXISTS host_name;
@@ -0,0 +1,12 @@
+-- Host identity on every table, indexed for per-host queries
+
+ALTER TABLE ups_stats ADD COLUMN host_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE ups_stats ALTER COLUMN host_name DROP DEFAULT;
+ALTER TABLE ups_stats DROP CONSTRAINT ups_stats_pkey;
+ALTER TABLE ups_stats ADD PRIMARY KEY (time, host_name);
+
+CREATE INDEX sys_stats_host_name_time_idx ON sys_stats (host_name, time DESC);
+CREATE INDEX ups_stats_host_name_time_idx ON ups_stats (host_name, time DESC);
+CREATE INDEX disk_stats_host_name_time_idx ON disk_stats (host_name, time DESC);
+CREATE INDEX proc_stats_host_name_time_idx ON proc_stats (host_name, time DESC);
+CREATE INDEX net_stats_host_name_time_idx ON net_stats (host_name, time DESC);
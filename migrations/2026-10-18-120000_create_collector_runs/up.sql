@@ -0,0 +1,17 @@
+-- Self-monitoring: one row per collector run
+CREATE TABLE collector_runs (
+   time             TIMESTAMP         NOT NULL,
+   host_name        TEXT              NOT NULL,
+   collector        TEXT              NOT NULL,
+
+   duration_ms      DOUBLE PRECISION  NOT NULL,
+   rows_produced    INTEGER           NOT NULL,
+   rows_written     INTEGER           NULL,
+   error            TEXT              NULL,
+
+   PRIMARY KEY (time, host_name, collector)
+);
+
+SELECT create_hypertable('collector_runs', 'time');
+
+CREATE INDEX collector_runs_host_name_time_idx ON collector_runs (host_name, time DESC);
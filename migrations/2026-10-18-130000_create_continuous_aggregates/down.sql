@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+-- Refresh policies are dropped with the aggregates
+DROP MATERIALIZED VIEW IF EXISTS sys_stats_hourly;
+DROP MATERIALIZED VIEW IF EXISTS sys_stats_daily;
+DROP MATERIALIZED VIEW IF EXISTS ups_stats_hourly;
+DROP MATERIALIZED VIEW IF EXISTS ups_stats_daily;
+DROP MATERIALIZED VIEW IF EXISTS disk_stats_hourly;
+DROP MATERIALIZED VIEW IF EXISTS disk_stats_daily;
+DROP MATERIALIZED VIEW IF EXISTS net_stats_hourly;
+DROP MATERIALIZED VIEW IF EXISTS net_stats_daily;
+-- created by the agent, when the percentiles are enabled:
+DROP MATERIALIZED VIEW IF EXISTS sys_stats_hourly_percentiles;
+DROP MATERIALIZED VIEW IF EXISTS sys_stats_daily_percentiles;
+DROP MATERIALIZED VIEW IF EXISTS ups_stats_hourly_percentiles;
+DROP MATERIALIZED VIEW IF EXISTS ups_stats_daily_percentiles;
+DROP MATERIALIZED VIEW IF EXISTS disk_stats_hourly_percentiles;
+DROP MATERIALIZED VIEW IF EXISTS disk_stats_daily_percentiles;
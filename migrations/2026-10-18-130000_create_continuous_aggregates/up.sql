@@ -0,0 +1,213 @@
+-- Hourly and daily rollups per host, maintained by TimescaleDB continuous aggregates.
+--
+-- Percentiles need the timescaledb_toolkit extension, so they're kept in their own
+-- "*_percentiles" aggregates, created by the agent when "sinks.postgres.percentiles" is set.
+--
+-- Refreshing a bucket whose raw rows were dropped empties it, so retention
+-- of the aggregated tables must be longer than the refresh windows (3 days).
+-- Buckets not materialized yet are computed from the raw rows at query time.
+
+CREATE MATERIALIZED VIEW sys_stats_hourly
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 hour', time) AS bucket,
+    host_name,
+    count(*) AS samples,
+    min(cpu_usage) AS cpu_usage_min,
+    avg(cpu_usage) AS cpu_usage_avg,
+    max(cpu_usage) AS cpu_usage_max,
+    max(total_memory) AS total_memory,
+    min(used_memory) AS used_memory_min,
+    avg(used_memory) AS used_memory_avg,
+    max(used_memory) AS used_memory_max,
+    max(total_swap) AS total_swap,
+    avg(used_swap) AS used_swap_avg,
+    max(used_swap) AS used_swap_max,
+    min(load_one) AS load_one_min,
+    avg(load_one) AS load_one_avg,
+    max(load_one) AS load_one_max,
+    avg(load_five) AS load_five_avg,
+    avg(load_fifteen) AS load_fifteen_avg
+FROM sys_stats
+GROUP BY bucket, host_name
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW sys_stats_daily
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 day', time) AS bucket,
+    host_name,
+    count(*) AS samples,
+    min(cpu_usage) AS cpu_usage_min,
+    avg(cpu_usage) AS cpu_usage_avg,
+    max(cpu_usage) AS cpu_usage_max,
+    max(total_memory) AS total_memory,
+    min(used_memory) AS used_memory_min,
+    avg(used_memory) AS used_memory_avg,
+    max(used_memory) AS used_memory_max,
+    max(total_swap) AS total_swap,
+    avg(used_swap) AS used_swap_avg,
+    max(used_swap) AS used_swap_max,
+    min(load_one) AS load_one_min,
+    avg(load_one) AS load_one_avg,
+    max(load_one) AS load_one_max,
+    avg(load_five) AS load_five_avg,
+    avg(load_fifteen) AS load_fifteen_avg
+FROM sys_stats
+GROUP BY bucket, host_name
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW ups_stats_hourly
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 hour', time) AS bucket,
+    host_name,
+    count(*) AS samples,
+    count(*) FILTER (WHERE status LIKE '%OB%') AS on_battery_samples,
+    min(load) AS load_min,
+    avg(load) AS load_avg,
+    max(load) AS load_max,
+    min(battery_charge) AS battery_charge_min,
+    avg(battery_charge) AS battery_charge_avg,
+    max(battery_charge) AS battery_charge_max,
+    min(battery_voltage) AS battery_voltage_min,
+    avg(battery_voltage) AS battery_voltage_avg,
+    max(battery_voltage) AS battery_voltage_max,
+    min(input_voltage) AS input_voltage_min,
+    avg(input_voltage) AS input_voltage_avg,
+    max(input_voltage) AS input_voltage_max,
+    avg(input_frequency) AS input_frequency_avg
+FROM ups_stats
+GROUP BY bucket, host_name
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW ups_stats_daily
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 day', time) AS bucket,
+    host_name,
+    count(*) AS samples,
+    count(*) FILTER (WHERE status LIKE '%OB%') AS on_battery_samples,
+    min(load) AS load_min,
+    avg(load) AS load_avg,
+    max(load) AS load_max,
+    min(battery_charge) AS battery_charge_min,
+    avg(battery_charge) AS battery_charge_avg,
+    max(battery_charge) AS battery_charge_max,
+    min(battery_voltage) AS battery_voltage_min,
+    avg(battery_voltage) AS battery_voltage_avg,
+    max(battery_voltage) AS battery_voltage_max,
+    min(input_voltage) AS input_voltage_min,
+    avg(input_voltage) AS input_voltage_avg,
+    max(input_voltage) AS input_voltage_max,
+    avg(input_frequency) AS input_frequency_avg
+FROM ups_stats
+GROUP BY bucket, host_name
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW disk_stats_hourly
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 hour', time) AS bucket,
+    host_name,
+    name,
+    count(*) AS samples,
+    min(temperature) AS temperature_min,
+    avg(temperature) AS temperature_avg,
+    max(temperature) AS temperature_max
+FROM disk_stats
+GROUP BY bucket, host_name, name
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW disk_stats_daily
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 day', time) AS bucket,
+    host_name,
+    name,
+    count(*) AS samples,
+    min(temperature) AS temperature_min,
+    avg(temperature) AS temperature_avg,
+    max(temperature) AS temperature_max
+FROM disk_stats
+GROUP BY bucket, host_name, name
+WITH NO DATA;
+
+-- byte and packet amounts are deltas since the previous sample,
+-- so their sums are the amounts of the bucket, and rates are in units per second:
+CREATE MATERIALIZED VIEW net_stats_hourly
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 hour', time) AS bucket,
+    host_name,
+    netdev,
+    count(*) AS samples,
+    sum(received) AS received,
+    sum(transmitted) AS transmitted,
+    sum(received) / 3600 AS received_rate,
+    sum(transmitted) / 3600 AS transmitted_rate,
+    max(received) AS received_max,
+    max(transmitted) AS transmitted_max,
+    sum(packets_received) AS packets_received,
+    sum(packets_transmitted) AS packets_transmitted,
+    sum(received_errors) AS received_errors,
+    sum(transmitted_errors) AS transmitted_errors
+FROM net_stats
+GROUP BY bucket, host_name, netdev
+WITH NO DATA;
+
+CREATE MATERIALIZED VIEW net_stats_daily
+WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+SELECT
+    time_bucket(INTERVAL '1 day', time) AS bucket,
+    host_name,
+    netdev,
+    count(*) AS samples,
+    sum(received) AS received,
+    sum(transmitted) AS transmitted,
+    sum(received) / 86400 AS received_rate,
+    sum(transmitted) / 86400 AS transmitted_rate,
+    max(received) AS received_max,
+    max(transmitted) AS transmitted_max,
+    sum(packets_received) AS packets_received,
+    sum(packets_transmitted) AS packets_transmitted,
+    sum(received_errors) AS received_errors,
+    sum(transmitted_errors) AS transmitted_errors
+FROM net_stats
+GROUP BY bucket, host_name, netdev
+WITH NO DATA;
+
+-- the most recent hour is left to the real-time aggregation:
+SELECT add_continuous_aggregate_policy('sys_stats_hourly',
+    start_offset => INTERVAL '3 hours',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '30 minutes');
+SELECT add_continuous_aggregate_policy('ups_stats_hourly',
+    start_offset => INTERVAL '3 hours',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '30 minutes');
+SELECT add_continuous_aggregate_policy('disk_stats_hourly',
+    start_offset => INTERVAL '3 hours',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '30 minutes');
+SELECT add_continuous_aggregate_policy('net_stats_hourly',
+    start_offset => INTERVAL '3 hours',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '30 minutes');
+
+SELECT add_continuous_aggregate_policy('sys_stats_daily',
+    start_offset => INTERVAL '3 days',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '1 hour');
+SELECT add_continuous_aggregate_policy('ups_stats_daily',
+    start_offset => INTERVAL '3 days',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '1 hour');
+SELECT add_continuous_aggregate_policy('disk_stats_daily',
+    start_offset => INTERVAL '3 days',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '1 hour');
+SELECT add_continuous_aggregate_policy('net_stats_daily',
+    start_offset => INTERVAL '3 days',
+    end_offset => INTERVAL '1 hour',
+    schedule_interval => INTERVAL '1 hour');
@@ -0,0 +1,18 @@
+-- History of the alerts: one row per firing or resolved alert
+CREATE TABLE alerts (
+   time             TIMESTAMP         NOT NULL,
+   host_name        TEXT              NOT NULL,
+   rule             TEXT              NOT NULL,
+   instance         TEXT              NOT NULL,
+
+   state            TEXT              NOT NULL,
+   severity         TEXT              NOT NULL,
+   value            DOUBLE PRECISION  NULL,
+   message          TEXT              NOT NULL,
+
+   PRIMARY KEY (time, host_name, rule, instance)
+);
+
+SELECT create_hypertable('alerts', 'time');
+
+CREATE INDEX alerts_host_name_time_idx ON alerts (host_name, time DESC);
@@ -0,0 +1,87 @@
+use crate::{error::Error, *};
+use diesel::{
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{Bool, Text},
+};
+
+
+/// Rollups of the percentiles: suffix, bucket width, start of the refresh window and
+/// the refresh schedule. The same as of the min/avg/max continuous aggregates
+const ROLLUPS: [(&str, &str, &str, &str); 2] = [
+    ("hourly", "1 hour", "3 hours", "30 minutes"),
+    ("daily", "1 day", "3 days", "1 hour"),
+];
+
+
+/// Aggregated tables: grouping columns besides the bucket, and the columns of the percentiles
+const PERCENTILES: [(&str, &str, &[&str]); 3] = [
+    (
+        "sys_stats",
+        "host_name",
+        &["cpu_usage", "used_memory", "load_one"],
+    ),
+    ("ups_stats", "host_name", &["load", "battery_charge"]),
+    ("disk_stats", "host_name, name", &["temperature"]),
+];
+
+
+/// Result of the existence check
+#[derive(Debug, Clone, Copy, QueryableByName)]
+struct Exists {
+    #[diesel(sql_type = Bool)]
+    exists: bool,
+}
+
+
+/// Create the missing "*_percentiles" continuous aggregates with their refresh policies,
+/// in a single transaction. Their columns hold percentile_agg() sketches, queried like:
+/// "SELECT bucket, approx_percentile(0.95, cpu_usage) FROM sys_stats_hourly_percentiles".
+/// Requires the timescaledb_toolkit extension, created if it's missing.
+/// Returns the created aggregates
+#[instrument(skip(pg_connection))]
+pub fn create_percentiles(pg_connection: &mut PgConnection) -> Result<Vec<String>, Error> {
+    pg_connection.transaction(|pg_connection| {
+        diesel::sql_query("CREATE EXTENSION IF NOT EXISTS timescaledb_toolkit")
+            .execute(pg_connection)?;
+        let mut created = vec![];
+        for (table, group_by, columns) in PERCENTILES {
+            for (suffix, width, start_offset, schedule_interval) in ROLLUPS {
+                let view = format!("{table}_{suffix}_percentiles");
+                let exists: Exists =
+                    diesel::sql_query("SELECT to_regclass($1) IS NOT NULL AS exists")
+                        .bind::<Text, _>(&view)
+                        .get_result(pg_connection)?;
+                if exists.exists {
+                    continue;
+                }
+                let percentiles = columns
+                    .iter()
+                    .map(|column| format!("percentile_agg({column}) AS {column}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let statements = [
+                    format!(
+                        "CREATE MATERIALIZED VIEW {view} \
+                         WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS \
+                         SELECT time_bucket(INTERVAL '{width}', time) AS bucket, {group_by}, \
+                         {percentiles} FROM {table} GROUP BY bucket, {group_by} WITH NO DATA"
+                    ),
+                    // the most recent hour is left to the real-time aggregation:
+                    format!(
+                        "SELECT add_continuous_aggregate_policy('{view}', \
+                         start_offset => INTERVAL '{start_offset}', \
+                         end_offset => INTERVAL '1 hour', \
+                         schedule_interval => INTERVAL '{schedule_interval}')"
+                    ),
+                ];
+                for statement in statements {
+                    debug!("Applying: {statement}");
+                    diesel::sql_query(statement).execute(pg_connection)?;
+                }
+                created.push(view);
+            }
+        }
+        Ok(created)
+    })
+}
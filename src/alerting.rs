@@ -0,0 +1,1426 @@
+use crate::{
+    config::{ActionConfig, ActionKind, AlertsConfig, ConfigError, Severity},
+    error::Error,
+    point::{Point, Value},
+    query::parse_window,
+    *,
+};
+use chrono::{DateTime, Local, Utc};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+
+/// Entries usable in the conditions
+#[derive(Debug)]
+struct Source {
+    /// Short name of the entries, as used in the conditions
+    name: &'static str,
+    /// Measurement of the points, the same as the table name
+    measurement: &'static str,
+    /// Tags identifying the alerting entry
+    identity: &'static [&'static str],
+    /// Fields and tags usable in the conditions
+    names: &'static [&'static str],
+}
+
+
+/// All entries usable in the conditions
+const SOURCES: [Source; 6] = [
+    Source {
+        name: "sys",
+        measurement: "sys_stats",
+        identity: &[],
+        names: &[
+            "host",
+            "name",
+            "os_version",
+            "kernel_version",
+            "processors",
+            "total_memory",
+            "used_memory",
+            "total_swap",
+            "used_swap",
+            "load_one",
+            "load_five",
+            "load_fifteen",
+            "cpu_usage",
+        ],
+    },
+    Source {
+        name: "ups",
+        measurement: "ups_stats",
+        identity: &[],
+        names: &[
+            "host",
+            "model",
+            "status",
+            "load",
+            "input_frequency",
+            "input_voltage",
+            "battery_charge",
+            "battery_voltage",
+        ],
+    },
+    Source {
+        name: "disk",
+        measurement: "disk_stats",
+        identity: &["device"],
+        names: &[
+            "host",
+            "device",
+            "temperature",
+            "crc_errors",
+            "seek_time",
+            "seek_error_rate",
+            "throughput",
+            "read_error_rate",
+        ],
+    },
+    Source {
+        name: "net",
+        measurement: "net_stats",
+        identity: &["netdev"],
+        names: &[
+            "host",
+            "netdev",
+            "packets_received",
+            "total_packets_received",
+            "packets_transmitted",
+            "total_packets_transmitted",
+            "received",
+            "total_received",
+            "transmitted",
+            "total_transmitted",
+            "transmitted_errors",
+            "transmitted_total_errors",
+            "received_errors",
+            "received_total_errors",
+        ],
+    },
+    Source {
+        name: "proc",
+        measurement: "proc_stats",
+        identity: &["name", "pid"],
+        names: &[
+            "host",
+            "name",
+            "pid",
+            "cpu_usage",
+            "rss",
+            "disk_read",
+            "disk_read_total",
+            "disk_written",
+            "disk_written_total",
+            "start_time",
+        ],
+    },
+    Source {
+        name: "runs",
+        measurement: "collector_runs",
+        identity: &["collector"],
+        names: &[
+            "host",
+            "collector",
+            "duration_ms",
+            "rows_produced",
+            "rows_written",
+            "failed",
+            "error",
+        ],
+    },
+];
+
+
+/// Symbols of the conditions. Longer ones go first
+const SYMBOLS: [&str; 12] = [
+    ">=", "<=", "==", "!=", ">", "<", "+", "-", "*", "/", "(", ")",
+];
+
+
+/// Token of a condition
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    /// Name, keyword, time window or a bare text
+    Word(String),
+    /// Quoted text
+    Text(String),
+    Symbol(&'static str),
+}
+
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{number}"),
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Text(text) => write!(f, "'{text}'"),
+            Token::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+
+/// Split the condition into tokens
+fn tokens(condition: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut characters = condition.char_indices().peekable();
+    let is_word = |character: char| character.is_alphanumeric() || "_.".contains(character);
+    while let Some(&(start, character)) = characters.peek() {
+        if character.is_whitespace() {
+            characters.next();
+        } else if character == '\'' || character == '"' {
+            characters.next();
+            let mut text = String::new();
+            loop {
+                match characters.next() {
+                    Some((_, quote)) if quote == character => break,
+                    Some((_, next)) => text.push(next),
+                    None => return Err(format!("unterminated text: {}", &condition[start..])),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if is_word(character) {
+            let mut word = String::new();
+            while let Some(&(_, next)) = characters.peek().filter(|(_, next)| is_word(*next)) {
+                word.push(next);
+                characters.next();
+            }
+            // words like "5m" are time windows, "nan" or "inf" are names:
+            let number = word
+                .starts_with(|first: char| first.is_ascii_digit() || first == '.')
+                .then(|| word.parse().ok())
+                .flatten();
+            tokens.push(match number {
+                Some(number) => Token::Number(number),
+                None => Token::Word(word),
+            });
+        } else {
+            let rest = &condition[start..];
+            let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) else {
+                return Err(format!("unexpected character: '{character}'"));
+            };
+            for _ in 0..symbol.len() {
+                characters.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+
+/// Arithmetic operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+
+/// Comparison of two values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    Contains,
+}
+
+
+/// Expression giving a value of the point
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Number(f64),
+    Text(String),
+    /// Field or tag of the point
+    Name(&'static str),
+    Arithmetic(Box<Expression>, Operator, Box<Expression>),
+}
+
+
+impl Expression {
+    /// Value of the expression. None if a field is missing,
+    /// a text isn't a number or the result isn't finite
+    fn evaluate(&self, point: &Point) -> Option<Value> {
+        match self {
+            Expression::Number(number) => Some(Value::Float(*number)),
+            Expression::Text(text) => Some(Value::Text(text.to_owned())),
+            Expression::Name(name) => {
+                point
+                    .fields
+                    .iter()
+                    .find(|field| field.name == *name)
+                    .map(|field| field.value.clone())
+                    .or_else(|| tag(point, name).map(|value| Value::Text(value.to_owned())))
+            }
+            Expression::Arithmetic(left, operator, right) => {
+                let left = number(&left.evaluate(point)?)?;
+                let right = number(&right.evaluate(point)?)?;
+                let value = match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                };
+                value.is_finite().then_some(Value::Float(value))
+            }
+        }
+    }
+}
+
+
+/// Test of the point
+#[derive(Debug, Clone, PartialEq)]
+enum Predicate {
+    Compare(Expression, Comparison, Expression),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+
+impl Predicate {
+    /// True if the point matches. None if it can't be evaluated (unknown).
+    /// A false side of the conjunction, or a true side of the disjunction, decides it still
+    fn test(&self, point: &Point) -> Option<bool> {
+        match self {
+            Predicate::Compare(left, comparison, right) => {
+                let (left, right) = (left.evaluate(point)?, right.evaluate(point)?);
+                Some(match comparison {
+                    Comparison::Greater => number(&left)? > number(&right)?,
+                    Comparison::GreaterOrEqual => number(&left)? >= number(&right)?,
+                    Comparison::Less => number(&left)? < number(&right)?,
+                    Comparison::LessOrEqual => number(&left)? <= number(&right)?,
+                    Comparison::Equal => equal(&left, &right),
+                    Comparison::NotEqual => !equal(&left, &right),
+                    Comparison::Contains => text(&left).contains(&text(&right)),
+                })
+            }
+            Predicate::And(left, right) => {
+                match (left.test(point), right.test(point)) {
+                    (Some(false), _) | (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                }
+            }
+            Predicate::Or(left, right) => {
+                match (left.test(point), right.test(point)) {
+                    (Some(true), _) | (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+
+    /// Value of the left side of the first comparison, reported with the alerts
+    fn observed(&self, point: &Point) -> Option<Value> {
+        match self {
+            Predicate::Compare(left, ..) => left.evaluate(point),
+            Predicate::And(left, _) | Predicate::Or(left, _) => left.observed(point),
+        }
+    }
+}
+
+
+/// Value as a number. Texts holding numbers (like the pid) are numbers too
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Text(text) => text.trim().parse().ok(),
+        value => value.as_f64(),
+    }
+}
+
+
+/// Value as a text
+fn text(value: &Value) -> String {
+    match value {
+        Value::Float(value) => value.to_string(),
+        Value::Integer(value) => value.to_string(),
+        Value::Text(text) => text.to_owned(),
+    }
+}
+
+
+/// Values are compared as numbers if both are numbers, as texts otherwise
+fn equal(left: &Value, right: &Value) -> bool {
+    match (number(left), number(right)) {
+        (Some(left), Some(right)) => left == right,
+        _ => text(left) == text(right),
+    }
+}
+
+
+/// Value of the tag of the point
+fn tag<'a>(point: &'a Point, name: &str) -> Option<&'a str> {
+    point
+        .tags
+        .iter()
+        .find_map(|(tag_name, value)| (*tag_name == name).then_some(value.as_str()))
+}
+
+
+/// Recursive descent parser of the conditions
+#[derive(Debug)]
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    source: &'static Source,
+}
+
+
+impl Parser {
+    /// Take the next token
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+
+    /// Take the next token if it's the keyword
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+
+    /// Take the next token if it's the symbol
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.tokens.get(self.position) {
+            Some(Token::Symbol(next)) if *next == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+
+    /// Comparisons joined with "or"
+    fn disjunction(&mut self) -> Result<Predicate, String> {
+        let mut predicate = self.conjunction()?;
+        while self.keyword("or") {
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.conjunction()?));
+        }
+        Ok(predicate)
+    }
+
+
+    /// Comparisons joined with "and"
+    fn conjunction(&mut self) -> Result<Predicate, String> {
+        let mut predicate = self.comparison()?;
+        while self.keyword("and") {
+            predicate = Predicate::And(Box::new(predicate), Box::new(self.comparison()?));
+        }
+        Ok(predicate)
+    }
+
+
+    /// Comparison of two values. Bare words compared as equal or contained are texts,
+    /// like: "ups.status contains OB"
+    fn comparison(&mut self) -> Result<Predicate, String> {
+        let left = self.sum()?;
+        let comparison = match self.next() {
+            Some(Token::Symbol(">")) => Comparison::Greater,
+            Some(Token::Symbol(">=")) => Comparison::GreaterOrEqual,
+            Some(Token::Symbol("<")) => Comparison::Less,
+            Some(Token::Symbol("<=")) => Comparison::LessOrEqual,
+            Some(Token::Symbol("==")) => Comparison::Equal,
+            Some(Token::Symbol("!=")) => Comparison::NotEqual,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("contains") => {
+                Comparison::Contains
+            }
+            other => {
+                return Err(format!(
+                    "expected a comparison (>, >=, <, <=, ==, != or contains), found: {}",
+                    shown(other)
+                ))
+            }
+        };
+        let right = match (comparison, self.tokens.get(self.position)) {
+            (
+                Comparison::Equal | Comparison::NotEqual | Comparison::Contains,
+                Some(Token::Word(word)),
+            ) if !word.contains('.') => {
+                let text = Expression::Text(word.to_owned());
+                self.position += 1;
+                text
+            }
+            _ => self.sum()?,
+        };
+        Ok(Predicate::Compare(left, comparison, right))
+    }
+
+
+    /// Terms added or subtracted
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut expression = self.product()?;
+        loop {
+            let operator = if self.symbol("+") {
+                Operator::Add
+            } else if self.symbol("-") {
+                Operator::Subtract
+            } else {
+                return Ok(expression);
+            };
+            expression = Expression::Arithmetic(
+                Box::new(expression),
+                operator,
+                Box::new(self.product()?),
+            );
+        }
+    }
+
+
+    /// Factors multiplied or divided
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut expression = self.factor()?;
+        loop {
+            let operator = if self.symbol("*") {
+                Operator::Multiply
+            } else if self.symbol("/") {
+                Operator::Divide
+            } else {
+                return Ok(expression);
+            };
+            expression = Expression::Arithmetic(
+                Box::new(expression),
+                operator,
+                Box::new(self.factor()?),
+            );
+        }
+    }
+
+
+    /// Single value: number, quoted text, name, negation or an expression in parentheses
+    fn factor(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expression::Number(number)),
+            Some(Token::Text(text)) => Ok(Expression::Text(text)),
+            Some(Token::Word(word)) => self.name(&word),
+            Some(Token::Symbol("-")) => {
+                Ok(Expression::Arithmetic(
+                    Box::new(Expression::Number(0.0)),
+                    Operator::Subtract,
+                    Box::new(self.factor()?),
+                ))
+            }
+            Some(Token::Symbol("(")) => {
+                let expression = self.sum()?;
+                if !self.symbol(")") {
+                    return Err(format!(
+                        "expected: ')', found: {}",
+                        shown(self.tokens.get(self.position).cloned())
+                    ));
+                }
+                Ok(expression)
+            }
+            other => Err(format!("expected a value, found: {}", shown(other))),
+        }
+    }
+
+
+    /// Field or tag of the entries, with or without the entries name
+    fn name(&self, word: &str) -> Result<Expression, String> {
+        let name = match word.split_once('.') {
+            Some((entries, name)) if entries == self.source.name => name,
+            Some(_) => {
+                return Err(format!(
+                    "'{word}' isn't of the {} entries. A condition tests a single entries",
+                    self.source.name
+                ))
+            }
+            None => word,
+        };
+        self.source
+            .names
+            .iter()
+            .find(|known| **known == name)
+            .map(|known| Expression::Name(known))
+            .ok_or_else(|| {
+                format!(
+                    "unknown name of the {} entries: '{name}'. Known: {}",
+                    self.source.name,
+                    self.source.names.join(", ")
+                )
+            })
+    }
+}
+
+
+/// Token shown in the errors
+fn shown(token: Option<Token>) -> String {
+    token
+        .map(|token| format!("'{token}'"))
+        .unwrap_or_else(|| String::from("the end"))
+}
+
+
+/// Parsed condition of an alert rule, like: "disk.temperature > 50 for 5m".
+/// Comparisons (>, >=, <, <=, ==, != and contains) of the fields and tags of a single
+/// entries, with arithmetic (+, -, *, /), joined with "and" or "or".
+/// The condition must hold for the time window given after "for" to fire
+#[derive(Debug, Clone)]
+pub struct Condition {
+    source: &'static Source,
+    predicate: Predicate,
+    pending: Duration,
+    text: String,
+}
+
+
+impl Condition {
+    /// Parse the condition
+    pub fn parse(condition: &str) -> Result<Self, String> {
+        let tokens = tokens(condition)?;
+        // the entries are given by the first name with the entries name, like: "disk.temperature":
+        let entries = tokens
+            .iter()
+            .find_map(|token| {
+                match token {
+                    Token::Word(word) => word.split_once('.').map(|(entries, _)| entries),
+                    _ => None,
+                }
+            })
+            .ok_or_else(|| {
+                String::from("must name a field with its entries, like: disk.temperature")
+            })?;
+        let source = SOURCES
+            .iter()
+            .find(|source| source.name == entries)
+            .ok_or_else(|| {
+                format!(
+                    "unknown entries: '{entries}'. Known: {}",
+                    SOURCES.map(|source| source.name).join(", ")
+                )
+            })?;
+
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            source,
+        };
+        let predicate = parser.disjunction()?;
+        let pending = if parser.keyword("for") {
+            match parser.next() {
+                Some(Token::Word(window)) => parse_window(&window)?,
+                other => {
+                    return Err(format!(
+                        "expected a time window after 'for', like: 5m, found: {}",
+                        shown(other)
+                    ))
+                }
+            }
+        } else {
+            Duration::ZERO
+        };
+        if let Some(token) = parser.next() {
+            return Err(format!("unexpected: '{token}'"));
+        }
+        Ok(Self {
+            source,
+            predicate,
+            pending,
+            text: condition.trim().to_string(),
+        })
+    }
+}
+
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+
+/// Alert rule, ready to evaluate
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    condition: Condition,
+    severity: Severity,
+    repeat: Option<Duration>,
+    notify_resolved: bool,
+    actions: Vec<String>,
+}
+
+
+/// State of the alert of a rule, for a single alerting entry
+#[derive(Debug, Clone, Copy)]
+struct Series {
+    /// Since when the condition holds
+    since: SystemTime,
+    /// True once the condition held for its time window
+    firing: bool,
+    /// When the actions were notified the last time
+    notified: SystemTime,
+}
+
+
+/// Alert notified to the actions
+#[derive(Debug, Clone)]
+struct Notification {
+    alert: Alert,
+    /// Like: "[warning] disk_hot firing on nas (device=sda)"
+    summary: String,
+    /// True if the firing alert was notified before
+    repeated: bool,
+    actions: Vec<String>,
+}
+
+
+/// Evaluates the alert rules over the entries of each tick.
+/// An alert is tracked per rule and alerting entry (like a disk), so the actions
+/// are notified once when it fires, then again only after the repeat interval,
+/// and when it's resolved
+#[derive(Debug)]
+pub struct Alerting {
+    host_name: String,
+    rules: Vec<Rule>,
+    series: BTreeMap<(usize, String), Series>,
+    notifier: Notifier,
+}
+
+
+impl Alerting {
+    /// Setup the alert rules and start the notifier of their actions
+    pub fn new(config: &AlertsConfig, host_name: &str) -> Result<Self, ConfigError> {
+        let mut rules = vec![];
+        let mut errors = vec![];
+        for rule in &config.rules {
+            let condition = Condition::parse(&rule.condition);
+            let repeat = rule.repeat.as_deref().map(parse_window).transpose();
+            match (condition, repeat) {
+                (Ok(condition), Ok(repeat)) => {
+                    rules.push(Rule {
+                        name: rule.name.to_owned(),
+                        condition,
+                        severity: rule.severity,
+                        repeat,
+                        notify_resolved: rule.notify_resolved,
+                        actions: rule.actions.to_owned(),
+                    })
+                }
+                (Err(error), _) | (_, Err(error)) => {
+                    errors.push(format!("alerts.rules: {}: {error}", rule.name))
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ConfigError {
+                errors,
+            });
+        }
+        Ok(Self {
+            host_name: host_name.to_owned(),
+            rules,
+            series: BTreeMap::new(),
+            notifier: Notifier::start(config.actions.to_owned()),
+        })
+    }
+
+
+    /// Names of the alert rules
+    pub fn names(&self) -> Vec<&str> {
+        self.rules.iter().map(|rule| rule.name.as_str()).collect()
+    }
+
+
+    /// Evaluate the rules over the entries collected in the tick, and notify the actions.
+    /// Returns the alerts that started firing or were resolved.
+    /// Entries missing from the tick, or the condition can't be evaluated on
+    /// (like of a missing field), keep their alerts. But an alerting entry
+    /// gone from the collected entries (like an ended process) is resolved
+    pub fn evaluate(&mut self, entries: &[Entries]) -> Vec<Alert> {
+        let mut alerts = vec![];
+        for an_entries in entries.iter().filter(|an_entries| !an_entries.is_empty()) {
+            let table = an_entries.table();
+            if !self
+                .rules
+                .iter()
+                .any(|rule| rule.condition.source.measurement == table)
+            {
+                continue;
+            }
+            let points = an_entries.to_points();
+            for (index, rule) in self.rules.iter().enumerate() {
+                if rule.condition.source.measurement != table {
+                    continue;
+                }
+                let mut seen = BTreeSet::new();
+                for point in &points {
+                    let instance = instance(rule.condition.source, point);
+                    seen.insert(instance.to_owned());
+                    let key = (index, instance);
+                    match rule.condition.predicate.test(point) {
+                        Some(true) => {}
+                        // unknown, like of a missing field, so the series is kept as it is:
+                        None => continue,
+                        Some(false) => {
+                            if let Some(series) = self.series.remove(&key) {
+                                if series.firing {
+                                    let value = rule.condition.predicate.observed(point);
+                                    let notification = self.notification(
+                                        rule, &key.1, "resolved", point.time, value,
+                                    );
+                                    alerts.push(self.raise(rule, notification, false));
+                                }
+                            }
+                            continue;
+                        }
+                    }
+
+                    let series = self.series.entry(key.to_owned()).or_insert(Series {
+                        since: point.time,
+                        firing: false,
+                        notified: point.time,
+                    });
+                    let elapsed = |since: SystemTime| {
+                        point.time.duration_since(since).unwrap_or_default()
+                    };
+                    if !series.firing && elapsed(series.since) >= rule.condition.pending {
+                        series.firing = true;
+                        series.notified = point.time;
+                        let value = rule.condition.predicate.observed(point);
+                        let notification =
+                            self.notification(rule, &key.1, "firing", point.time, value);
+                        alerts.push(self.raise(rule, notification, false));
+                    } else if series.firing
+                        && rule
+                            .repeat
+                            .is_some_and(|repeat| elapsed(series.notified) >= repeat)
+                    {
+                        series.notified = point.time;
+                        let value = rule.condition.predicate.observed(point);
+                        let notification =
+                            self.notification(rule, &key.1, "firing", point.time, value);
+                        self.raise(rule, notification, true);
+                    }
+                }
+
+                // the alerting entries gone from the collected ones:
+                let gone = self
+                    .series
+                    .keys()
+                    .filter(|(rule_index, instance)| {
+                        *rule_index == index && !seen.contains(instance)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let time = points
+                    .first()
+                    .map_or_else(SystemTime::now, |point| point.time);
+                for key in gone {
+                    if self.series.remove(&key).is_some_and(|series| series.firing) {
+                        let notification =
+                            self.notification(rule, &key.1, "resolved", time, None);
+                        alerts.push(self.raise(rule, notification, false));
+                    }
+                }
+            }
+        }
+        alerts
+    }
+
+
+    /// Notification of the alert of the rule
+    fn notification(
+        &self,
+        rule: &Rule,
+        instance: &str,
+        state: &str,
+        time: SystemTime,
+        value: Option<Value>,
+    ) -> Notification {
+        let mut summary = format!(
+            "[{}] {} {state} on {}",
+            rule.severity, rule.name, self.host_name
+        );
+        if !instance.is_empty() {
+            summary.push_str(&format!(" ({instance})"));
+        }
+        let mut message = format!("{summary}: {}", rule.condition);
+        if let Some(value) = &value {
+            message.push_str(&format!(", value: {}", text(value)));
+        }
+        Notification {
+            alert: Alert {
+                time,
+                host_name: self.host_name.to_owned(),
+                rule: rule.name.to_owned(),
+                instance: instance.to_owned(),
+                state: state.to_owned(),
+                severity: rule.severity.to_string(),
+                value: value.as_ref().and_then(number),
+                message,
+            },
+            summary,
+            repeated: false,
+            actions: rule.actions.to_owned(),
+        }
+    }
+
+
+    /// Log the alert and notify the actions. Resolved alerts are notified if requested
+    fn raise(&self, rule: &Rule, mut notification: Notification, repeated: bool) -> Alert {
+        let alert = notification.alert.clone();
+        if repeated {
+            warn!("Alert: {} (repeated)", alert.message);
+        } else if alert.state == "firing" {
+            warn!("Alert: {}", alert.message);
+        } else {
+            info!("Alert: {}", alert.message);
+        }
+        if alert.state == "firing" || rule.notify_resolved {
+            notification.repeated = repeated;
+            self.notifier.send(notification);
+        }
+        alert
+    }
+}
+
+
+/// Identity of the alerting entry, like: "device=sda". Empty for sys and ups
+fn instance(source: &Source, point: &Point) -> String {
+    source
+        .identity
+        .iter()
+        .filter_map(|name| tag(point, name).map(|value| format!("{name}={value}")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+
+/// Notifies the actions of the alerts in its own thread, so slow actions
+/// don't delay the collection
+#[derive(Debug)]
+struct Notifier {
+    sender: Option<Sender<Notification>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl Notifier {
+    /// Start the thread notifying the actions
+    fn start(actions: BTreeMap<String, ActionConfig>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Notification>();
+        let thread = thread::Builder::new()
+            .name(String::from("alerts"))
+            .spawn(move || {
+                for notification in receiver {
+                    for name in &notification.actions {
+                        let Some(action) = actions.get(name) else {
+                            continue;
+                        };
+                        match notify(name, action, &notification) {
+                            Ok(()) => {
+                                debug!("Alert: {} notified by: {name}", notification.summary)
+                            }
+                            Err(error) => error!("{error}"),
+                        }
+                    }
+                }
+            });
+        match thread {
+            Ok(thread) => {
+                Self {
+                    sender: Some(sender),
+                    thread: Some(thread),
+                }
+            }
+            Err(error) => {
+                error!("Can't start the alert notifications: {error}");
+                Self {
+                    sender: None,
+                    thread: None,
+                }
+            }
+        }
+    }
+
+
+    /// Queue the notification
+    fn send(&self, notification: Notification) {
+        let summary = notification.summary.to_owned();
+        if self
+            .sender
+            .as_ref()
+            .is_none_or(|sender| sender.send(notification).is_err())
+        {
+            warn!("Alert: {summary} not notified. Notifications are stopped.");
+        }
+    }
+}
+
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        // queued notifications are sent before the thread stops:
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// Notify the action of the alert
+fn notify(
+    name: &str,
+    action: &ActionConfig,
+    notification: &Notification,
+) -> Result<(), Error> {
+    let result = match action.kind {
+        ActionKind::Command => run_command(action, notification).map_err(Error::from),
+        ActionKind::Webhook => post_webhook(action, notification),
+        ActionKind::Smtp => send_mail(action, notification).map_err(Error::from),
+    };
+    result.map_err(|error| {
+        Error::Action {
+            action: name.to_owned(),
+            reason: error.to_string(),
+        }
+    })
+}
+
+
+/// Alert as JSON, posted to the webhooks
+fn payload(notification: &Notification) -> Json {
+    let alert = &notification.alert;
+    json!({
+        "rule": alert.rule,
+        "state": alert.state,
+        "severity": alert.severity,
+        "host": alert.host_name,
+        "instance": alert.instance,
+        "value": alert.value,
+        "message": alert.message,
+        "time": DateTime::<Utc>::from(alert.time).to_rfc3339(),
+        "repeated": notification.repeated,
+    })
+}
+
+
+/// Run the command with the alert in its environment. Killed after the timeout
+fn run_command(action: &ActionConfig, notification: &Notification) -> io::Result<()> {
+    let alert = &notification.alert;
+    let (program, arguments) = action
+        .command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command"))?;
+    let mut child = Command::new(program)
+        .args(arguments)
+        .env("DCOLLECTOR_ALERT_RULE", &alert.rule)
+        .env("DCOLLECTOR_ALERT_STATE", &alert.state)
+        .env("DCOLLECTOR_ALERT_SEVERITY", &alert.severity)
+        .env("DCOLLECTOR_ALERT_HOST", &alert.host_name)
+        .env("DCOLLECTOR_ALERT_INSTANCE", &alert.instance)
+        .env(
+            "DCOLLECTOR_ALERT_VALUE",
+            alert
+                .value
+                .map(|value| value.to_string())
+                .unwrap_or_default(),
+        )
+        .env("DCOLLECTOR_ALERT_MESSAGE", &alert.message)
+        .env(
+            "DCOLLECTOR_ALERT_TIME",
+            DateTime::<Utc>::from(alert.time).to_rfc3339(),
+        )
+        .env(
+            "DCOLLECTOR_ALERT_REPEATED",
+            notification.repeated.to_string(),
+        )
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!("command {status}")));
+        }
+        if started.elapsed() >= Duration::from_secs(action.timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "command timed out, and was killed",
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+
+/// POST the alert as JSON to the webhook
+fn post_webhook(action: &ActionConfig, notification: &Notification) -> Result<(), Error> {
+    ureq::post(&action.url)
+        .timeout(Duration::from_secs(action.timeout))
+        .set("Content-Type", "application/json")
+        .send_string(&payload(notification).to_string())?;
+    Ok(())
+}
+
+
+/// Send the alert as a mail, through the SMTP server without TLS nor authentication,
+/// like a local MTA
+fn send_mail(action: &ActionConfig, notification: &Notification) -> io::Result<()> {
+    let timeout = Duration::from_secs(action.timeout);
+    let mut last_error = None;
+    let mut stream = None;
+    for address in action.server.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(error) => last_error = Some(error),
+        }
+    }
+    let mut stream = stream.ok_or_else(|| {
+        last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address not resolved"))
+    })?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut replies = BufReader::new(stream.try_clone()?);
+
+    let alert = &notification.alert;
+    reply(&mut replies, '2')?;
+    command(
+        &mut stream,
+        &mut replies,
+        &format!("EHLO {}", alert.host_name),
+        '2',
+    )?;
+    command(
+        &mut stream,
+        &mut replies,
+        &format!("MAIL FROM:<{}>", action.from),
+        '2',
+    )?;
+    for to in &action.to {
+        command(&mut stream, &mut replies, &format!("RCPT TO:<{to}>"), '2')?;
+    }
+    command(&mut stream, &mut replies, "DATA", '3')?;
+
+    let mut subject = notification.summary.to_owned();
+    if notification.repeated {
+        subject.push_str(" (repeated)");
+    }
+    let body = format!(
+        "{}\n\nRule: {}\nState: {}\nSeverity: {}\nHost: {}\nInstance: {}\nValue: {}\nTime: {}\n",
+        alert.message,
+        alert.rule,
+        alert.state,
+        alert.severity,
+        alert.host_name,
+        alert.instance,
+        alert.value.map(|value| value.to_string()).unwrap_or_default(),
+        DateTime::<Local>::from(alert.time).to_rfc3339(),
+    );
+    let mut mail = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\n\
+        MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 8bit\r\n\r\n",
+        action.from,
+        action.to.join(", "),
+        Local::now().to_rfc2822(),
+    );
+    // lines starting with a dot are escaped, a single dot ends the mail:
+    for line in body.lines() {
+        if line.starts_with('.') {
+            mail.push('.');
+        }
+        mail.push_str(line);
+        mail.push_str("\r\n");
+    }
+    mail.push_str(".\r\n");
+    stream.write_all(mail.as_bytes())?;
+    reply(&mut replies, '2')?;
+    // the mail is accepted already:
+    let _ = command(&mut stream, &mut replies, "QUIT", '2');
+    Ok(())
+}
+
+
+/// Send the SMTP command, then read its reply
+fn command(
+    stream: &mut TcpStream,
+    replies: &mut BufReader<TcpStream>,
+    command: &str,
+    expected: char,
+) -> io::Result<()> {
+    stream.write_all(format!("{command}\r\n").as_bytes())?;
+    reply(replies, expected)
+        .map_err(|error| io::Error::new(error.kind(), format!("{command}: {error}")))
+}
+
+
+/// Read the (multiline) SMTP reply, expecting its code to start with the digit
+fn reply(replies: &mut BufReader<TcpStream>, expected: char) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if replies.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by the server",
+            ));
+        }
+        let line = line.trim_end();
+        // continued lines have a dash after the code, like: "250-SIZE":
+        if line.get(3..4) == Some("-") {
+            continue;
+        }
+        if line.starts_with(expected) {
+            return Ok(());
+        }
+        return Err(io::Error::other(format!("unexpected reply: {line}")));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::AlertRule, point::ToPoint};
+
+
+    /// Time of the tick, in seconds since UNIX_EPOCH
+    fn at(second: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(second)
+    }
+
+
+    /// Entry of the disk, with its temperature
+    fn disk(second: u64, device: &str, temperature: Option<f64>) -> DiskStat {
+        DiskStat {
+            time: at(second),
+            host_name: String::from("nas"),
+            name: device.to_string(),
+            temperature,
+            ..Default::default()
+        }
+    }
+
+
+    /// Alerting of the single rule
+    fn alerting(condition: &str, repeat: Option<&str>) -> Alerting {
+        let config = AlertsConfig {
+            enabled: true,
+            actions: BTreeMap::new(),
+            rules: vec![AlertRule {
+                name: String::from("disk_hot"),
+                condition: condition.to_string(),
+                repeat: repeat.map(str::to_string),
+                ..Default::default()
+            }],
+        };
+        Alerting::new(&config, "nas").unwrap()
+    }
+
+
+    /// States of the alerts, with their instances
+    fn states(alerts: &[Alert]) -> Vec<(&str, &str)> {
+        alerts
+            .iter()
+            .map(|alert| (alert.state.as_str(), alert.instance.as_str()))
+            .collect()
+    }
+
+
+    #[test]
+    fn parses_the_threshold_with_the_time_window() {
+        let condition = Condition::parse("disk.temperature > 50 for 5m").unwrap();
+        assert_eq!(condition.source.name, "disk");
+        assert_eq!(condition.pending, Duration::from_secs(300));
+        assert_eq!(condition.to_string(), "disk.temperature > 50 for 5m");
+        let test = |temperature| {
+            condition
+                .predicate
+                .test(&disk(0, "sda", temperature).to_point())
+        };
+        assert_eq!(test(Some(55.0)), Some(true));
+        assert_eq!(test(Some(50.0)), Some(false));
+        assert_eq!(test(None), None);
+    }
+
+
+    #[test]
+    fn parses_the_text_containment() {
+        let condition = Condition::parse("ups.status contains OB").unwrap();
+        assert_eq!(condition.source.name, "ups");
+        assert_eq!(condition.pending, Duration::ZERO);
+        let test = |status: &str| {
+            let ups = UpsStat {
+                status: Some(status.to_string()),
+                ..Default::default()
+            };
+            condition.predicate.test(&ups.to_point())
+        };
+        assert_eq!(test("OB LB"), Some(true));
+        assert_eq!(test("OL"), Some(false));
+    }
+
+
+    #[test]
+    fn parses_the_arithmetic() {
+        let condition = Condition::parse("sys.used_swap / total_swap > 0.8").unwrap();
+        assert_eq!(condition.source.name, "sys");
+        let test = |used_swap, total_swap| {
+            let sys = SysStat {
+                used_swap: Some(used_swap),
+                total_swap: Some(total_swap),
+                ..Default::default()
+            };
+            condition.predicate.test(&sys.to_point())
+        };
+        assert_eq!(test(9, 10), Some(true));
+        assert_eq!(test(8, 10), Some(false));
+        // no swap at all:
+        assert_eq!(test(0, 0), None);
+    }
+
+
+    #[test]
+    fn decides_the_unknown_sides_of_and_or() {
+        let point = disk(0, "sda", None).to_point();
+        let test = |condition| Condition::parse(condition).unwrap().predicate.test(&point);
+        assert_eq!(test("disk.temperature > 50 or device == sda"), Some(true));
+        assert_eq!(test("disk.temperature > 50 and device == sdb"), Some(false));
+        assert_eq!(test("disk.temperature > 50 and device == sda"), None);
+        assert_eq!(test("disk.temperature > 50 or device == sdb"), None);
+    }
+
+
+    #[test]
+    fn rejects_the_invalid_conditions() {
+        for (condition, error) in [
+            ("", "must name a field"),
+            ("temperature > 50", "must name a field"),
+            ("cpu.usage > 50", "unknown entries: 'cpu'"),
+            ("disk.speed > 50", "unknown"),
+            ("disk.temperature >", "expected"),
+            ("disk.temperature > 50 for", "expected a time window"),
+            ("disk.temperature > 50 for 5x", "invalid unit"),
+            ("disk.temperature > 50 50", "unexpected"),
+            ("ups.status == 'OB", "unterminated text"),
+        ] {
+            let parsed = Condition::parse(condition);
+            assert!(
+                parsed.as_ref().is_err_and(|parsed| parsed.contains(error)),
+                "{condition}: {parsed:?}"
+            );
+        }
+    }
+
+
+    #[test]
+    fn fires_after_the_time_window_once() {
+        let mut alerting = alerting("disk.temperature > 50 for 5m", None);
+        let mut evaluate = |second, temperature| {
+            alerting.evaluate(&[Entries::Disk(vec![disk(second, "sda", temperature)])])
+        };
+        assert!(evaluate(0, Some(55.0)).is_empty());
+        assert!(evaluate(240, Some(56.0)).is_empty());
+        let alerts = evaluate(300, Some(57.0));
+        assert_eq!(states(&alerts), [("firing", "device=sda")]);
+        assert_eq!(alerts[0].rule, "disk_hot");
+        assert_eq!(alerts[0].host_name, "nas");
+        assert_eq!(alerts[0].severity, "warning");
+        assert_eq!(alerts[0].value, Some(57.0));
+        assert_eq!(alerts[0].time, at(300));
+        // deduplicated while firing:
+        assert!(evaluate(360, Some(58.0)).is_empty());
+        assert!(evaluate(3_600, Some(58.0)).is_empty());
+    }
+
+
+    #[test]
+    fn restarts_the_time_window_when_the_condition_stops_holding() {
+        let mut alerting = alerting("disk.temperature > 50 for 5m", None);
+        let mut evaluate = |second, temperature| {
+            alerting.evaluate(&[Entries::Disk(vec![disk(second, "sda", Some(temperature))])])
+        };
+        assert!(evaluate(0, 55.0).is_empty());
+        assert!(evaluate(240, 45.0).is_empty());
+        assert!(evaluate(300, 55.0).is_empty());
+        assert_eq!(states(&evaluate(600, 55.0)), [("firing", "device=sda")]);
+    }
+
+
+    #[test]
+    fn repeats_the_notification_of_the_firing_alert() {
+        let mut alerting = alerting("disk.temperature > 50", Some("10m"));
+        let key = (0, String::from("device=sda"));
+        for second in [0, 300, 599] {
+            alerting.evaluate(&[Entries::Disk(vec![disk(second, "sda", Some(55.0))])]);
+            assert_eq!(alerting.series[&key].notified, at(0));
+        }
+        // notified again, but not a new alert:
+        let alerts = alerting.evaluate(&[Entries::Disk(vec![disk(600, "sda", Some(55.0))])]);
+        assert!(alerts.is_empty());
+        assert_eq!(alerting.series[&key].notified, at(600));
+    }
+
+
+    #[test]
+    fn resolves_the_alert() {
+        let mut alerting = alerting("disk.temperature > 50", None);
+        let mut evaluate = |second, temperature| {
+            alerting.evaluate(&[Entries::Disk(vec![disk(second, "sda", temperature)])])
+        };
+        assert_eq!(states(&evaluate(0, Some(55.0))), [("firing", "device=sda")]);
+        // unknown, so still firing:
+        assert!(evaluate(60, None).is_empty());
+        let alerts = evaluate(120, Some(45.0));
+        assert_eq!(states(&alerts), [("resolved", "device=sda")]);
+        assert_eq!(alerts[0].value, Some(45.0));
+        assert!(evaluate(180, Some(45.0)).is_empty());
+    }
+
+
+    #[test]
+    fn resolves_the_alerts_of_the_gone_entries() {
+        let mut alerting = alerting("disk.temperature > 50", None);
+        let alerts = alerting.evaluate(&[Entries::Disk(vec![
+            disk(0, "sda", Some(55.0)),
+            disk(0, "sdb", Some(55.0)),
+        ])]);
+        assert_eq!(
+            states(&alerts),
+            [("firing", "device=sda"), ("firing", "device=sdb")]
+        );
+        // entries not collected in the tick keep their alerts:
+        assert!(alerting.evaluate(&[Entries::Disk(vec![])]).is_empty());
+        assert!(alerting.evaluate(&[]).is_empty());
+        let alerts = alerting.evaluate(&[Entries::Disk(vec![disk(60, "sda", Some(55.0))])]);
+        assert_eq!(states(&alerts), [("resolved", "device=sdb")]);
+        assert_eq!(alerts[0].time, at(60));
+    }
+}
@@ -0,0 +1,392 @@
+use crate::{
+    config::{CollectorsConfig, UpsConfig},
+    error::Error,
+    models::DefaultWithTime,
+    point::{Point, ToPoint},
+    systeminfo::{disk_stats_entry, net_stats_entries, sys_process_entries, sys_stats_entry},
+    ups::ups_stats_entry,
+    *,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime},
+};
+use sysinfo::{System, SystemExt};
+
+
+/// Names of all collectors known to the agent
+pub const COLLECTOR_NAMES: [&str; 5] = ["sys", "ups", "disk", "proc", "net"];
+
+
+/// Entries produced by a single collector run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Entries {
+    /// System stats
+    Sys(Vec<SysStat>),
+    /// UPS stats
+    Ups(Vec<UpsStat>),
+    /// Disk stats
+    Disk(Vec<DiskStat>),
+    /// Processes stats
+    Proc(Vec<ProcStat>),
+    /// Network stats
+    Net(Vec<NetStat>),
+    /// Collector runs describing the preceding entries, in the same order
+    Runs(Vec<CollectorRun>),
+    /// Alerts that started firing or were resolved, following the collector runs
+    Alerts(Vec<Alert>),
+}
+
+
+impl Entries {
+    /// Amount of entries
+    pub fn len(&self) -> usize {
+        match self {
+            Entries::Sys(entries) => entries.len(),
+            Entries::Ups(entries) => entries.len(),
+            Entries::Disk(entries) => entries.len(),
+            Entries::Proc(entries) => entries.len(),
+            Entries::Net(entries) => entries.len(),
+            Entries::Runs(entries) => entries.len(),
+            Entries::Alerts(entries) => entries.len(),
+        }
+    }
+
+
+    /// True if there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+
+    /// Name of the table that holds the entries
+    pub fn table(&self) -> &'static str {
+        match self {
+            Entries::Sys(_) => "sys_stats",
+            Entries::Ups(_) => "ups_stats",
+            Entries::Disk(_) => "disk_stats",
+            Entries::Proc(_) => "proc_stats",
+            Entries::Net(_) => "net_stats",
+            Entries::Runs(_) => "collector_runs",
+            Entries::Alerts(_) => "alerts",
+        }
+    }
+
+
+    /// Entries as lines of text, using Display of each entry
+    pub fn to_lines(&self) -> Vec<String> {
+        match self {
+            Entries::Sys(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Ups(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Disk(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Proc(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Net(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Runs(entries) => entries.iter().map(ToString::to_string).collect(),
+            Entries::Alerts(entries) => entries.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+
+    /// Entries as points, independent of the output format
+    pub fn to_points(&self) -> Vec<Point> {
+        match self {
+            Entries::Sys(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Ups(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Disk(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Proc(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Net(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Runs(entries) => entries.iter().map(ToPoint::to_point).collect(),
+            Entries::Alerts(entries) => entries.iter().map(ToPoint::to_point).collect(),
+        }
+    }
+
+
+    /// Record the error of writing the entries in the collector runs without an error
+    pub fn set_write_error(&mut self, error: &str) {
+        if let Entries::Runs(runs) = self {
+            for run in runs.iter_mut().filter(|run| run.error.is_none()) {
+                run.error = Some(format!("write failed: {}", error.trim()));
+            }
+        }
+    }
+}
+
+
+/// Common trait of all data sources of the agent
+pub trait Collector: Debug {
+    /// Unique name of the collector, used in configuration and logs
+    fn name(&self) -> &'static str;
+
+    /// Name of the table that holds entries of this collector
+    fn table(&self) -> &'static str;
+
+    /// Interval between runs of the collector, unless configured otherwise
+    fn default_interval(&self) -> Duration {
+        Duration::from_secs(5)
+    }
+
+    /// Refresh the parts of the system the collector reads, then read the entries.
+    /// Entries equal to the defaults are skipped. A collector failing only partially
+    /// returns Error::Partial, holding the entries collected anyway
+    fn collect(&mut self, sys: &mut System) -> Result<Entries, Error>;
+}
+
+
+/// Drop entries that hold nothing but the default values
+fn skip_defaults<T: DefaultWithTime + PartialEq>(entries: Vec<T>) -> Vec<T> {
+    entries
+        .into_iter()
+        .filter(|entry| *entry != T::default_skip_time(entry))
+        .collect()
+}
+
+
+/// Collects SysStat entry
+#[derive(Debug, Default, Clone)]
+pub struct SysCollector {
+    /// Host identity stored with the entries
+    pub host_name: String,
+}
+
+
+impl Collector for SysCollector {
+    fn name(&self) -> &'static str {
+        "sys"
+    }
+
+
+    fn table(&self) -> &'static str {
+        "sys_stats"
+    }
+
+
+    fn collect(&mut self, sys: &mut System) -> Result<Entries, Error> {
+        sys.refresh_cpu();
+        sys.refresh_memory();
+        Ok(Entries::Sys(skip_defaults(vec![sys_stats_entry(
+            sys,
+            &self.host_name,
+            SystemTime::now(),
+        )])))
+    }
+}
+
+
+/// Collects UpsStat entry
+#[derive(Debug, Default, Clone)]
+pub struct UpsCollector {
+    /// Host identity stored with the entries
+    pub host_name: String,
+    /// NUT server settings
+    pub config: UpsConfig,
+}
+
+
+impl Collector for UpsCollector {
+    fn name(&self) -> &'static str {
+        "ups"
+    }
+
+
+    fn table(&self) -> &'static str {
+        "ups_stats"
+    }
+
+
+    fn collect(&mut self, _sys: &mut System) -> Result<Entries, Error> {
+        let entry = ups_stats_entry(&self.config, &self.host_name, SystemTime::now())?;
+        Ok(Entries::Ups(skip_defaults(vec![entry])))
+    }
+}
+
+
+/// Collects DiskStat entries
+#[derive(Debug, Default, Clone)]
+pub struct DiskCollector {
+    /// Host identity stored with the entries
+    pub host_name: String,
+}
+
+
+impl Collector for DiskCollector {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+
+    fn table(&self) -> &'static str {
+        "disk_stats"
+    }
+
+
+    fn default_interval(&self) -> Duration {
+        // spawning smartctl for each disk is expensive, and SMART attributes change slowly
+        Duration::from_secs(600)
+    }
+
+
+    fn collect(&mut self, _sys: &mut System) -> Result<Entries, Error> {
+        let entries = disk_stats_entry(&self.host_name, SystemTime::now())?;
+        Ok(Entries::Disk(skip_defaults(entries)))
+    }
+}
+
+
+/// Collects ProcStat entries
+#[derive(Debug, Default, Clone)]
+pub struct ProcCollector {
+    /// Host identity stored with the entries
+    pub host_name: String,
+}
+
+
+impl Collector for ProcCollector {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+
+    fn table(&self) -> &'static str {
+        "proc_stats"
+    }
+
+
+    fn collect(&mut self, sys: &mut System) -> Result<Entries, Error> {
+        sys.refresh_processes();
+        Ok(Entries::Proc(skip_defaults(sys_process_entries(
+            sys,
+            &self.host_name,
+            SystemTime::now(),
+        ))))
+    }
+}
+
+
+/// Collects NetStat entries
+#[derive(Debug, Default, Clone)]
+pub struct NetCollector {
+    /// Host identity stored with the entries
+    pub host_name: String,
+}
+
+
+impl Collector for NetCollector {
+    fn name(&self) -> &'static str {
+        "net"
+    }
+
+
+    fn table(&self) -> &'static str {
+        "net_stats"
+    }
+
+
+    fn collect(&mut self, sys: &mut System) -> Result<Entries, Error> {
+        sys.refresh_networks_list();
+        sys.refresh_networks();
+        Ok(Entries::Net(skip_defaults(net_stats_entries(
+            sys,
+            &self.host_name,
+            SystemTime::now(),
+        ))))
+    }
+}
+
+
+/// Create a builtin collector by its name
+pub fn builtin_collector(
+    name: &str,
+    config: &CollectorsConfig,
+    host_name: &str,
+) -> Option<Box<dyn Collector>> {
+    let host_name = host_name.to_string();
+    match name {
+        "sys" => {
+            Some(Box::new(SysCollector {
+                host_name,
+            }))
+        }
+        "ups" => {
+            Some(Box::new(UpsCollector {
+                host_name,
+                config: config.ups.clone(),
+            }))
+        }
+        "disk" => {
+            Some(Box::new(DiskCollector {
+                host_name,
+            }))
+        }
+        "proc" => {
+            Some(Box::new(ProcCollector {
+                host_name,
+            }))
+        }
+        "net" => {
+            Some(Box::new(NetCollector {
+                host_name,
+            }))
+        }
+        _ => None,
+    }
+}
+
+
+/// Registry of the enabled collectors
+#[derive(Debug, Default)]
+pub struct Registry {
+    collectors: Vec<Box<dyn Collector>>,
+}
+
+
+impl Registry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Registry with builtin collectors enabled in the configuration,
+    /// storing given host identity with the entries
+    pub fn from_config(config: &CollectorsConfig, host_name: &str) -> Self {
+        let mut registry = Self::new();
+        for name in config.enabled() {
+            match builtin_collector(name, config, host_name) {
+                Some(collector) => registry.register(collector),
+                None => warn!("Unknown collector: '{name}'. Skipping."),
+            }
+        }
+        registry
+    }
+
+
+    /// Register a collector. Collector with the same name gets replaced
+    pub fn register(&mut self, collector: Box<dyn Collector>) {
+        self.collectors
+            .retain(|registered| registered.name() != collector.name());
+        self.collectors.push(collector);
+    }
+
+
+    /// Names of the registered collectors
+    pub fn names(&self) -> Vec<&'static str> {
+        self.collectors
+            .iter()
+            .map(|collector| collector.name())
+            .collect()
+    }
+
+
+    /// True if there are no collectors registered
+    pub fn is_empty(&self) -> bool {
+        self.collectors.is_empty()
+    }
+
+
+    /// Take the registered collectors out of the registry
+    pub fn into_collectors(self) -> Vec<Box<dyn Collector>> {
+        self.collectors
+    }
+}
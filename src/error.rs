@@ -0,0 +1,200 @@
+use crate::{config::ConfigError, postgres::SchemaError, *};
+use nut_client::ClientError;
+use std::{fmt, io};
+
+
+/// Errors of the agent
+#[derive(Debug)]
+pub enum Error {
+    /// Can't connect to the NUT server
+    NutConnection {
+        /// UPS name and NUT server address, like: "ups@localhost:3493"
+        ups: String,
+        /// Cause of the failure
+        source: ClientError,
+    },
+    /// NUT server doesn't provide the UPS variable
+    NutVariable {
+        /// UPS name and NUT server address
+        ups: String,
+        /// Name of the variable
+        variable: &'static str,
+        /// Cause of the failure
+        source: ClientError,
+    },
+    /// Can't list the disk devices
+    DiskList(String),
+    /// smartctl failed to read the device
+    Smartctl {
+        /// Path to the device
+        device: String,
+        /// Cause of the failure
+        reason: String,
+    },
+    /// Part of the collection failed. Holds the entries collected anyway
+    Partial {
+        /// Entries collected despite the errors
+        entries: Entries,
+        /// Errors of the failed parts
+        errors: Vec<Error>,
+    },
+    /// Sink can't write the entries now. Holds the reason
+    Unavailable(String),
+    /// Can't establish the database connection
+    Connection(diesel::ConnectionError),
+    /// Database query failed
+    Database(diesel::result::Error),
+    /// Database schema can't be used
+    Schema(SchemaError),
+    /// Invalid configuration
+    Config(ConfigError),
+    /// TimescaleDB policies of the table can't be managed
+    Policy {
+        /// Name of the table
+        table: &'static str,
+        /// Cause of the failure
+        reason: String,
+    },
+    /// HTTP request failed, or was rejected by the server
+    Http {
+        /// URL of the request
+        url: String,
+        /// Cause of the failure, with the response body if any
+        reason: String,
+    },
+    /// Action notifying of an alert failed
+    Action {
+        /// Name of the action
+        action: String,
+        /// Cause of the failure
+        reason: String,
+    },
+    /// I/O error
+    Io(io::Error),
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NutConnection {
+                ups,
+                source,
+            } => write!(f, "Can't connect to the NUT server of: {ups}: {source}"),
+            Error::NutVariable {
+                ups,
+                variable,
+                source,
+            } => write!(f, "No {variable} of UPS: {ups}: {source}"),
+            Error::DiskList(reason) => write!(f, "Can't list the disks: {reason}"),
+            Error::Smartctl {
+                device,
+                reason,
+            } => write!(f, "smartctl failed for: {device}: {reason}"),
+            Error::Partial {
+                errors, ..
+            } => {
+                let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+                write!(f, "{}", errors.join("; "))
+            }
+            Error::Unavailable(reason) => write!(f, "{reason}"),
+            Error::Connection(error) => write!(f, "TimescaleDB Connection Failure: {error}"),
+            Error::Database(error) => write!(f, "{error}"),
+            Error::Schema(error) => write!(f, "{error}"),
+            Error::Config(error) => write!(f, "{error}"),
+            Error::Policy {
+                table,
+                reason,
+            } => write!(f, "Can't manage the policies of: {table}: {reason}"),
+            Error::Http {
+                url,
+                reason,
+            } => write!(f, "HTTP request to: {url} failed: {reason}"),
+            Error::Action {
+                action,
+                reason,
+            } => write!(f, "Alert action: {action} failed: {reason}"),
+            Error::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+
+impl std::error::Error for Error {}
+
+
+impl Error {
+    /// True if the error can't go away without the operator's action,
+    /// so there is no point in running any longer. Failures to read or apply
+    /// the migrations may be transient, and aren't fatal
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::Schema(SchemaError::Newer(_) | SchemaError::Pending(_)) | Error::Config(_)
+        )
+    }
+}
+
+
+impl From<diesel::ConnectionError> for Error {
+    fn from(error: diesel::ConnectionError) -> Self {
+        Error::Connection(error)
+    }
+}
+
+
+impl From<diesel::result::Error> for Error {
+    fn from(error: diesel::result::Error) -> Self {
+        Error::Database(error)
+    }
+}
+
+
+impl From<SchemaError> for Error {
+    fn from(error: SchemaError) -> Self {
+        Error::Schema(error)
+    }
+}
+
+
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Self {
+        Error::Config(error)
+    }
+}
+
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+
+impl From<ureq::Error> for Error {
+    fn from(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let url = response.get_url().to_string();
+                let body = response.into_string().unwrap_or_default();
+                Error::Http {
+                    url,
+                    reason: format!("status: {status}: {}", body.trim()),
+                }
+            }
+            ureq::Error::Transport(transport) => {
+                let url = transport.url().map(ToString::to_string).unwrap_or_default();
+                // the transport error starts with the URL already:
+                let message = transport.to_string();
+                let reason = message
+                    .strip_prefix(&format!("{url}: "))
+                    .unwrap_or(&message)
+                    .to_string();
+                Error::Http {
+                    url,
+                    reason,
+                }
+            }
+        }
+    }
+}
@@ -23,11 +23,7 @@
     unused_comparisons,
     unused_parens,
     while_true,
-    missing_debug_implementations,
-    trivial_casts,
-    trivial_numeric_casts,
-    unused_extern_crates,
-    unused_import_braces
+    unused_extern_crates
 )]
 
 
@@ -39,21 +35,52 @@ extern crate diesel;
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 
+/// Percentile continuous aggregates of TimescaleDB
+pub mod aggregates;
+/// Threshold alerting over the collected entries
+pub mod alerting;
+/// Collectors API
+pub mod collector;
+/// Configuration file
+pub mod config;
+/// Errors of the agent
+pub mod error;
 /// RDBM models
 pub mod models;
+/// Single instance protection
+pub mod pidfile;
+/// Output format independent measurements
+pub mod point;
+/// TimescaleDB retention, compression and chunk policies
+pub mod policies;
 /// Postgres functions
 pub mod postgres;
+/// Reading the stored entries back
+pub mod query;
+/// Collectors scheduler
+pub mod scheduler;
 /// Autogenerated Diesel schema
 #[allow(missing_docs)]
 pub mod schema;
+/// Process signals handling
+pub mod signals;
+/// Outputs of the agent
+pub mod sink;
+/// Local spool of unwritten entries
+pub mod spool;
 /// System info API
 pub mod systeminfo;
 /// UPS API
 pub mod ups;
 
 
-pub use models::{DiskStat, NetStat, ProcStat, SysStat, UpsStat};
-pub use schema::{disk_stats, net_stats, proc_stats, sys_stats, ups_stats};
+pub use collector::{Collector, Entries, Registry};
+pub use config::Config;
+pub use error::Error;
+pub use models::{Alert, CollectorRun, DiskStat, NetStat, ProcStat, SysStat, UpsStat};
+pub use scheduler::Scheduler;
+pub use sink::{Sink, Sinks};
+pub use schema::{alerts, collector_runs, disk_stats, net_stats, proc_stats, sys_stats, ups_stats};
 pub use std::{
     fmt::Display,
     time::{SystemTime, UNIX_EPOCH},
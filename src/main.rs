@@ -1,79 +1,686 @@
 //! "Dcollector" TimescaleDB agent.
 
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use dcollector::{
-    postgres::{establish_postgres_connection, store_entries},
+    aggregates::create_percentiles,
+    alerting::Alerting,
+    config::ConfigError,
+    pidfile::PidFile,
+    policies::{apply_policies, plan_policies},
+    postgres::{establish_postgres_connection, prepare_schema, schema_status, SchemaError},
+    query::{parse_window, points_table, read_entries, summarize, window_start, Table},
+    signals::Signals,
+    sink::{otlp::SpanLayer, Sinks},
     *,
 };
+use diesel::PgConnection;
 use dotenv::dotenv;
-use std::{env, thread, time::Duration};
+use std::{io, path::PathBuf, process::ExitCode, thread, time::Duration};
 use sysinfo::{System, SystemExt};
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+
+/// Data collector, that uses TimescaleDB to store the data
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the configuration file [default: $DCOLLECTOR_CONFIG or /etc/dcollector.toml]
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Log filter, like: "debug" or "dcollector=trace". Overrides the configuration
+    #[arg(short, long, global = true)]
+    log_level: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+
+/// Commands of the agent
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// Collect and store the data continuously (default)
+    Run,
+    /// Collect and store the data once, then exit
+    Once,
+    /// Collect the data once and print it, without touching the database
+    Print {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Apply pending database schema migrations
+    Migrate {
+        /// Only report the pending migrations, without applying them
+        #[arg(long)]
+        check: bool,
+    },
+    /// Manage the TimescaleDB retention, compression and chunk policies
+    Policies {
+        #[command(subcommand)]
+        action: PoliciesAction,
+    },
+    /// Read the stored entries of the host back from the database
+    Query(QueryArgs),
+}
+
+
+/// Arguments of the query command
+#[derive(Debug, Clone, Args)]
+struct QueryArgs {
+    /// Entries to read, or their summary
+    #[arg(value_enum, default_value_t = Subject::Summary)]
+    subject: Subject,
+    /// Host name [default: the host identity]
+    #[arg(long)]
+    host: Option<String>,
+    /// Time window, like: "30m", "1h", "2d" or "1w"
+    #[arg(short, long, default_value = "1h", value_parser = parse_window)]
+    since: Duration,
+    /// Max amount of the rows read
+    #[arg(long, default_value_t = 1000)]
+    limit: i64,
+    /// Amount of the disks and processes in the summary
+    #[arg(long, default_value_t = 5)]
+    top: i64,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+
+/// What the query command reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Subject {
+    /// Latest system and UPS stats, hottest disks and top processes
+    Summary,
+    /// System stats
+    Sys,
+    /// UPS stats
+    Ups,
+    /// Disk stats
+    Disk,
+    /// Processes stats
+    Proc,
+    /// Network stats
+    Net,
+    /// Collector runs
+    Runs,
+    /// Alerts history
+    Alerts,
+}
+
+
+/// Actions of the policies command
+#[derive(Debug, Clone, Copy, Subcommand)]
+enum PoliciesAction {
+    /// Make the policies of the managed tables match the configuration
+    Apply {
+        /// Only report the changes, without making them
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+
+/// Output format of the print and query commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Human readable lines
+    Text,
+    /// Table for each collector
+    Table,
+    /// JSON line for each collector
+    Json,
+}
+
+
+/// Changes the filter of the running logger
+type LogReloader = Box<dyn Fn(&str) -> Result<(), String>>;
 
 
 /// Initialize logger and tracingformatter
 #[instrument]
-fn initialize() {
-    let env_log = match EnvFilter::try_from_env("LOG") {
-        Ok(env_value_from_env) => env_value_from_env,
-        Err(_) => EnvFilter::from("info"),
-    };
-    fmt()
+fn initialize(log_filter: &str) -> LogReloader {
+    let builder = fmt()
         .compact()
         .with_thread_names(false)
         .with_thread_ids(false)
         .with_ansi(true)
-        .with_env_filter(env_log)
-        .with_filter_reloading()
-        .init();
+        .with_writer(io::stderr)
+        .with_env_filter(EnvFilter::from(log_filter))
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.finish().with(SpanLayer).init();
+    Box::new(move |log_filter| {
+        let filter = EnvFilter::try_new(log_filter).map_err(|error| error.to_string())?;
+        handle.reload(filter).map_err(|error| error.to_string())
+    })
 }
 
 
-/// main()
-#[instrument]
-fn main() {
-    initialize();
-    dotenv().ok();
+/// Where the configuration comes from, so it can be loaded again
+#[derive(Debug, Clone)]
+struct ConfigSource {
+    /// Path to the configuration file
+    path: Option<PathBuf>,
+    /// Log filter overriding the configuration
+    log_level: Option<String>,
+}
 
-    let sleep = env::var("SLEEP_SECONDS")
-        .unwrap_or_else(|_| String::from("5"))
-        .parse::<u64>()
-        .unwrap_or(5);
 
-    info!(
-        "Starting dcollector, version: {}",
-        env!("CARGO_PKG_VERSION")
-    );
+impl ConfigSource {
+    /// Load and validate the configuration
+    fn load(&self, needs_sinks: bool) -> Result<Config, ConfigError> {
+        let mut config = Config::load(self.path.as_deref())?;
+        if let Some(log_level) = &self.log_level {
+            config.log.filter = log_level.to_owned();
+        }
+        config.validate(needs_sinks)?;
+        Ok(config)
+    }
+}
+
+
+/// Establish the database connection using the configuration
+fn connect(config: &Config) -> Result<PgConnection, Error> {
+    let database_url = config.sinks.postgres.database_url()?;
+    Ok(establish_postgres_connection(&database_url)?)
+}
 
+
+/// Scheduler of the collectors enabled in the configuration
+fn scheduler(config: &Config, host_name: &str) -> Scheduler {
+    let registry = Registry::from_config(&config.collectors, host_name);
+    info!("Enabled collectors: {:?}", registry.names());
+    Scheduler::new(registry, host_name, |collector| {
+        config
+            .collectors
+            .interval_of(collector.name())
+            .unwrap_or_else(|| collector.default_interval())
+    })
+}
+
+
+/// Host identity stored with the entries
+fn host_name(config: &Config) -> Result<String, ConfigError> {
+    let host_name = config.host.identity()?;
+    info!("Host identity: {host_name}");
+    Ok(host_name)
+}
+
+
+/// Collect the data of all enabled collectors once
+fn collect_once(config: &Config, host_name: &str) -> Vec<Entries> {
+    let mut scheduler = scheduler(config, host_name);
+    let mut system = System::new_all();
+    // CPU usage is computed between two refreshes:
+    thread::sleep(Duration::from_millis(250));
+    scheduler.run_all(&mut system)
+}
+
+
+/// Alert rules of the agent, unless the alerting is disabled
+fn alerting(config: &Config, host_name: &str) -> Result<Option<Alerting>, ConfigError> {
+    if !config.alerts.enabled {
+        return Ok(None);
+    }
+    let alerting = Alerting::new(&config.alerts, host_name)?;
+    info!("Alert rules: {:?}", alerting.names());
+    Ok(Some(alerting))
+}
+
+
+/// State of the continuously running agent, built from the configuration
+#[derive(Debug)]
+struct Agent {
+    config: Config,
+    sinks: Sinks,
+    scheduler: Scheduler,
+    alerting: Option<Alerting>,
+}
+
+
+impl Agent {
+    /// Setup the agent using the configuration
+    fn new(config: Config) -> Result<Self, ConfigError> {
+        let host_name = host_name(&config)?;
+        let sinks = Sinks::from_config(&config.sinks, &host_name)?;
+        info!("Enabled sinks: {:?}", sinks.names());
+        let scheduler = scheduler(&config, &host_name);
+        let alerting = alerting(&config, &host_name)?;
+        Ok(Self {
+            config,
+            sinks,
+            scheduler,
+            alerting,
+        })
+    }
+
+
+    /// Replace the configuration. The sinks are kept if their settings
+    /// and the host identity didn't change. So are the alerts, if their settings didn't
+    fn reload(&mut self, config: Config) -> Result<(), ConfigError> {
+        if config.sinks == self.config.sinks && config.host == self.config.host {
+            let host_name = host_name(&config)?;
+            if config.alerts != self.config.alerts {
+                self.alerting = alerting(&config, &host_name)?;
+            }
+            self.scheduler = scheduler(&config, &host_name);
+            self.config = config;
+            return Ok(());
+        }
+        let agent = Self::new(config)?;
+        self.sinks.flush();
+        *self = agent;
+        Ok(())
+    }
+
+
+    /// Collect the data of the collectors due in this tick, evaluate the alert rules,
+    /// then write it to the sinks. Returns false if the agent can't continue
+    fn iteration(&mut self, system: &mut System, iteration: u128) -> bool {
+        let mut entries = self.scheduler.run_due(system);
+        if let Some(alerting) = &mut self.alerting {
+            let alerts = alerting.evaluate(&entries);
+            if !alerts.is_empty() {
+                entries.push(Entries::Alerts(alerts));
+            }
+        }
+        self.sinks.set_missed_ticks(self.scheduler.missed_ticks());
+        match self.sinks.write(&entries) {
+            Ok(0) => debug!("Iteration #{iteration} was successful."),
+            Ok(failed) => warn!("Iteration #{iteration} failed in {failed} sink(s)."),
+            Err(error) => {
+                error!("{error}");
+                return false;
+            }
+        }
+        true
+    }
+}
+
+
+/// Collect and store the data continuously, until a shutdown signal.
+/// SIGHUP reloads the configuration
+fn run(config: Config, source: &ConfigSource, reload_log: &LogReloader) -> ExitCode {
+    let signals = match Signals::register() {
+        Ok(signals) => signals,
+        Err(error) => {
+            error!("Can't register the signal handlers: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
     // setup once per runtime:
+    let mut agent = match Agent::new(config) {
+        Ok(agent) => agent,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // a database schema the agent can't write to stops it before the first tick:
+    if let Err(error) = agent.sinks.prepare() {
+        error!("{error}");
+        return ExitCode::FAILURE;
+    }
     let mut system = System::new_all();
     let mut iteration = 0u128;
     loop {
+        if agent.scheduler.is_empty() {
+            warn!("No collectors enabled. Nothing to do.");
+            return ExitCode::FAILURE;
+        }
+        if !agent.scheduler.wait_for_next_tick_or(|| signals.pending()) {
+            if signals.shutdown_requested() {
+                break;
+            }
+            if signals.take_reload() {
+                info!("Reloading the configuration…");
+                match source.load(true).and_then(|config| {
+                    let log_filter = config.log.filter.to_owned();
+                    agent.reload(config)?;
+                    Ok(log_filter)
+                }) {
+                    Ok(log_filter) => {
+                        if let Err(error) = reload_log(&log_filter) {
+                            error!("Can't reload the log filter: {error}");
+                        }
+                        info!("Configuration reloaded.");
+                    }
+                    Err(error) => {
+                        error!("Configuration not reloaded. Keeping the current one. {error}")
+                    }
+                }
+            }
+            continue;
+        }
+
         iteration += 1;
         debug!("Iteration #{iteration} is starting…");
+        // a shutdown requested during the iteration waits until its writes are finished:
+        if !agent.iteration(&mut system, iteration) {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    info!("Shutting down…");
+    agent.sinks.flush();
+    info!("Stopped after {iteration} iteration(s).");
+    ExitCode::SUCCESS
+}
+
+
+/// Collect the data once and write it to the sinks
+fn once(config: &Config) -> ExitCode {
+    let host_name = match host_name(config) {
+        Ok(host_name) => host_name,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut sinks = match Sinks::from_config(&config.sinks, &host_name) {
+        Ok(sinks) => sinks,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(error) = sinks.prepare() {
+        error!("{error}");
+        return ExitCode::FAILURE;
+    }
+    let entries = collect_once(config, &host_name);
+    let written = sinks.write(&entries);
+    let not_flushed = sinks.flush();
+    match written {
+        Ok(0) if not_flushed == 0 => {
+            info!(
+                "Written {} entries to: {:?}.",
+                entries.iter().map(Entries::len).sum::<usize>(),
+                sinks.names()
+            );
+            ExitCode::SUCCESS
+        }
+        Ok(_) => ExitCode::FAILURE,
+        Err(error) => {
+            error!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// Collect the data once and print it
+fn print(config: &Config, format: Format) -> ExitCode {
+    let all_entries = match host_name(config) {
+        Ok(host_name) => collect_once(config, &host_name),
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    output(&all_entries, format)
+}
+
+
+/// Print the entries in the format
+fn output(all_entries: &[Entries], format: Format) -> ExitCode {
+    for entries in all_entries {
+        match format {
+            Format::Text => {
+                for line in entries.to_lines() {
+                    println!("[{}] {line}", entries.table());
+                }
+            }
+            Format::Table => {
+                println!("[{}]", entries.table());
+                print!("{}", points_table(&entries.to_points()));
+            }
+            Format::Json => {
+                match serde_json::to_string(&entries) {
+                    Ok(json) => println!("{json}"),
+                    Err(error) => {
+                        error!("Failed to serialize {} entries: {error}", entries.table());
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
 
-        // refresh the data every iteration
-        system.refresh_all();
 
-        // Continously attempt to make connection with the configured TimescaleDB:
-        let mut pg_conn = match establish_postgres_connection() {
-            Ok(connection) => connection,
+/// Apply pending database schema migrations, or only report them
+fn migrate(config: &Config, check: bool) -> ExitCode {
+    let mut pg_conn = match connect(config) {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if check {
+        return match schema_status(&mut pg_conn) {
+            Ok(status) if status.unknown.is_empty() && status.pending.is_empty() => {
+                info!("Database schema is up to date.");
+                ExitCode::SUCCESS
+            }
+            Ok(status) => {
+                if !status.unknown.is_empty() {
+                    error!("{}", SchemaError::Newer(status.unknown));
+                }
+                if !status.pending.is_empty() {
+                    warn!("Pending migrations: {}", status.pending.join(", "));
+                }
+                ExitCode::FAILURE
+            }
             Err(error) => {
-                error!(
-                    "Sleeping 5s while we experience TimescaleDB Connection Failure: {error}",
-                );
-                thread::sleep(Duration::from_secs(5));
-                continue;
+                error!("{error}");
+                ExitCode::FAILURE
             }
         };
+    }
+    match prepare_schema(&mut pg_conn, true) {
+        Ok(versions) if versions.is_empty() => info!("Database schema is up to date."),
+        Ok(versions) => info!("Applied migrations: {}", versions.join(", ")),
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if !config.sinks.postgres.percentiles {
+        return ExitCode::SUCCESS;
+    }
+    match create_percentiles(&mut pg_conn) {
+        Ok(created) => {
+            for view in created {
+                info!("Continuous aggregate created: {view}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("Percentile aggregates not created: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-        match store_entries(&mut system, &mut pg_conn) {
-            Ok(_) => debug!("Iteration #{iteration} was successful."),
-            Err(error) => {
-                error!("Iteration #{iteration} failed with error: {error}");
-                thread::sleep(Duration::from_secs(5));
-                continue;
+
+/// Make the TimescaleDB policies match the configuration, or only report the changes
+fn policies(config: &Config, action: PoliciesAction) -> ExitCode {
+    let PoliciesAction::Apply {
+        check,
+    } = action;
+    let policies = &config.sinks.postgres.policies;
+    if policies.tables().is_empty() {
+        warn!("No tables with policies configured. Nothing to do.");
+        return ExitCode::SUCCESS;
+    }
+    let mut pg_conn = match connect(config) {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let changes = if check {
+        plan_policies(&mut pg_conn, policies)
+    } else {
+        apply_policies(&mut pg_conn, policies)
+    };
+    match changes {
+        Ok(changes) if changes.is_empty() => {
+            info!("Policies are up to date.");
+            ExitCode::SUCCESS
+        }
+        Ok(changes) if check => {
+            for change in changes {
+                warn!("Pending policy change: {change}");
             }
+            ExitCode::FAILURE
+        }
+        Ok(changes) => {
+            for change in changes {
+                info!("Policy changed: {change}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            error!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+
+/// Read the stored entries of the host back, or summarize them
+fn query(config: &Config, args: QueryArgs) -> ExitCode {
+    let QueryArgs {
+        subject,
+        host,
+        since,
+        limit,
+        top,
+        format,
+    } = args;
+    let host = match host.map_or_else(|| host_name(config), Ok) {
+        Ok(host) => host,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut pg_conn = match connect(config) {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let since = window_start(since, SystemTime::now());
+    let table = match subject {
+        Subject::Summary => {
+            let summary = match summarize(&mut pg_conn, &host, since, top) {
+                Ok(summary) => summary,
+                Err(error) => {
+                    error!("{error}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            match format {
+                Format::Text => print!("{summary}"),
+                Format::Table => print!("{}", summary.to_tables()),
+                Format::Json => {
+                    match serde_json::to_string(&summary) {
+                        Ok(json) => println!("{json}"),
+                        Err(error) => {
+                            error!("Failed to serialize the summary: {error}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+            }
+            return ExitCode::SUCCESS;
+        }
+        Subject::Sys => Table::Sys,
+        Subject::Ups => Table::Ups,
+        Subject::Disk => Table::Disk,
+        Subject::Proc => Table::Proc,
+        Subject::Net => Table::Net,
+        Subject::Runs => Table::Runs,
+        Subject::Alerts => Table::Alerts,
+    };
+    match read_entries(&mut pg_conn, table, &host, since, limit) {
+        Ok(entries) if entries.is_empty() => {
+            warn!(
+                "No {} entries of: {host} in the time window.",
+                entries.table()
+            );
+            ExitCode::SUCCESS
+        }
+        Ok(entries) => output(&[entries], format),
+        Err(error) => {
+            error!("{error}");
+            ExitCode::FAILURE
         }
-        thread::sleep(Duration::from_secs(sleep));
+    }
+}
+
+
+/// main()
+fn main() -> ExitCode {
+    dotenv().ok();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
+
+    let source = ConfigSource {
+        path: cli.config,
+        log_level: cli.log_level,
+    };
+    let config = match source.load(!matches!(command, Command::Print { .. })) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let reload_log = initialize(&config.log.filter);
+
+    info!(
+        "Starting dcollector, version: {}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    // only one instance may write the data of the host:
+    let _pid_file = match (&config.pid_file, &command) {
+        (Some(path), Command::Run | Command::Once) => {
+            match PidFile::acquire(path) {
+                Ok(pid_file) => Some(pid_file),
+                Err(error) => {
+                    error!("{error}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => None,
+    };
+
+    match command {
+        Command::Run => run(config, &source, &reload_log),
+        Command::Once => once(&config),
+        Command::Print {
+            format,
+        } => print(&config, format),
+        Command::Migrate {
+            check,
+        } => migrate(&config, check),
+        Command::Policies {
+            action,
+        } => policies(&config, action),
+        Command::Query(args) => query(&config, args),
     }
 }
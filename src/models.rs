@@ -2,53 +2,63 @@ use crate::*;
 
 use chrono::{DateTime, Local, TimeZone};
 use core::fmt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 
 /// SysStat holds one row of system stats
-#[derive(Debug, Clone, Deserialize, Insertable, Queryable, PartialEq)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
+#[diesel(treat_none_as_default_value = false)]
 pub struct NetStat {
     /// PK
     pub time: SystemTime,
+    /// Holds the host name
+    pub host_name: String,
     /// Holds network device name
-    pub netdev: Option<String>,
+    pub netdev: String,
     /// Packets received since the last refresh
-    pub packets_received: Option<i64>,
+    pub packets_received: i64,
     /// Packets received since the boot
-    pub total_packets_received: Option<i64>,
+    pub total_packets_received: i64,
     /// Packets transmitted since the last refresh
-    pub packets_transmitted: Option<i64>,
+    pub packets_transmitted: i64,
     /// Packets transmitted since the boot
-    pub total_packets_transmitted: Option<i64>,
+    pub total_packets_transmitted: i64,
 
     /// Bytes received since the boot
-    pub received: Option<i64>,
+    pub received: i64,
     /// Bytes received since the boot
-    pub total_received: Option<i64>,
+    pub total_received: i64,
     /// Bytes transmitted since the last refresh
-    pub transmitted: Option<i64>,
+    pub transmitted: i64,
     /// Bytes transmitted since the boot
-    pub total_transmitted: Option<i64>,
+    pub total_transmitted: i64,
 
     /// Transmitted errors since the last refresh
-    pub transmitted_errors: Option<i64>,
+    pub transmitted_errors: i64,
     /// Total transmitted errors since the boot
-    pub transmitted_total_errors: Option<i64>,
+    pub transmitted_total_errors: i64,
 
     /// Received errors since the last refresh
-    pub received_errors: Option<i64>,
+    pub received_errors: i64,
     /// Received errors since the last boot
-    pub received_total_errors: Option<i64>,
+    pub received_total_errors: i64,
 }
 
 
 /// ProcStat holds one row of user processes with resources usage
-#[derive(Debug, Clone, Deserialize, Insertable, Queryable, PartialEq)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
+#[diesel(treat_none_as_default_value = false)]
 pub struct ProcStat {
     /// PK
     pub time: SystemTime,
     /// Holds hostname where process is running
-    pub host_name: Option<String>,
+    pub host_name: String,
+    /// Holds process identifier
+    pub pid: i32,
     /// Holds time, when process started
     pub start_time: Option<SystemTime>,
     /// Holds abs path to executable
@@ -78,19 +88,20 @@ impl Default for NetStat {
     fn default() -> NetStat {
         NetStat {
             time: SystemTime::now(),
-            netdev: None,
-            packets_received: None,
-            total_packets_received: None,
-            packets_transmitted: None,
-            total_packets_transmitted: None,
-            received: None,
-            total_received: None,
-            transmitted: None,
-            total_transmitted: None,
-            transmitted_errors: None,
-            transmitted_total_errors: None,
-            received_errors: None,
-            received_total_errors: None,
+            host_name: String::new(),
+            netdev: String::new(),
+            packets_received: 0,
+            total_packets_received: 0,
+            packets_transmitted: 0,
+            total_packets_transmitted: 0,
+            received: 0,
+            total_received: 0,
+            transmitted: 0,
+            total_transmitted: 0,
+            transmitted_errors: 0,
+            transmitted_total_errors: 0,
+            received_errors: 0,
+            received_total_errors: 0,
         }
     }
 }
@@ -100,7 +111,8 @@ impl Default for ProcStat {
     fn default() -> ProcStat {
         ProcStat {
             time: SystemTime::now(),
-            host_name: None,
+            host_name: String::new(),
+            pid: 0,
             start_time: None,
             exe: None,
             cmd: None,
@@ -118,7 +130,9 @@ impl Default for ProcStat {
 
 
 /// SysStat holds one row of system stats
-#[derive(Debug, Clone, Deserialize, Insertable, Queryable, PartialEq)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
 pub struct SysStat {
     /// PK
     pub time: SystemTime,
@@ -129,7 +143,7 @@ pub struct SysStat {
     /// Holds system version
     pub os_version: Option<String>,
     /// Holds machine's host name
-    pub host_name: Option<String>,
+    pub host_name: String,
     /// Holds amount of processors on the machine
     pub processors: Option<i32>,
     /// Holds total memory available on the machine
@@ -158,7 +172,7 @@ impl Default for SysStat {
             name: None,
             kernel_version: None,
             os_version: None,
-            host_name: None,
+            host_name: String::new(),
             processors: None,
             total_memory: None,
             used_memory: None,
@@ -174,14 +188,17 @@ impl Default for SysStat {
 
 
 /// upsStat holds one row of UPS data fetched from Nut server
-#[derive(Debug, Clone, Deserialize, Insertable, Queryable, PartialEq)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
+#[diesel(treat_none_as_default_value = false)]
 pub struct DiskStat {
     /// PK
     pub time: SystemTime,
     /// Holds the host name
-    pub host_name: Option<String>,
+    pub host_name: String,
     /// Holds the device name
-    pub name: Option<String>,
+    pub name: String,
     /// Holds the disk temperature
     pub temperature: Option<f64>,
     /// CRC errors counter
@@ -201,8 +218,8 @@ impl Default for DiskStat {
     fn default() -> DiskStat {
         DiskStat {
             time: SystemTime::now(),
-            host_name: None,
-            name: None,
+            host_name: String::new(),
+            name: String::new(),
             temperature: None,
             crc_errors: None,
             seek_time: None,
@@ -215,10 +232,14 @@ impl Default for DiskStat {
 
 
 /// upsStat holds one row of UPS data fetched from Nut server
-#[derive(Debug, Clone, Deserialize, Insertable, Queryable, PartialEq)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
 pub struct UpsStat {
     /// PK
     pub time: SystemTime,
+    /// Holds the host name
+    pub host_name: String,
     /// Holds UPS model name
     pub model: Option<String>,
     /// Holds UPS status
@@ -240,6 +261,7 @@ impl Default for UpsStat {
     fn default() -> UpsStat {
         UpsStat {
             time: SystemTime::now(),
+            host_name: String::new(),
             model: None,
             status: None,
             load: None,
@@ -252,9 +274,55 @@ impl Default for UpsStat {
 }
 
 
+/// CollectorRun holds one row of the agent self-monitoring, describing a single collector run
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
+pub struct CollectorRun {
+    /// PK, holds time, when the run started
+    pub time: SystemTime,
+    /// Holds the host name
+    pub host_name: String,
+    /// Holds the collector name
+    pub collector: String,
+    /// Holds the run duration in milliseconds
+    pub duration_ms: f64,
+    /// Holds amount of entries produced by the run
+    pub rows_produced: i32,
+    /// Holds amount of rows written to the database. Unknown if the write failed
+    pub rows_written: Option<i32>,
+    /// Holds the error of the run, if any
+    pub error: Option<String>,
+}
+
+
+/// Alert holds one row of the alerts history: an alert that started firing, or was resolved
+#[derive(
+    Debug, Clone, Serialize, Deserialize, Insertable, Queryable, Selectable, PartialEq,
+)]
+pub struct Alert {
+    /// PK, holds time of the entry that changed the alert state
+    pub time: SystemTime,
+    /// Holds the host name
+    pub host_name: String,
+    /// Holds the name of the alert rule
+    pub rule: String,
+    /// Holds the identity of the alerting entry, like: "device=sda". Empty for sys and ups
+    pub instance: String,
+    /// Holds the state of the alert: "firing" or "resolved"
+    pub state: String,
+    /// Holds the severity of the alert rule
+    pub severity: String,
+    /// Holds the observed value, if numeric
+    pub value: Option<f64>,
+    /// Holds the alert description, as notified
+    pub message: String,
+}
+
+
 /// Convert SystemTime to chrono DateTime
 #[instrument]
-fn system_time_to_date_time(t: SystemTime) -> DateTime<Local> {
+pub(crate) fn system_time_to_date_time(t: SystemTime) -> DateTime<Local> {
     let (sec, nsec) = match t.duration_since(UNIX_EPOCH) {
         Ok(dur) => (dur.as_secs() as i64, dur.subsec_nanos()),
         Err(e) => {
@@ -277,7 +345,7 @@ impl Display for DiskStat {
         write!(
             f,
             "Name: {name}, Temperature: {temperature}, CRC Errors: {crc_errors}, Seek Time: {seek_time}, Seek Error Rate: {seek_error_rate}, Throughput: {throughput}, Read Error Rate: {read_error_rate}",
-            name = self.name.clone().unwrap_or_default(),
+            name = self.name,
             temperature = self.temperature.unwrap_or_default(),
             crc_errors = self.crc_errors.unwrap_or_default(),
             seek_time = self.seek_time.unwrap_or_default(),
@@ -328,7 +396,7 @@ impl Display for SysStat {
             self.load_fifteen.unwrap_or_default(),
             self.kernel_version.clone().unwrap_or_default(),
             self.os_version.clone().unwrap_or_default(),
-            self.host_name.clone().unwrap_or_default(),
+            self.host_name,
             self.processors.unwrap_or_default(),
             self.total_memory.unwrap_or_default(),
             self.used_memory.unwrap_or_default(),
@@ -363,26 +431,62 @@ impl Display for NetStat {
             f,
             "Time: {}, packets_received: {}, total_packets_received: {}, packets_transmitted: {}, total_packets_transmitted: {}, received: {}, total_received: {}, transmitted: {}, total_transmitted: {}, transmitted_errors: {}, transmitted_total_errors: {}, received_errors: {}, received_total_errors: {}",
             system_time_to_date_time(self.time),
-            self.packets_received.unwrap_or_default(),
-            self.total_packets_received.unwrap_or_default(),
-            self.packets_transmitted.unwrap_or_default(),
-            self.total_packets_transmitted.unwrap_or_default(),
-            self.received.unwrap_or_default(),
-            self.total_received.unwrap_or_default(),
-            self.transmitted.unwrap_or_default(),
-            self.total_transmitted.unwrap_or_default(),
-            self.transmitted_errors.unwrap_or_default(),
-            self.transmitted_total_errors.unwrap_or_default(),
-            self.received_errors.unwrap_or_default(),
-            self.received_total_errors.unwrap_or_default(),
+            self.packets_received,
+            self.total_packets_received,
+            self.packets_transmitted,
+            self.total_packets_transmitted,
+            self.received,
+            self.total_received,
+            self.transmitted,
+            self.total_transmitted,
+            self.transmitted_errors,
+            self.transmitted_total_errors,
+            self.received_errors,
+            self.received_total_errors,
+        )
+    }
+}
+
+
+impl Display for CollectorRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Time: {}, Collector: {}, Duration: {:.3}ms, Rows produced: {}, Rows written: {}, Error: {}",
+            system_time_to_date_time(self.time),
+            self.collector,
+            self.duration_ms,
+            self.rows_produced,
+            self.rows_written
+                .map(|rows| rows.to_string())
+                .unwrap_or_default(),
+            self.error.clone().unwrap_or_default(),
+        )
+    }
+}
+
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Time: {}, Rule: {}, Instance: {}, State: {}, Severity: {}, Value: {}, Message: {}",
+            system_time_to_date_time(self.time),
+            self.rule,
+            self.instance,
+            self.state,
+            self.severity,
+            self.value.map(|value| value.to_string()).unwrap_or_default(),
+            self.message,
         )
     }
 }
 
 
 /// Common trait to implement a Default for a type, but we wish to skip the "time" field
+/// and the fields identifying the entry
 pub trait DefaultWithTime {
-    /// Return type Default, without the "time" field
+    /// Return type Default, without the "time" and identity fields
     fn default_skip_time(entry: &Self) -> Self;
 }
 
@@ -391,6 +495,8 @@ impl DefaultWithTime for ProcStat {
     fn default_skip_time(entry: &Self) -> Self {
         Self {
             time: entry.time,
+            host_name: entry.host_name.clone(),
+            pid: entry.pid,
             ..Self::default()
         }
     }
@@ -401,6 +507,7 @@ impl DefaultWithTime for SysStat {
     fn default_skip_time(entry: &Self) -> Self {
         Self {
             time: entry.time,
+            host_name: entry.host_name.clone(),
             ..Self::default()
         }
     }
@@ -411,6 +518,7 @@ impl DefaultWithTime for UpsStat {
     fn default_skip_time(entry: &Self) -> Self {
         Self {
             time: entry.time,
+            host_name: entry.host_name.clone(),
             ..Self::default()
         }
     }
@@ -421,6 +529,8 @@ impl DefaultWithTime for DiskStat {
     fn default_skip_time(entry: &Self) -> Self {
         Self {
             time: entry.time,
+            host_name: entry.host_name.clone(),
+            name: entry.name.clone(),
             ..Self::default()
         }
     }
@@ -431,6 +541,8 @@ impl DefaultWithTime for NetStat {
     fn default_skip_time(entry: &Self) -> Self {
         Self {
             time: entry.time,
+            host_name: entry.host_name.clone(),
+            netdev: entry.netdev.clone(),
             ..Self::default()
         }
     }
@@ -0,0 +1,206 @@
+use crate::*;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+
+/// Reasons the PID file can't be acquired
+#[derive(Debug)]
+pub enum PidFileError {
+    /// Another live instance holds the lock. Holds its PID, if readable
+    Locked(PathBuf, Option<u32>),
+    /// Can't create, lock or write the file
+    Io(PathBuf, io::Error),
+}
+
+
+impl fmt::Display for PidFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PidFileError::Locked(path, Some(pid)) => {
+                write!(
+                    f,
+                    "Another dcollector instance (PID {pid}) is running. PID file: {}",
+                    path.display()
+                )
+            }
+            PidFileError::Locked(path, None) => {
+                write!(
+                    f,
+                    "Another dcollector instance is running. PID file: {}",
+                    path.display()
+                )
+            }
+            PidFileError::Io(path, error) => {
+                write!(f, "Can't acquire the PID file: {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+
+impl std::error::Error for PidFileError {}
+
+
+/// True if the file is the one at the path, not removed or replaced since it was opened
+fn is_at(file: &File, path: &Path) -> io::Result<bool> {
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == opened.dev() && current.ino() == opened.ino()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+
+/// PID file locked for the lifetime of the process, so only one instance runs at a time.
+/// The lock is released by the OS when the process dies, so a file left by a dead process
+/// is simply taken over. The file is removed on drop
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+
+impl PidFile {
+    /// Create and lock the PID file, writing the PID of this process to it
+    #[instrument]
+    pub fn acquire(path: &Path) -> Result<Self, PidFileError> {
+        let io_error = |error| PidFileError::Io(path.to_path_buf(), error);
+        let mut contents = String::new();
+        let mut file = loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(io_error)?;
+            match file.try_lock() {
+                Ok(()) => (),
+                Err(TryLockError::WouldBlock) => {
+                    file.read_to_string(&mut contents).unwrap_or_default();
+                    return Err(PidFileError::Locked(
+                        path.to_path_buf(),
+                        contents.trim().parse().ok(),
+                    ));
+                }
+                Err(TryLockError::Error(error)) => return Err(io_error(error)),
+            }
+            // the instance exiting in between removes the file before unlocking it.
+            // The lock of the removed file guards nothing, so the path is opened again:
+            if is_at(&file, path).map_err(io_error)? {
+                break file;
+            }
+            debug!("PID file removed while locking it: {}", path.display());
+        };
+
+        file.read_to_string(&mut contents).map_err(io_error)?;
+        if !contents.trim().is_empty() {
+            warn!(
+                "Taking over a stale PID file of a dead process: {}: {}",
+                path.display(),
+                contents.trim()
+            );
+        }
+        file.set_len(0).map_err(io_error)?;
+        file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        writeln!(file, "{}", process::id()).map_err(io_error)?;
+        file.sync_all().map_err(io_error)?;
+
+        debug!("Acquired the PID file: {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+
+    /// Path to the PID file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // removed while still locked, so no other instance acquires the file being removed:
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!(
+                "Can't remove the PID file: {}: {error}",
+                self.path.display()
+            );
+        }
+        self.file.unlock().unwrap_or_default();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+
+    /// Path of the PID file, unique to the test
+    fn pid_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("dcollector-{}-{name}.pid", process::id()));
+        fs::remove_file(&path).unwrap_or_default();
+        path
+    }
+
+
+    #[test]
+    fn refuses_the_second_instance() {
+        let path = pid_path("second");
+        let pid_file = PidFile::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        // locks of the separately opened files exclude each other, even in one process:
+        match PidFile::acquire(&path) {
+            Err(PidFileError::Locked(locked, pid)) => {
+                assert_eq!((locked, pid), (path.to_owned(), Some(process::id())));
+            }
+            other => panic!("acquired twice: {other:?}"),
+        }
+
+        drop(pid_file);
+        assert!(!path.exists());
+        drop(PidFile::acquire(&path).unwrap());
+    }
+
+
+    #[test]
+    fn takes_over_the_stale_file() {
+        let path = pid_path("stale");
+        fs::write(&path, "4194305\n").unwrap();
+        let pid_file = PidFile::acquire(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(pid_file.path()).unwrap(),
+            format!("{}\n", process::id())
+        );
+    }
+
+
+    #[test]
+    fn tells_the_removed_file() {
+        let path = pid_path("removed");
+        let file = File::create(&path).unwrap();
+        assert!(is_at(&file, &path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(!is_at(&file, &path).unwrap());
+        // created again by another instance:
+        File::create(&path).unwrap();
+        assert!(!is_at(&file, &path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
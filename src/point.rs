@@ -0,0 +1,277 @@
+use crate::*;
+use std::time::SystemTime;
+
+
+/// Value of a point field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Floating point number
+    Float(f64),
+    /// Integer number
+    Integer(i64),
+    /// Text, like a status
+    Text(String),
+}
+
+
+impl Value {
+    /// Numeric value as float. None for the text values
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float(value) => Some(*value),
+            Value::Integer(value) => Some(*value as f64),
+            Value::Text(_) => None,
+        }
+    }
+}
+
+
+/// Field of a point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Name of the field, like a column name
+    pub name: &'static str,
+    /// Value of the field
+    pub value: Value,
+    /// True for counters, that only grow (until a restart)
+    pub monotonic: bool,
+}
+
+
+/// Measurement of a single entry, independent of the output format.
+/// Identifying values are tags, the measured ones are fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    /// Name of the measurement, the same as the table name
+    pub measurement: &'static str,
+    /// Identifying values, like the host name or the device name
+    pub tags: Vec<(&'static str, String)>,
+    /// Measured values. Missing values are skipped
+    pub fields: Vec<Field>,
+    /// Time of the measurement
+    pub time: SystemTime,
+}
+
+
+impl Point {
+    /// Empty point of the measurement
+    fn new(measurement: &'static str, time: SystemTime) -> Self {
+        Self {
+            measurement,
+            tags: vec![],
+            fields: vec![],
+            time,
+        }
+    }
+
+
+    /// Add the tag, unless its value is missing or empty
+    fn tag(mut self, name: &'static str, value: Option<&str>) -> Self {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            self.tags.push((name, value.to_string()));
+        }
+        self
+    }
+
+
+    /// Add the gauge field, unless its value is missing
+    fn gauge(mut self, name: &'static str, value: Option<Value>) -> Self {
+        if let Some(value) = value {
+            self.fields.push(Field {
+                name,
+                value,
+                monotonic: false,
+            });
+        }
+        self
+    }
+
+
+    /// Add the counter field, unless its value is missing
+    fn counter(mut self, name: &'static str, value: Option<i64>) -> Self {
+        if let Some(value) = value {
+            self.fields.push(Field {
+                name,
+                value: Value::Integer(value),
+                monotonic: true,
+            });
+        }
+        self
+    }
+}
+
+
+/// Conversion of an entry to a point
+pub trait ToPoint {
+    /// The entry as a point
+    fn to_point(&self) -> Point;
+}
+
+
+/// Seconds since UNIX_EPOCH
+fn epoch_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+
+impl ToPoint for SysStat {
+    fn to_point(&self) -> Point {
+        Point::new("sys_stats", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("name", self.name.as_deref())
+            .tag("os_version", self.os_version.as_deref())
+            .tag("kernel_version", self.kernel_version.as_deref())
+            .gauge(
+                "processors",
+                self.processors.map(|value| Value::Integer(value as i64)),
+            )
+            .gauge("total_memory", self.total_memory.map(Value::Integer))
+            .gauge("used_memory", self.used_memory.map(Value::Integer))
+            .gauge("total_swap", self.total_swap.map(Value::Integer))
+            .gauge("used_swap", self.used_swap.map(Value::Integer))
+            .gauge("load_one", self.load_one.map(Value::Float))
+            .gauge("load_five", self.load_five.map(Value::Float))
+            .gauge("load_fifteen", self.load_fifteen.map(Value::Float))
+            .gauge(
+                "cpu_usage",
+                self.cpu_usage.map(|value| Value::Float(value as f64)),
+            )
+    }
+}
+
+
+impl ToPoint for UpsStat {
+    fn to_point(&self) -> Point {
+        Point::new("ups_stats", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("model", self.model.as_deref())
+            .gauge("status", self.status.clone().map(Value::Text))
+            .gauge("load", self.load.map(|value| Value::Integer(value as i64)))
+            .gauge("input_frequency", self.input_frequency.map(Value::Float))
+            .gauge("input_voltage", self.input_voltage.map(Value::Float))
+            .gauge(
+                "battery_charge",
+                self.battery_charge
+                    .map(|value| Value::Integer(value as i64)),
+            )
+            .gauge("battery_voltage", self.battery_voltage.map(Value::Float))
+    }
+}
+
+
+impl ToPoint for DiskStat {
+    fn to_point(&self) -> Point {
+        Point::new("disk_stats", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("device", Some(&self.name))
+            .gauge("temperature", self.temperature.map(Value::Float))
+            .counter("crc_errors", self.crc_errors)
+            .gauge("seek_time", self.seek_time.map(Value::Integer))
+            .gauge("seek_error_rate", self.seek_error_rate.map(Value::Integer))
+            .gauge("throughput", self.throughput.map(Value::Integer))
+            .gauge("read_error_rate", self.read_error_rate.map(Value::Integer))
+    }
+}
+
+
+impl ToPoint for ProcStat {
+    fn to_point(&self) -> Point {
+        Point::new("proc_stats", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("name", self.name.as_deref())
+            .tag("pid", Some(&self.pid.to_string()))
+            .gauge(
+                "cpu_usage",
+                self.cpu_usage.map(|value| Value::Float(value as f64)),
+            )
+            .gauge("rss", self.rss.map(Value::Integer))
+            .gauge("disk_read", self.disk_read.map(Value::Integer))
+            .counter("disk_read_total", self.disk_read_total)
+            .gauge("disk_written", self.disk_written.map(Value::Integer))
+            .counter("disk_written_total", self.disk_written_total)
+            .gauge(
+                "start_time",
+                self.start_time
+                    .map(|start_time| Value::Integer(epoch_seconds(start_time))),
+            )
+    }
+}
+
+
+impl ToPoint for NetStat {
+    fn to_point(&self) -> Point {
+        Point::new("net_stats", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("netdev", Some(&self.netdev))
+            .gauge(
+                "packets_received",
+                Some(Value::Integer(self.packets_received)),
+            )
+            .counter("total_packets_received", Some(self.total_packets_received))
+            .gauge(
+                "packets_transmitted",
+                Some(Value::Integer(self.packets_transmitted)),
+            )
+            .counter(
+                "total_packets_transmitted",
+                Some(self.total_packets_transmitted),
+            )
+            .gauge("received", Some(Value::Integer(self.received)))
+            .counter("total_received", Some(self.total_received))
+            .gauge("transmitted", Some(Value::Integer(self.transmitted)))
+            .counter("total_transmitted", Some(self.total_transmitted))
+            .gauge(
+                "transmitted_errors",
+                Some(Value::Integer(self.transmitted_errors)),
+            )
+            .counter(
+                "transmitted_total_errors",
+                Some(self.transmitted_total_errors),
+            )
+            .gauge(
+                "received_errors",
+                Some(Value::Integer(self.received_errors)),
+            )
+            .counter("received_total_errors", Some(self.received_total_errors))
+    }
+}
+
+
+impl ToPoint for CollectorRun {
+    fn to_point(&self) -> Point {
+        Point::new("collector_runs", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("collector", Some(&self.collector))
+            .gauge("duration_ms", Some(Value::Float(self.duration_ms)))
+            .gauge(
+                "rows_produced",
+                Some(Value::Integer(self.rows_produced as i64)),
+            )
+            .gauge(
+                "rows_written",
+                self.rows_written.map(|value| Value::Integer(value as i64)),
+            )
+            .gauge("failed", Some(Value::Integer(self.error.is_some() as i64)))
+            .gauge("error", self.error.clone().map(Value::Text))
+    }
+}
+
+
+impl ToPoint for Alert {
+    fn to_point(&self) -> Point {
+        Point::new("alerts", self.time)
+            .tag("host", Some(&self.host_name))
+            .tag("rule", Some(&self.rule))
+            .tag("instance", Some(&self.instance))
+            .tag("severity", Some(&self.severity))
+            .gauge("state", Some(Value::Text(self.state.to_owned())))
+            .gauge(
+                "firing",
+                Some(Value::Integer((self.state == "firing") as i64)),
+            )
+            .gauge("value", self.value.map(Value::Float))
+            .gauge("message", Some(Value::Text(self.message.to_owned())))
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    config::{PoliciesConfig, TablePolicy},
+    error::Error,
+    *,
+};
+use diesel::{
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{Bool, Nullable, Text},
+};
+use std::fmt;
+
+
+/// Current policies of the hypertable, as reported by TimescaleDB
+#[derive(Debug, Clone, Default, PartialEq, Eq, QueryableByName)]
+struct TableState {
+    /// None if the table isn't a hypertable
+    #[diesel(sql_type = Nullable<Text>)]
+    chunk_interval: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    retention: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    compress_after: Option<String>,
+    #[diesel(sql_type = Nullable<Bool>)]
+    compression_enabled: Option<bool>,
+    #[diesel(sql_type = Nullable<Text>)]
+    segment_by: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    order_by: Option<String>,
+    /// True if some chunks are compressed, so the compression settings can't change
+    #[diesel(sql_type = Bool)]
+    compressed_chunks: bool,
+}
+
+
+/// Interval, as formatted by PostgreSQL
+#[derive(Debug, Clone, QueryableByName)]
+struct Interval {
+    #[diesel(sql_type = Text)]
+    interval: String,
+}
+
+
+/// Change of the policies of a table, needed to match the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyChange {
+    /// Name of the table
+    pub table: &'static str,
+    /// What changes, like: "retention: none -> 14 days"
+    pub description: String,
+    /// Statements making the change
+    pub statements: Vec<String>,
+}
+
+
+impl Display for PolicyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.table, self.description)
+    }
+}
+
+
+/// Read the current policies of the table
+fn table_state(pg_connection: &mut PgConnection, table: &str) -> Result<TableState, Error> {
+    Ok(diesel::sql_query(
+        "SELECT
+            (SELECT time_interval::text FROM timescaledb_information.dimensions
+                WHERE hypertable_name = $1 AND dimension_number = 1) AS chunk_interval,
+            (SELECT (config->>'drop_after')::interval::text FROM timescaledb_information.jobs
+                WHERE hypertable_name = $1 AND proc_name = 'policy_retention'
+                LIMIT 1) AS retention,
+            (SELECT (config->>'compress_after')::interval::text FROM timescaledb_information.jobs
+                WHERE hypertable_name = $1 AND proc_name = 'policy_compression'
+                LIMIT 1) AS compress_after,
+            (SELECT compression_enabled FROM timescaledb_information.hypertables
+                WHERE hypertable_name = $1) AS compression_enabled,
+            (SELECT string_agg(attname, ', ' ORDER BY segmentby_column_index)
+                FROM timescaledb_information.compression_settings
+                WHERE hypertable_name = $1 AND segmentby_column_index IS NOT NULL) AS segment_by,
+            (SELECT string_agg(attname || CASE WHEN orderby_asc THEN ' ASC' ELSE ' DESC' END,
+                    ', ' ORDER BY orderby_column_index)
+                FROM timescaledb_information.compression_settings
+                WHERE hypertable_name = $1 AND orderby_column_index IS NOT NULL) AS order_by,
+            EXISTS (SELECT FROM timescaledb_information.chunks
+                WHERE hypertable_name = $1 AND is_compressed) AS compressed_chunks",
+    )
+    .bind::<Text, _>(table)
+    .get_result(pg_connection)?)
+}
+
+
+/// Interval formatted by PostgreSQL, so it compares with the current one
+fn interval(pg_connection: &mut PgConnection, interval: &str) -> Result<String, Error> {
+    let interval: Interval =
+        diesel::sql_query("SELECT CAST($1 AS interval)::text AS interval")
+            .bind::<Text, _>(interval)
+            .get_result(pg_connection)?;
+    Ok(interval.interval)
+}
+
+
+/// Changes of the policies of a single table
+fn table_changes(
+    pg_connection: &mut PgConnection,
+    table: &'static str,
+    policy: &TablePolicy,
+) -> Result<Vec<PolicyChange>, Error> {
+    let state = table_state(pg_connection, table)?;
+    let mut formatted = |wanted: &Option<String>| {
+        wanted
+            .as_deref()
+            .map(|wanted| interval(pg_connection, wanted))
+            .transpose()
+    };
+    let policy = TablePolicy {
+        chunk_interval: formatted(&policy.chunk_interval)?,
+        retention: formatted(&policy.retention)?,
+        compress_after: formatted(&policy.compress_after)?,
+        ..policy.clone()
+    };
+    state_changes(table, &state, &policy)
+}
+
+
+/// Changes of the current policies of the table, needed to match the policy.
+/// Intervals of the policy are formatted by PostgreSQL already
+fn state_changes(
+    table: &'static str,
+    state: &TableState,
+    policy: &TablePolicy,
+) -> Result<Vec<PolicyChange>, Error> {
+    let Some(chunk_interval) = &state.chunk_interval else {
+        return Err(Error::Policy {
+            table,
+            reason: String::from("not a TimescaleDB hypertable"),
+        });
+    };
+    let change = |description: String, statements: Vec<String>| {
+        PolicyChange {
+            table,
+            description,
+            statements,
+        }
+    };
+    let shown = |interval: &Option<String>| interval.as_deref().unwrap_or("none").to_string();
+    let mut changes = vec![];
+
+    if let Some(wanted) = &policy.chunk_interval {
+        if chunk_interval != wanted {
+            changes.push(change(
+                format!("chunk_interval: {chunk_interval} -> {wanted}"),
+                vec![format!(
+                    "SELECT set_chunk_time_interval('{table}', INTERVAL '{wanted}')"
+                )],
+            ));
+        }
+    }
+
+    // compression settings go first, the policy requires them:
+    let compress_after = &policy.compress_after;
+    if compress_after.is_some() {
+        let segment_by = policy.segment_by.join(", ");
+        let order_by = policy
+            .order_by
+            .iter()
+            .map(|order| {
+                let mut words = order.split_whitespace();
+                let column = words.next().unwrap_or_default();
+                let direction = words.next().unwrap_or("ASC").to_uppercase();
+                format!("{column} {direction}")
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let settings_differ = state.segment_by.as_deref().unwrap_or_default() != segment_by
+            || (!order_by.is_empty() && state.order_by.as_deref() != Some(order_by.as_str()));
+        // compressed chunks would have to be decompressed first, that's left to the user:
+        if state.compression_enabled == Some(true)
+            && settings_differ
+            && state.compressed_chunks
+        {
+            return Err(Error::Policy {
+                table,
+                reason: format!(
+                    "compression settings can't change while chunks are compressed, \
+                     decompress them first. Segment by: [{}] -> [{segment_by}], order by: [{}] -> [{order_by}]",
+                    state.segment_by.as_deref().unwrap_or_default(),
+                    state.order_by.as_deref().unwrap_or_default(),
+                ),
+            });
+        }
+        if state.compression_enabled != Some(true) || settings_differ {
+            let mut options = format!(
+                "timescaledb.compress, timescaledb.compress_segmentby = '{segment_by}'"
+            );
+            if !order_by.is_empty() {
+                options.push_str(&format!(", timescaledb.compress_orderby = '{order_by}'"));
+            }
+            changes.push(change(
+                format!("compression: segment by: [{segment_by}], order by: [{order_by}]"),
+                vec![format!("ALTER TABLE {table} SET ({options})")],
+            ));
+        }
+    }
+    // a changed policy is removed, then added again:
+    if state.compress_after != *compress_after {
+        let mut statements = vec![];
+        if state.compress_after.is_some() {
+            statements.push(format!(
+                "SELECT remove_compression_policy('{table}', if_exists => true)"
+            ));
+        }
+        if let Some(compress_after) = compress_after {
+            statements.push(format!(
+                "SELECT add_compression_policy('{table}', INTERVAL '{compress_after}')"
+            ));
+        }
+        changes.push(change(
+            format!(
+                "compress_after: {} -> {}",
+                shown(&state.compress_after),
+                shown(compress_after)
+            ),
+            statements,
+        ));
+    }
+
+    let retention = &policy.retention;
+    if state.retention != *retention {
+        let mut statements = vec![];
+        if state.retention.is_some() {
+            statements.push(format!(
+                "SELECT remove_retention_policy('{table}', if_exists => true)"
+            ));
+        }
+        if let Some(retention) = retention {
+            statements.push(format!(
+                "SELECT add_retention_policy('{table}', INTERVAL '{retention}')"
+            ));
+        }
+        changes.push(change(
+            format!(
+                "retention: {} -> {}",
+                shown(&state.retention),
+                shown(retention)
+            ),
+            statements,
+        ));
+    }
+    Ok(changes)
+}
+
+
+/// Compare the policies of the managed tables with the configuration.
+/// Returns the changes needed to match it
+#[instrument(skip(pg_connection, config))]
+pub fn plan_policies(
+    pg_connection: &mut PgConnection,
+    config: &PoliciesConfig,
+) -> Result<Vec<PolicyChange>, Error> {
+    let mut changes = vec![];
+    for (table, policy) in config.tables() {
+        changes.extend(table_changes(pg_connection, table, policy)?);
+    }
+    Ok(changes)
+}
+
+
+/// Make the policies of the managed tables match the configuration,
+/// in a single transaction. Returns the changes made
+#[instrument(skip(pg_connection, config))]
+pub fn apply_policies(
+    pg_connection: &mut PgConnection,
+    config: &PoliciesConfig,
+) -> Result<Vec<PolicyChange>, Error> {
+    pg_connection.transaction(|pg_connection| {
+        let changes = plan_policies(pg_connection, config)?;
+        for statement in changes.iter().flat_map(|change| &change.statements) {
+            debug!("Applying: {statement}");
+            diesel::sql_query(statement).execute(pg_connection)?;
+        }
+        Ok(changes)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    /// Hypertable compressed by the host, with the retention
+    fn compressed() -> TableState {
+        TableState {
+            chunk_interval: Some(String::from("1 day")),
+            retention: Some(String::from("14 days")),
+            compress_after: Some(String::from("2 days")),
+            compression_enabled: Some(true),
+            segment_by: Some(String::from("host_name")),
+            order_by: Some(String::from("time DESC")),
+            compressed_chunks: true,
+        }
+    }
+
+
+    /// Policy matching the compressed hypertable
+    fn policy() -> TablePolicy {
+        TablePolicy {
+            chunk_interval: Some(String::from("1 day")),
+            retention: Some(String::from("14 days")),
+            compress_after: Some(String::from("2 days")),
+            segment_by: vec![String::from("host_name")],
+            order_by: vec![String::from("time desc")],
+        }
+    }
+
+
+    /// Statements of the changes, in their order
+    fn statements(changes: &[PolicyChange]) -> Vec<&str> {
+        changes
+            .iter()
+            .flat_map(|change| &change.statements)
+            .map(String::as_str)
+            .collect()
+    }
+
+
+    #[test]
+    fn keeps_the_matching_policies() {
+        let changes = state_changes("sys_stats", &compressed(), &policy()).unwrap();
+        assert_eq!(changes, []);
+        // the chunk interval is kept, unless configured:
+        let policy = TablePolicy {
+            chunk_interval: None,
+            ..policy()
+        };
+        assert_eq!(
+            state_changes("sys_stats", &compressed(), &policy).unwrap(),
+            []
+        );
+    }
+
+
+    #[test]
+    fn replaces_the_changed_policies() {
+        let policy = TablePolicy {
+            chunk_interval: Some(String::from("12:00:00")),
+            retention: Some(String::from("30 days")),
+            compress_after: None,
+            ..policy()
+        };
+        let changes = state_changes("sys_stats", &compressed(), &policy).unwrap();
+        assert_eq!(
+            changes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "sys_stats: chunk_interval: 1 day -> 12:00:00",
+                "sys_stats: compress_after: 2 days -> none",
+                "sys_stats: retention: 14 days -> 30 days",
+            ]
+        );
+        assert_eq!(
+            statements(&changes),
+            [
+                "SELECT set_chunk_time_interval('sys_stats', INTERVAL '12:00:00')",
+                "SELECT remove_compression_policy('sys_stats', if_exists => true)",
+                "SELECT remove_retention_policy('sys_stats', if_exists => true)",
+                "SELECT add_retention_policy('sys_stats', INTERVAL '30 days')",
+            ]
+        );
+    }
+
+
+    #[test]
+    fn sets_the_compression_before_its_policy() {
+        let state = TableState {
+            chunk_interval: Some(String::from("7 days")),
+            ..Default::default()
+        };
+        let policy = TablePolicy {
+            chunk_interval: None,
+            retention: None,
+            order_by: vec![],
+            ..policy()
+        };
+        let changes = state_changes("proc_stats", &state, &policy).unwrap();
+        assert_eq!(
+            statements(&changes),
+            [
+                "ALTER TABLE proc_stats SET (timescaledb.compress, \
+                 timescaledb.compress_segmentby = 'host_name')",
+                "SELECT add_compression_policy('proc_stats', INTERVAL '2 days')",
+            ]
+        );
+    }
+
+
+    #[test]
+    fn refuses_to_change_the_settings_of_the_compressed_chunks() {
+        let policy = TablePolicy {
+            segment_by: vec![String::from("host_name"), String::from("name")],
+            ..policy()
+        };
+        let error = state_changes("proc_stats", &compressed(), &policy).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Policy {
+                table: "proc_stats",
+                ..
+            }
+        ));
+        assert!(error
+            .to_string()
+            .contains("Segment by: [host_name] -> [host_name, name]"));
+
+        // without the compressed chunks, the settings change:
+        let state = TableState {
+            compressed_chunks: false,
+            ..compressed()
+        };
+        let changes = state_changes("proc_stats", &state, &policy).unwrap();
+        assert_eq!(
+            statements(&changes),
+            ["ALTER TABLE proc_stats SET (timescaledb.compress, \
+              timescaledb.compress_segmentby = 'host_name, name', \
+              timescaledb.compress_orderby = 'time DESC')"]
+        );
+    }
+
+
+    #[test]
+    fn refuses_the_plain_tables() {
+        let error = state_changes("sys_stats", &TableState::default(), &policy()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Can't manage the policies of: sys_stats: not a TimescaleDB hypertable"
+        );
+    }
+}
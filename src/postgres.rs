@@ -1,124 +1,509 @@
 use crate::{
-    // disk_stats::host_name,
-    models::DefaultWithTime,
+    collector::Entries,
     schema::{
-        // disk_stats::dsl::disk_stats,
-        // disk_stats::{dsl::disk_stats, time as disk_stats_time},
-        disk_stats::dsl::disk_stats,
-        // ups_stats::{dsl::ups_stats, time as ups_stats_time},
-        net_stats::dsl::net_stats,
-        // proc_stats::{dsl::proc_stats, time as proc_stats_time},
-        proc_stats::dsl::proc_stats,
-        // sys_stats::{dsl::sys_stats, time as sys_stats_time},
-        sys_stats::dsl::sys_stats,
-        // ups_stats::{dsl::ups_stats, time as ups_stats_time},
+        alerts::dsl::alerts, collector_runs::dsl::collector_runs, disk_stats::dsl::disk_stats,
+        net_stats::dsl::net_stats, proc_stats::dsl::proc_stats, sys_stats::dsl::sys_stats,
         ups_stats::dsl::ups_stats,
     },
-    systeminfo::{disk_stats_entry, net_stats_entries, sys_process_entries, sys_stats_entry},
-    ups::ups_stats_entry,
     *,
 };
 use diesel::{
-    pg::PgConnection,
+    migration::MigrationSource,
+    pg::{Pg, PgConnection},
     prelude::*,
     result::{ConnectionError, Error},
 };
-use std::env;
-use sysinfo::System;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
+
+
+/// Schema migrations, embedded from the "migrations" directory
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
 
 /// Establish connection with TimescaleDB
-#[instrument]
-pub fn establish_postgres_connection() -> Result<PgConnection, ConnectionError> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
+#[instrument(skip(database_url))]
+pub fn establish_postgres_connection(
+    database_url: &str,
+) -> Result<PgConnection, ConnectionError> {
+    PgConnection::establish(database_url)
 }
 
 
-/// Store all entries (Systat, UpsStat and ProcStat) in a single RDBMS transaction
-#[instrument(skip(pg_connection))]
-pub fn store_entries(sys: &mut System, pg_connection: &mut PgConnection) -> Result<(), Error> {
-    pg_connection.transaction(|pg_connection| {
-        // prevent from storing default values. Skip write to the DB in that case:
-
-        // System stats (a single entry)
-        let a_sys_stats_entry = sys_stats_entry(sys);
-        if a_sys_stats_entry != SysStat::default_skip_time(&a_sys_stats_entry) {
-            diesel::insert_into(sys_stats)
-                .values(a_sys_stats_entry)
-                .execute(pg_connection)
-                .unwrap_or_default();
-        } else {
-            debug!("Empty SysStat entry. Skipping DB store.");
-        }
-
-        // UPS stats (a single entry)
-        let a_ups_stats_entry = ups_stats_entry();
-        if a_ups_stats_entry != UpsStat::default_skip_time(&a_ups_stats_entry) {
-            diesel::insert_into(ups_stats)
-                .values(a_ups_stats_entry)
-                .get_result::<UpsStat>(pg_connection)?;
-        } else {
-            debug!("Empty UpsStat entry. Skipping DB store.");
-        }
-
-        // Disk stats (multiple entries)
-        let a_disk_stats_entries = disk_stats_entry(sys)
-            .into_iter()
-            .filter_map(|entry| {
-                if entry != DiskStat::default_skip_time(&entry) {
-                    Some(entry)
+/// State of the long-lived database connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionState {
+    /// True if the connection is established
+    pub connected: bool,
+    /// The last connection error
+    pub last_error: Option<String>,
+    /// Amount of connections established after the first one
+    pub reconnects: u64,
+    /// Amount of failed connection attempts since the last success
+    pub failures: u32,
+    /// Time of the next connection attempt
+    pub retry_at: Option<SystemTime>,
+}
+
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connected: {}, Reconnects: {}, Failures: {}, Last error: {}",
+            self.connected,
+            self.reconnects,
+            self.failures,
+            self.last_error.clone().unwrap_or_default(),
+        )
+    }
+}
+
+
+/// Delay before the connection attempt after given amount of failures.
+/// Grows exponentially up to the max delay, with random jitter of up to 50% subtracted
+pub fn backoff_delay(failures: u32, min_delay: Duration, max_delay: Duration) -> Duration {
+    let exponent = failures.saturating_sub(1).min(31);
+    let delay = min_delay
+        .saturating_mul(1u32 << exponent)
+        .min(max_delay)
+        .max(min_delay);
+    let jitter = RandomState::new().build_hasher().finish() % 1_000;
+    delay.saturating_sub(delay.mul_f64(jitter as f64 / 2_000.0))
+}
+
+
+/// Long-lived TimescaleDB connection. Health-checked before use,
+/// and re-established with exponential backoff when lost
+pub struct Database {
+    database_url: String,
+    min_delay: Duration,
+    max_delay: Duration,
+    connection: Option<PgConnection>,
+    state: ConnectionState,
+    ever_connected: bool,
+}
+
+
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Database")
+            .field("min_delay", &self.min_delay)
+            .field("max_delay", &self.max_delay)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+
+impl Database {
+    /// Create the database handle. Connection is established on the first use
+    pub fn new(database_url: &str, min_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            database_url: database_url.to_string(),
+            min_delay,
+            max_delay,
+            connection: None,
+            state: ConnectionState::default(),
+            ever_connected: false,
+        }
+    }
+
+
+    /// State of the connection
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+
+    /// Drop the connection after an error, so it's re-established on the next use
+    pub fn disconnect(&mut self, error: &str) {
+        if self.connection.take().is_some() {
+            warn!("Lost TimescaleDB connection: {error}");
+        }
+        self.state.connected = false;
+        self.state.last_error = Some(error.to_string());
+    }
+
+
+    /// Healthy connection, if available. Dead connection is dropped,
+    /// and a new one is established unless we wait for the backoff delay to pass
+    #[instrument(skip(self))]
+    pub fn connection(&mut self) -> Option<&mut PgConnection> {
+        if let Some(connection) = self.connection.as_mut() {
+            if let Err(error) = diesel::sql_query("SELECT 1").execute(connection) {
+                self.disconnect(&error.to_string());
+            }
+        }
+        if self.connection.is_none() {
+            self.connect();
+        }
+        self.connection.as_mut()
+    }
+
+
+    /// Attempt to establish the connection, unless we wait for the backoff delay to pass
+    fn connect(&mut self) {
+        let now = SystemTime::now();
+        if matches!(self.state.retry_at, Some(retry_at) if retry_at > now) {
+            return;
+        }
+        match establish_postgres_connection(&self.database_url) {
+            Ok(connection) => {
+                if self.ever_connected {
+                    self.state.reconnects += 1;
+                    info!(
+                        "Reconnected to TimescaleDB after {} failed attempt(s). Reconnects: {}",
+                        self.state.failures, self.state.reconnects
+                    );
                 } else {
-                    None
+                    info!("Connected to TimescaleDB.");
                 }
-            })
-            .collect::<Vec<_>>();
-        if !a_disk_stats_entries.is_empty() {
-            diesel::insert_into(disk_stats)
-                .values(a_disk_stats_entries)
-                .execute(pg_connection)?;
-        } else {
-            debug!("Empty DiskStat entry. Skipping DB store.");
-        }
-
-        // Processes stats (multiple entries)
-        let a_proc_stats_entries = sys_process_entries(sys)
-            .into_iter()
-            .filter_map(|entry| {
-                if entry != ProcStat::default_skip_time(&entry) {
-                    Some(entry)
-                } else {
-                    None
+                self.ever_connected = true;
+                self.connection = Some(connection);
+                self.state.connected = true;
+                self.state.failures = 0;
+                self.state.retry_at = None;
+            }
+            Err(error) => {
+                self.state.connected = false;
+                self.state.failures = self.state.failures.saturating_add(1);
+                self.state.last_error = Some(error.to_string());
+                let delay = backoff_delay(self.state.failures, self.min_delay, self.max_delay);
+                self.state.retry_at = Some(now + delay);
+                error!(
+                    "TimescaleDB Connection Failure #{}: {error}. Next attempt in {:.1}s",
+                    self.state.failures,
+                    delay.as_secs_f64()
+                );
+            }
+        }
+    }
+}
+
+
+/// Reasons to refuse using the database schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// Database has migrations applied, that are unknown to this binary
+    Newer(Vec<String>),
+    /// Database has pending migrations, and applying them is disabled
+    Pending(Vec<String>),
+    /// Reading or applying the migrations failed
+    Migration(String),
+}
+
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Newer(versions) => {
+                write!(
+                    f,
+                    "Database schema is newer than this binary. Unknown migrations: {}. Upgrade dcollector!",
+                    versions.join(", ")
+                )
+            }
+            SchemaError::Pending(versions) => {
+                write!(
+                    f,
+                    "Database schema is outdated. Pending migrations: {}. Run: dcollector migrate",
+                    versions.join(", ")
+                )
+            }
+            SchemaError::Migration(error) => write!(f, "Migrations failed: {error}"),
+        }
+    }
+}
+
+
+impl std::error::Error for SchemaError {}
+
+
+/// Migrations state of the database, compared to the embedded ones
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaStatus {
+    /// Versions of the embedded migrations not yet applied to the database
+    pub pending: Vec<String>,
+    /// Versions of the migrations applied to the database, unknown to this binary
+    pub unknown: Vec<String>,
+}
+
+
+/// Compare migrations applied to the database with the embedded ones
+#[instrument(skip(pg_connection))]
+pub fn schema_status(pg_connection: &mut PgConnection) -> Result<SchemaStatus, SchemaError> {
+    let migration_error = |error: Box<dyn std::error::Error + Send + Sync>| {
+        SchemaError::Migration(error.to_string())
+    };
+    let embedded = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .map_err(migration_error)?
+        .iter()
+        .map(|migration| migration.name().version().to_string())
+        .collect::<Vec<_>>();
+    let applied = pg_connection
+        .applied_migrations()
+        .map_err(migration_error)?
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    Ok(SchemaStatus {
+        pending: embedded
+            .iter()
+            .filter(|version| !applied.contains(version))
+            .cloned()
+            .collect(),
+        unknown: applied
+            .iter()
+            .filter(|version| !embedded.contains(version))
+            .cloned()
+            .collect(),
+    })
+}
+
+
+/// Verify the database schema, and apply pending migrations if requested.
+/// Returns the versions of applied migrations
+#[instrument(skip(pg_connection))]
+pub fn prepare_schema(
+    pg_connection: &mut PgConnection,
+    apply: bool,
+) -> Result<Vec<String>, SchemaError> {
+    let status = schema_status(pg_connection)?;
+    if !status.unknown.is_empty() {
+        return Err(SchemaError::Newer(status.unknown));
+    }
+    if status.pending.is_empty() {
+        return Ok(vec![]);
+    }
+    if !apply {
+        return Err(SchemaError::Pending(status.pending));
+    }
+
+    info!("Applying pending migrations: {}", status.pending.join(", "));
+    let versions = pg_connection
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|error| SchemaError::Migration(error.to_string()))?;
+    Ok(versions.iter().map(ToString::to_string).collect())
+}
+
+
+/// Max amount of bind parameters in a single PostgreSQL statement
+const MAX_BIND_PARAMETERS: usize = 65_535;
+
+
+/// Max amount of rows in a single INSERT statement. The widest table has 15 columns
+const INSERT_CHUNK_ROWS: usize = MAX_BIND_PARAMETERS / 16;
+
+
+/// Bulk write settings of the high-cardinality tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkWrite {
+    /// Write proc, net and disk entries using COPY instead of INSERTs
+    pub copy: bool,
+    /// Max amount of rows written by a single COPY statement
+    pub chunk_rows: usize,
+}
+
+
+impl Default for BulkWrite {
+    fn default() -> Self {
+        Self {
+            copy: true,
+            chunk_rows: 10_000,
+        }
+    }
+}
+
+
+/// Insert entries to the table matching their type, in chunks fitting the bind parameters limit
+macro_rules! insert_entries {
+    ($table:expr, $entries:expr, $pg_connection:expr, $skip_conflicts:expr) => {{
+        let mut inserted = 0;
+        for chunk in $entries.chunks(INSERT_CHUNK_ROWS) {
+            inserted += if $skip_conflicts {
+                diesel::insert_into($table)
+                    .values(chunk)
+                    .on_conflict_do_nothing()
+                    .execute($pg_connection)?
+            } else {
+                diesel::insert_into($table)
+                    .values(chunk)
+                    .execute($pg_connection)?
+            };
+        }
+        Ok(inserted)
+    }};
+}
+
+
+/// Copy entries to the table matching their type, using binary COPY FROM STDIN in chunks
+macro_rules! copy_entries {
+    ($table:expr, $entries:expr, $pg_connection:expr, $chunk_rows:expr) => {{
+        let mut copied = 0;
+        for chunk in $entries.chunks($chunk_rows.max(1)) {
+            copied += diesel::copy_from($table)
+                .from_insertable(chunk)
+                .execute($pg_connection)?;
+        }
+        Ok(Some(copied))
+    }};
+}
+
+
+/// Insert entries to the table matching their type.
+/// Entries conflicting with the existing rows are skipped if requested
+fn insert_entries(
+    entries: &Entries,
+    pg_connection: &mut PgConnection,
+    skip_conflicts: bool,
+) -> Result<usize, Error> {
+    match entries {
+        Entries::Sys(entries) => {
+            insert_entries!(sys_stats, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Ups(entries) => {
+            insert_entries!(ups_stats, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Disk(entries) => {
+            insert_entries!(disk_stats, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Proc(entries) => {
+            insert_entries!(proc_stats, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Net(entries) => {
+            insert_entries!(net_stats, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Runs(entries) => {
+            insert_entries!(collector_runs, entries, pg_connection, skip_conflicts)
+        }
+        Entries::Alerts(entries) => {
+            insert_entries!(alerts, entries, pg_connection, skip_conflicts)
+        }
+    }
+}
+
+
+/// Copy entries of the high-cardinality tables.
+/// Returns None for entries that are not written using COPY
+fn copy_entries(
+    entries: &Entries,
+    pg_connection: &mut PgConnection,
+    chunk_rows: usize,
+) -> Result<Option<usize>, Error> {
+    match entries {
+        Entries::Disk(entries) => {
+            copy_entries!(disk_stats, entries, pg_connection, chunk_rows)
+        }
+        Entries::Proc(entries) => {
+            copy_entries!(proc_stats, entries, pg_connection, chunk_rows)
+        }
+        Entries::Net(entries) => copy_entries!(net_stats, entries, pg_connection, chunk_rows),
+        Entries::Sys(_) | Entries::Ups(_) | Entries::Runs(_) | Entries::Alerts(_) => Ok(None),
+    }
+}
+
+
+/// Write entries using COPY if enabled and supported by their table,
+/// falling back to batched INSERTs if COPY fails
+fn write_entries(
+    entries: &Entries,
+    pg_connection: &mut PgConnection,
+    bulk: BulkWrite,
+) -> Result<usize, Error> {
+    if bulk.copy {
+        // COPY runs in a savepoint, so its failure doesn't abort the whole transaction:
+        match pg_connection
+            .transaction(|pg_connection| copy_entries(entries, pg_connection, bulk.chunk_rows))
+        {
+            Ok(Some(copied)) => return Ok(copied),
+            Ok(None) => (),
+            Err(error) => {
+                warn!(
+                    "COPY of {} entries to {} failed: {error}. Falling back to INSERTs.",
+                    entries.len(),
+                    entries.table()
+                );
+            }
+        }
+    }
+    insert_entries(entries, pg_connection, false)
+}
+
+
+/// Collector runs with the amounts of rows written of the entries they describe
+fn with_rows_written(runs: &[CollectorRun], written: &[usize]) -> Vec<CollectorRun> {
+    let mut runs = runs.to_vec();
+    // runs of the failed collectors have no entries, and their rows written are known:
+    let mut pending = runs
+        .iter_mut()
+        .filter(|run| run.rows_written.is_none())
+        .collect::<Vec<_>>();
+    if pending.len() == written.len() {
+        for (run, written) in pending.iter_mut().zip(written) {
+            run.rows_written = Some(*written as i32);
+        }
+    }
+    runs
+}
+
+
+/// Store entries of all collectors in a single RDBMS transaction.
+/// Collector runs are stored with the amounts of rows written
+#[instrument(skip(entries, pg_connection))]
+pub fn store_entries(
+    entries: &[Entries],
+    pg_connection: &mut PgConnection,
+    bulk: BulkWrite,
+) -> Result<(), Error> {
+    pg_connection.transaction(|pg_connection| {
+        // rows written of each entries, described by the following collector runs:
+        let mut written = vec![];
+        for an_entries in entries {
+            match an_entries {
+                Entries::Runs(runs) => {
+                    let runs = Entries::Runs(with_rows_written(runs, &written));
+                    written.clear();
+                    if !runs.is_empty() {
+                        insert_entries(&runs, pg_connection, false)?;
+                    }
                 }
-            })
-            .collect::<Vec<_>>();
-        if !a_proc_stats_entries.is_empty() {
-            diesel::insert_into(proc_stats)
-                .values(a_proc_stats_entries)
-                .execute(pg_connection)?;
-        } else {
-            debug!("Empty ProcStat entry. Skipping DB store.");
-        }
-
-        // Networks stats (multiple entries)
-        let a_net_stats_entries = net_stats_entries(sys)
-            .into_iter()
-            .filter_map(|entry| {
-                if entry != NetStat::default_skip_time(&entry) {
-                    Some(entry)
-                } else {
-                    None
+                // alerts aren't described by the collector runs:
+                Entries::Alerts(_) => {
+                    if !an_entries.is_empty() {
+                        insert_entries(an_entries, pg_connection, false)?;
+                    }
                 }
-            })
-            .collect::<Vec<_>>();
-        if !a_net_stats_entries.is_empty() {
-            diesel::insert_into(net_stats)
-                .values(a_net_stats_entries)
-                .execute(pg_connection)?;
-        } else {
-            debug!("Empty NetStat entry. Skipping DB store.");
+                // prevent from storing empty sets. Skip write to the DB in that case:
+                an_entries if an_entries.is_empty() => written.push(0),
+                an_entries => written.push(write_entries(an_entries, pg_connection, bulk)?),
+            }
         }
+        Ok(())
+    })
+}
 
+
+/// Store previously collected entries in a single RDBMS transaction.
+/// Entries already stored are skipped, so storing them again is harmless.
+/// COPY can't skip conflicting rows, so INSERTs are used
+#[instrument(skip(entries, pg_connection))]
+pub fn replay_entries(
+    entries: &[Entries],
+    pg_connection: &mut PgConnection,
+) -> Result<(), Error> {
+    pg_connection.transaction(|pg_connection| {
+        for an_entries in entries {
+            if an_entries.is_empty() {
+                continue;
+            }
+            insert_entries(an_entries, pg_connection, true)?;
+        }
         Ok(())
     })
 }
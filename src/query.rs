@@ -0,0 +1,595 @@
+use crate::{
+    error::Error,
+    models::system_time_to_date_time,
+    point::{Field, Point, ToPoint, Value},
+    *,
+};
+use diesel::{
+    dsl::{avg, count_star, max},
+    pg::PgConnection,
+    prelude::*,
+};
+use serde::Serialize;
+use std::{
+    fmt::{self, Write as _},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+
+/// Tables the entries are read back from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    /// System stats
+    Sys,
+    /// UPS stats
+    Ups,
+    /// Disk stats
+    Disk,
+    /// Processes stats
+    Proc,
+    /// Network stats
+    Net,
+    /// Collector runs
+    Runs,
+    /// Alerts history
+    Alerts,
+}
+
+
+/// Read the latest rows of the host from the table, in chronological order.
+/// Rows of a single snapshot are ordered by the key
+macro_rules! read_rows {
+    (
+        $table:ident,
+        $model:ty,
+        $key:expr,
+        $pg_connection:expr,
+        $host:expr,
+        $since:expr,
+        $limit:expr
+    ) => {{
+        let mut rows = $table::table
+            .filter($table::host_name.eq($host))
+            .filter($table::time.ge($since))
+            .order(($table::time.desc(), $key.desc()))
+            .limit($limit)
+            .select(<$model>::as_select())
+            .load::<$model>($pg_connection)?;
+        rows.reverse();
+        rows
+    }};
+}
+
+
+/// Read the latest entries of the host, stored since the time.
+/// At most the limit of the rows is read
+#[instrument(skip(pg_connection))]
+pub fn read_entries(
+    pg_connection: &mut PgConnection,
+    table: Table,
+    host: &str,
+    since: SystemTime,
+    limit: i64,
+) -> Result<Entries, Error> {
+    Ok(match table {
+        Table::Sys => {
+            Entries::Sys(read_rows!(
+                sys_stats,
+                SysStat,
+                sys_stats::host_name,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Ups => {
+            Entries::Ups(read_rows!(
+                ups_stats,
+                UpsStat,
+                ups_stats::host_name,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Disk => {
+            Entries::Disk(read_rows!(
+                disk_stats,
+                DiskStat,
+                disk_stats::name,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Proc => {
+            Entries::Proc(read_rows!(
+                proc_stats,
+                ProcStat,
+                proc_stats::pid,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Net => {
+            Entries::Net(read_rows!(
+                net_stats,
+                NetStat,
+                net_stats::netdev,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Runs => {
+            Entries::Runs(read_rows!(
+                collector_runs,
+                CollectorRun,
+                collector_runs::collector,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+        Table::Alerts => {
+            Entries::Alerts(read_rows!(
+                alerts,
+                Alert,
+                alerts::rule,
+                pg_connection,
+                host,
+                since,
+                limit
+            ))
+        }
+    })
+}
+
+
+/// Temperatures of a disk over the time window
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable)]
+pub struct DiskSummary {
+    /// Disk device name
+    pub name: String,
+    /// Amount of the entries
+    pub samples: i64,
+    /// Average temperature
+    pub temperature_avg: Option<f64>,
+    /// Max temperature
+    pub temperature_max: Option<f64>,
+}
+
+
+impl Display for DiskSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Name: {}, Max temperature: {:.1}, Average temperature: {:.1}, Samples: {}",
+            self.name,
+            self.temperature_max.unwrap_or_default(),
+            self.temperature_avg.unwrap_or_default(),
+            self.samples
+        )
+    }
+}
+
+
+/// Resources used by a process over the time window
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable)]
+pub struct ProcSummary {
+    /// Process ID
+    pub pid: i32,
+    /// Process name
+    pub name: Option<String>,
+    /// Amount of the entries
+    pub samples: i64,
+    /// Average CPU usage (in percent)
+    pub cpu_usage_avg: Option<f64>,
+    /// Max CPU usage (in percent)
+    pub cpu_usage_max: Option<f32>,
+    /// Max resident set size (in bytes)
+    pub rss_max: Option<i64>,
+}
+
+
+impl Display for ProcSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PID: {}, Name: {}, Average CPU usage: {:.1}, Max CPU usage: {:.1}, Max RSS: {}KiB, Samples: {}",
+            self.pid,
+            self.name.clone().unwrap_or_default(),
+            self.cpu_usage_avg.unwrap_or_default(),
+            self.cpu_usage_max.unwrap_or_default(),
+            self.rss_max.unwrap_or_default() / 1024,
+            self.samples
+        )
+    }
+}
+
+
+/// State of the host over the time window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    /// Host name
+    pub host_name: String,
+    /// Start of the time window
+    pub since: SystemTime,
+    /// The latest system stats
+    pub sys: Option<SysStat>,
+    /// The latest UPS stats
+    pub ups: Option<UpsStat>,
+    /// Disks with the highest temperatures
+    pub hottest_disks: Vec<DiskSummary>,
+    /// Processes with the highest average CPU usage
+    pub top_cpu: Vec<ProcSummary>,
+    /// Processes with the highest resident set size
+    pub top_rss: Vec<ProcSummary>,
+}
+
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Host: {}, Since: {}",
+            self.host_name,
+            system_time_to_date_time(self.since).format("%Y-%m-%d %H:%M:%S")
+        )?;
+        match &self.sys {
+            Some(sys) => writeln!(f, "Latest system stats: {sys}")?,
+            None => writeln!(f, "Latest system stats: none")?,
+        }
+        match &self.ups {
+            Some(ups) => writeln!(f, "Latest UPS stats: {ups}")?,
+            None => writeln!(f, "Latest UPS stats: none")?,
+        }
+        let sections = [
+            ("Hottest disks", lines(&self.hottest_disks)),
+            ("Top processes by CPU usage", lines(&self.top_cpu)),
+            ("Top processes by RSS", lines(&self.top_rss)),
+        ];
+        for (title, lines) in sections {
+            writeln!(f, "{title}:")?;
+            for line in lines {
+                writeln!(f, "  {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl Summary {
+    /// Summary rendered as tables
+    pub fn to_tables(&self) -> String {
+        let latest = self
+            .sys
+            .iter()
+            .map(ToPoint::to_point)
+            .chain(self.ups.iter().map(ToPoint::to_point))
+            .collect::<Vec<_>>();
+        let disks = self
+            .hottest_disks
+            .iter()
+            .map(|disk| {
+                vec![
+                    disk.name.to_owned(),
+                    format!("{:.1}", disk.temperature_max.unwrap_or_default()),
+                    format!("{:.1}", disk.temperature_avg.unwrap_or_default()),
+                    disk.samples.to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let processes = |processes: &[ProcSummary]| {
+            processes
+                .iter()
+                .map(|process| {
+                    vec![
+                        process.pid.to_string(),
+                        process.name.clone().unwrap_or_default(),
+                        format!("{:.1}", process.cpu_usage_avg.unwrap_or_default()),
+                        format!("{:.1}", process.cpu_usage_max.unwrap_or_default()),
+                        (process.rss_max.unwrap_or_default() / 1024).to_string(),
+                        process.samples.to_string(),
+                    ]
+                })
+                .collect::<Vec<_>>()
+        };
+        let process_header = [
+            "pid",
+            "name",
+            "cpu_usage_avg",
+            "cpu_usage_max",
+            "rss_max_kib",
+            "samples",
+        ];
+        let mut tables = format!(
+            "Host: {}, Since: {}\n",
+            self.host_name,
+            system_time_to_date_time(self.since).format("%Y-%m-%d %H:%M:%S")
+        );
+        for point in &latest {
+            let _ = write!(
+                tables,
+                "\nLatest {}:\n{}",
+                point.measurement,
+                points_table(std::slice::from_ref(point))
+            );
+        }
+        let sections = [
+            (
+                "Hottest disks",
+                table(
+                    &["name", "temperature_max", "temperature_avg", "samples"],
+                    &disks,
+                ),
+            ),
+            (
+                "Top processes by CPU usage",
+                table(&process_header, &processes(&self.top_cpu)),
+            ),
+            (
+                "Top processes by RSS",
+                table(&process_header, &processes(&self.top_rss)),
+            ),
+        ];
+        for (title, table) in sections {
+            let _ = write!(tables, "\n{title}:\n{table}");
+        }
+        tables
+    }
+}
+
+
+/// Summarize the state of the host since the time.
+/// The top lists hold at most the given amount of disks or processes
+#[instrument(skip(pg_connection))]
+pub fn summarize(
+    pg_connection: &mut PgConnection,
+    host: &str,
+    since: SystemTime,
+    top: i64,
+) -> Result<Summary, Error> {
+    let sys = sys_stats::table
+        .filter(sys_stats::host_name.eq(host))
+        .filter(sys_stats::time.ge(since))
+        .order(sys_stats::time.desc())
+        .select(SysStat::as_select())
+        .first(pg_connection)
+        .optional()?;
+    let ups = ups_stats::table
+        .filter(ups_stats::host_name.eq(host))
+        .filter(ups_stats::time.ge(since))
+        .order(ups_stats::time.desc())
+        .select(UpsStat::as_select())
+        .first(pg_connection)
+        .optional()?;
+    let hottest_disks = disk_stats::table
+        .filter(disk_stats::host_name.eq(host))
+        .filter(disk_stats::time.ge(since))
+        .group_by(disk_stats::name)
+        .select((
+            disk_stats::name,
+            count_star(),
+            avg(disk_stats::temperature),
+            max(disk_stats::temperature),
+        ))
+        .order(max(disk_stats::temperature).desc().nulls_last())
+        .limit(top)
+        .load::<DiskSummary>(pg_connection)?;
+
+    let processes = proc_stats::table
+        .filter(proc_stats::host_name.eq(host))
+        .filter(proc_stats::time.ge(since))
+        .group_by((proc_stats::pid, proc_stats::name))
+        .select((
+            proc_stats::pid,
+            proc_stats::name,
+            count_star(),
+            avg(proc_stats::cpu_usage),
+            max(proc_stats::cpu_usage),
+            max(proc_stats::rss),
+        ))
+        .limit(top);
+    let top_cpu = processes
+        .order(avg(proc_stats::cpu_usage).desc().nulls_last())
+        .load::<ProcSummary>(pg_connection)?;
+    let top_rss = processes
+        .order(max(proc_stats::rss).desc().nulls_last())
+        .load::<ProcSummary>(pg_connection)?;
+
+    Ok(Summary {
+        host_name: host.to_owned(),
+        since,
+        sys,
+        ups,
+        hottest_disks,
+        top_cpu,
+        top_rss,
+    })
+}
+
+
+/// Parse the length of the time window, like: "90s", "30m", "1h", "2d" or "1w"
+pub fn parse_window(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (amount, unit) = text.split_at(split);
+    let amount = amount
+        .parse::<u64>()
+        .map_err(|_| format!("invalid time window: '{text}'. Expected like: 30m, 1h or 2d"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => {
+            return Err(format!(
+                "invalid unit of the time window: '{text}'. Expected one of: s, m, h, d, w"
+            ))
+        }
+    };
+    Ok(Duration::from_secs(amount.saturating_mul(seconds)))
+}
+
+
+/// Start of the time window ending at the time, or the epoch for the windows reaching past it
+pub fn window_start(window: Duration, end: SystemTime) -> SystemTime {
+    end.checked_sub(window).unwrap_or(UNIX_EPOCH).max(UNIX_EPOCH)
+}
+
+
+/// Display of each item, as lines
+fn lines<T: Display>(items: &[T]) -> Vec<String> {
+    items.iter().map(ToString::to_string).collect()
+}
+
+
+/// Render the rows as a table with aligned columns
+pub fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths = header.iter().map(|name| name.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let render = |cells: &[String]| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
+    let mut table = render(&header.iter().map(ToString::to_string).collect::<Vec<_>>());
+    table.push_str(&render(
+        &widths
+            .iter()
+            .map(|width| "-".repeat(*width))
+            .collect::<Vec<_>>(),
+    ));
+    for row in rows {
+        table.push_str(&render(row));
+    }
+    table
+}
+
+
+/// Render the points as a table: the time, the tags and the fields of all points
+pub fn points_table(points: &[Point]) -> String {
+    let mut header = vec!["time"];
+    for point in points {
+        let names = point
+            .tags
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(point.fields.iter().map(|field| field.name));
+        for name in names {
+            if !header.contains(&name) {
+                header.push(name);
+            }
+        }
+    }
+    let rows = points
+        .iter()
+        .map(|point| {
+            header
+                .iter()
+                .map(|name| {
+                    if *name == "time" {
+                        return system_time_to_date_time(point.time)
+                            .format("%Y-%m-%d %H:%M:%S")
+                            .to_string();
+                    }
+                    if let Some((_, value)) = point.tags.iter().find(|(tag, _)| tag == name) {
+                        return value.to_owned();
+                    }
+                    match point.fields.iter().find(|field| field.name == *name) {
+                        Some(Field {
+                            value: Value::Float(value),
+                            ..
+                        }) => format!("{value:.2}"),
+                        Some(Field {
+                            value: Value::Integer(value),
+                            ..
+                        }) => value.to_string(),
+                        Some(Field {
+                            value: Value::Text(value),
+                            ..
+                        }) => value.to_owned(),
+                        None => String::new(),
+                    }
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    table(&header, &rows)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn parses_the_windows() {
+        for (window, seconds) in [
+            ("90s", 90),
+            ("30m", 1_800),
+            ("1h", 3_600),
+            ("2d", 172_800),
+            ("1w", 604_800),
+            (" 5m ", 300),
+            ("0s", 0),
+        ] {
+            assert_eq!(parse_window(window), Ok(Duration::from_secs(seconds)));
+        }
+    }
+
+
+    #[test]
+    fn starts_the_windows_at_the_epoch_at_most() {
+        let now = SystemTime::now();
+        assert_eq!(
+            window_start(Duration::from_secs(3_600), now),
+            now - Duration::from_secs(3_600)
+        );
+        // saturated by the parser, longer than the time since the epoch:
+        let window = parse_window("99999999999w").unwrap();
+        assert_eq!(window_start(window, now), UNIX_EPOCH);
+        assert_eq!(window_start(Duration::MAX, now), UNIX_EPOCH);
+    }
+
+
+    #[test]
+    fn rejects_the_invalid_windows() {
+        for (window, error) in [
+            ("", "invalid time window"),
+            ("m", "invalid time window"),
+            ("-5m", "invalid time window"),
+            ("5", "invalid unit"),
+            ("5min", "invalid unit"),
+            ("5 m", "invalid unit"),
+            ("1.5h", "invalid unit"),
+        ] {
+            let parsed = parse_window(window);
+            assert!(
+                parsed.as_ref().is_err_and(|parsed| parsed.contains(error)),
+                "{window}: {parsed:?}"
+            );
+        }
+    }
+}
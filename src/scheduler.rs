@@ -0,0 +1,329 @@
+use crate::*;
+use std::{
+    collections::BTreeMap,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use sysinfo::System;
+
+
+/// How often an interruptible wait checks if it was interrupted
+const INTERRUPT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// Collector scheduled with its own interval
+#[derive(Debug)]
+struct Job {
+    collector: Box<dyn Collector>,
+    interval: Duration,
+    next_tick: SystemTime,
+    missed_ticks: u64,
+}
+
+
+/// Runs collectors on their own intervals, with ticks aligned to the wall-clock boundaries
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    host_name: String,
+}
+
+
+/// First tick after given time, aligned to the multiple of the interval since UNIX_EPOCH
+pub fn aligned_tick_after(time: SystemTime, interval: Duration) -> SystemTime {
+    let interval_nanos = interval.as_nanos().max(1);
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let next_tick = (since_epoch / interval_nanos + 1) * interval_nanos;
+    UNIX_EPOCH
+        + Duration::new(
+            (next_tick / 1_000_000_000) as u64,
+            (next_tick % 1_000_000_000) as u32,
+        )
+}
+
+
+/// Amount of the aligned ticks between the due tick and the next one
+fn ticks_missed(due_tick: SystemTime, next_tick: SystemTime, interval: Duration) -> u64 {
+    (next_tick
+        .duration_since(due_tick)
+        .unwrap_or_default()
+        .as_nanos()
+        / interval.as_nanos().max(1))
+    .saturating_sub(1) as u64
+}
+
+
+impl Scheduler {
+    /// Schedule registered collectors with intervals given by the function.
+    /// Runs of the collectors are recorded with given host identity
+    pub fn new<F>(registry: Registry, host_name: &str, interval_of: F) -> Self
+    where
+        F: Fn(&dyn Collector) -> Duration,
+    {
+        let now = SystemTime::now();
+        let jobs = registry
+            .into_collectors()
+            .into_iter()
+            .map(|collector| {
+                let interval = interval_of(collector.as_ref());
+                info!(
+                    "Collector: {} scheduled every {}s",
+                    collector.name(),
+                    interval.as_secs_f64()
+                );
+                Job {
+                    next_tick: aligned_tick_after(now, interval),
+                    collector,
+                    interval,
+                    missed_ticks: 0,
+                }
+            })
+            .collect();
+        Self {
+            jobs,
+            host_name: host_name.to_string(),
+        }
+    }
+
+
+    /// True if there are no scheduled collectors
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+
+    /// The earliest tick of all scheduled collectors
+    pub fn next_tick(&self) -> Option<SystemTime> {
+        self.jobs.iter().map(|job| job.next_tick).min()
+    }
+
+
+    /// Sleep until the earliest tick of all scheduled collectors, or until interrupted.
+    /// Returns false if the wait was interrupted
+    pub fn wait_for_next_tick_or<F>(&self, interrupted: F) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        let Some(next_tick) = self.next_tick() else {
+            return !interrupted();
+        };
+        while let Ok(remaining) = next_tick.duration_since(SystemTime::now()) {
+            if interrupted() {
+                return false;
+            }
+            thread::sleep(remaining.min(INTERRUPT_CHECK_INTERVAL));
+        }
+        !interrupted()
+    }
+
+
+    /// Amounts of ticks missed by each collector
+    pub fn missed_ticks(&self) -> BTreeMap<&'static str, u64> {
+        self.jobs
+            .iter()
+            .map(|job| (job.collector.name(), job.missed_ticks))
+            .collect()
+    }
+
+
+    /// Run the collector of the job, recording the run.
+    /// A failing collector produces no entries, and its run holds the error
+    fn run_job(
+        job: &mut Job,
+        sys: &mut System,
+        host_name: &str,
+    ) -> (Option<Entries>, CollectorRun) {
+        let time = SystemTime::now();
+        let started = Instant::now();
+        let (entries, error) = match job.collector.collect(sys) {
+            Ok(entries) => (Some(entries), None),
+            Err(Error::Partial {
+                entries,
+                errors,
+            }) => {
+                let error = errors
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                warn!(
+                    "Collector: {} partially failed: {error}",
+                    job.collector.name()
+                );
+                (Some(entries), Some(error))
+            }
+            Err(error) => {
+                warn!("Collector: {} failed: {error}", job.collector.name());
+                (None, Some(error.to_string()))
+            }
+        };
+        let run = CollectorRun {
+            time,
+            host_name: host_name.to_string(),
+            collector: job.collector.name().to_string(),
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+            rows_produced: entries.as_ref().map_or(0, Entries::len) as i32,
+            // nothing to write for a failed collector:
+            rows_written: entries.is_none().then_some(0),
+            error,
+        };
+        (entries, run)
+    }
+
+
+    /// Run all scheduled collectors once, regardless of their ticks.
+    /// The runs are recorded as the last entries
+    #[instrument(skip(self, sys))]
+    pub fn run_all(&mut self, sys: &mut System) -> Vec<Entries> {
+        let (all_entries, runs): (Vec<_>, Vec<_>) = self
+            .jobs
+            .iter_mut()
+            .map(|job| Self::run_job(job, sys, &self.host_name))
+            .unzip();
+        let mut all_entries = all_entries.into_iter().flatten().collect::<Vec<_>>();
+        all_entries.push(Entries::Runs(runs));
+        all_entries
+    }
+
+
+    /// Run collectors whose tick is due, then schedule their next ticks.
+    /// The runs are recorded as the last entries
+    #[instrument(skip(self, sys))]
+    pub fn run_due(&mut self, sys: &mut System) -> Vec<Entries> {
+        let mut all_entries = vec![];
+        let mut runs = vec![];
+        for job in self.jobs.iter_mut() {
+            if job.next_tick > SystemTime::now() {
+                continue;
+            }
+
+            let (entries, run) = Self::run_job(job, sys, &self.host_name);
+            runs.push(run);
+            match entries {
+                Some(entries) if entries.is_empty() => {
+                    debug!(
+                        "Empty {} entries from collector: {}.",
+                        job.collector.table(),
+                        job.collector.name()
+                    );
+                    all_entries.push(entries);
+                }
+                Some(entries) => all_entries.push(entries),
+                None => (),
+            }
+
+            // the next tick is the first aligned one after the collection finished,
+            // ticks passed in between are reported as missed instead of piling up delay:
+            let due_tick = job.next_tick;
+            job.next_tick = aligned_tick_after(SystemTime::now(), job.interval);
+            let missed = ticks_missed(due_tick, job.next_tick, job.interval);
+            if missed > 0 {
+                job.missed_ticks += missed;
+                warn!(
+                    "Collector: {} missed {missed} tick(s) ({} in total). Interval of {}s is too short?",
+                    job.collector.name(),
+                    job.missed_ticks,
+                    job.interval.as_secs_f64()
+                );
+            }
+        }
+        if !runs.is_empty() {
+            all_entries.push(Entries::Runs(runs));
+        }
+        all_entries
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sysinfo::SystemExt;
+
+
+    /// Collector taking the time to collect
+    #[derive(Debug)]
+    struct SlowCollector(Duration);
+
+
+    impl Collector for SlowCollector {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+
+        fn table(&self) -> &'static str {
+            "sys_stats"
+        }
+
+
+        fn collect(&mut self, _sys: &mut System) -> Result<Entries, Error> {
+            thread::sleep(self.0);
+            Ok(Entries::Sys(vec![]))
+        }
+    }
+
+
+    /// Time since UNIX_EPOCH
+    fn at(seconds: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis)
+    }
+
+
+    #[test]
+    fn aligns_the_ticks_to_the_boundaries() {
+        let minute = Duration::from_secs(60);
+        // a tick exactly on the boundary is past, the next boundary follows:
+        assert_eq!(aligned_tick_after(at(120, 0), minute), at(180, 0));
+        assert_eq!(aligned_tick_after(at(120, 1), minute), at(180, 0));
+        assert_eq!(aligned_tick_after(at(179, 999), minute), at(180, 0));
+
+        let interval = Duration::from_millis(250);
+        assert_eq!(aligned_tick_after(at(10, 0), interval), at(10, 250));
+        assert_eq!(aligned_tick_after(at(10, 260), interval), at(10, 500));
+        assert_eq!(
+            aligned_tick_after(at(10, 0) + Duration::from_nanos(1), interval),
+            at(10, 250)
+        );
+        // a zero interval doesn't divide by zero:
+        assert!(aligned_tick_after(at(10, 0), Duration::ZERO) > at(10, 0));
+    }
+
+
+    #[test]
+    fn counts_the_missed_ticks() {
+        let interval = Duration::from_secs(5);
+        assert_eq!(ticks_missed(at(10, 0), at(15, 0), interval), 0);
+        assert_eq!(ticks_missed(at(10, 0), at(20, 0), interval), 1);
+        assert_eq!(ticks_missed(at(10, 0), at(35, 0), interval), 4);
+        assert_eq!(ticks_missed(at(10, 0), at(10, 0), interval), 0);
+        assert_eq!(ticks_missed(at(15, 0), at(10, 0), interval), 0);
+    }
+
+
+    #[test]
+    fn reports_the_ticks_missed_by_the_overrunning_collection() {
+        let interval = Duration::from_millis(100);
+        let mut registry = Registry::new();
+        registry.register(Box::new(SlowCollector(Duration::from_millis(350))));
+        let mut scheduler = Scheduler::new(registry, "nas", |_| interval);
+        let mut sys = System::new();
+        assert!(scheduler.run_due(&mut sys).is_empty());
+
+        scheduler.wait_for_next_tick_or(|| false);
+        let entries = scheduler.run_due(&mut sys);
+        assert_eq!(entries.len(), 2);
+        // 3 boundaries passed while collecting, or 4 if the tick was run late:
+        let missed = scheduler.missed_ticks()["slow"];
+        assert!((3..=4).contains(&missed), "missed: {missed}");
+        let next_tick = scheduler.next_tick().unwrap();
+        assert!(next_tick > SystemTime::now());
+        assert_eq!(
+            aligned_tick_after(next_tick - interval, interval),
+            next_tick
+        );
+    }
+}
@@ -1,21 +1,46 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    disk_stats (time) {
+    alerts (time, host_name, rule, instance) {
         time -> Timestamp,
-        name -> Nullable<Text>,
+        host_name -> Text,
+        rule -> Text,
+        instance -> Text,
+        state -> Text,
+        severity -> Text,
+        value -> Nullable<Float8>,
+        message -> Text,
+    }
+}
+
+diesel::table! {
+    collector_runs (time, host_name, collector) {
+        time -> Timestamp,
+        host_name -> Text,
+        collector -> Text,
+        duration_ms -> Float8,
+        rows_produced -> Int4,
+        rows_written -> Nullable<Int4>,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    disk_stats (time, host_name, name) {
+        time -> Timestamp,
+        name -> Text,
         temperature -> Nullable<Float8>,
         crc_errors -> Nullable<Int8>,
         seek_time -> Nullable<Int8>,
         seek_error_rate -> Nullable<Int8>,
         throughput -> Nullable<Int8>,
         read_error_rate -> Nullable<Int8>,
-        host_name -> Nullable<Text>,
+        host_name -> Text,
     }
 }
 
 diesel::table! {
-    net_stats (time) {
+    net_stats (time, host_name, netdev) {
         time -> Timestamp,
         netdev -> Text,
         packets_received -> Int8,
@@ -30,11 +55,12 @@ diesel::table! {
         transmitted_total_errors -> Int8,
         received_errors -> Int8,
         received_total_errors -> Int8,
+        host_name -> Text,
     }
 }
 
 diesel::table! {
-    proc_stats (time) {
+    proc_stats (time, host_name, pid) {
         time -> Timestamp,
         start_time -> Nullable<Timestamp>,
         exe -> Nullable<Text>,
@@ -47,17 +73,18 @@ diesel::table! {
         cpu_usage -> Nullable<Float4>,
         rss -> Nullable<Int8>,
         status -> Nullable<Text>,
-        host_name -> Nullable<Text>,
+        host_name -> Text,
+        pid -> Int4,
     }
 }
 
 diesel::table! {
-    sys_stats (time) {
+    sys_stats (time, host_name) {
         time -> Timestamp,
         name -> Nullable<Text>,
         kernel_version -> Nullable<Text>,
         os_version -> Nullable<Text>,
-        host_name -> Nullable<Text>,
+        host_name -> Text,
         processors -> Nullable<Int4>,
         total_memory -> Nullable<Int8>,
         used_memory -> Nullable<Int8>,
@@ -71,7 +98,7 @@ diesel::table! {
 }
 
 diesel::table! {
-    ups_stats (time) {
+    ups_stats (time, host_name) {
         time -> Timestamp,
         model -> Nullable<Text>,
         status -> Nullable<Text>,
@@ -80,10 +107,13 @@ diesel::table! {
         input_voltage -> Nullable<Float8>,
         battery_charge -> Nullable<Int4>,
         battery_voltage -> Nullable<Float8>,
+        host_name -> Text,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    alerts,
+    collector_runs,
     disk_stats,
     net_stats,
     proc_stats,
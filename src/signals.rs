@@ -0,0 +1,56 @@
+use crate::*;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    flag,
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+
+/// Flags raised by the process signals, checked by the main loop.
+/// SIGTERM and SIGINT request a graceful shutdown; the second one terminates immediately.
+/// SIGHUP requests the configuration reload
+#[derive(Debug, Clone, Default)]
+pub struct Signals {
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+
+impl Signals {
+    /// Register the signal handlers
+    #[instrument]
+    pub fn register() -> io::Result<Self> {
+        let signals = Self::default();
+        for signal in [SIGTERM, SIGINT] {
+            // terminate if the shutdown was already requested, then raise the flag:
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&signals.shutdown))?;
+            flag::register(signal, Arc::clone(&signals.shutdown))?;
+        }
+        flag::register(SIGHUP, Arc::clone(&signals.reload))?;
+        Ok(signals)
+    }
+
+
+    /// True if the graceful shutdown was requested
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+
+    /// True if the configuration reload was requested since the last call
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+
+
+    /// True if any of the signals is pending
+    pub fn pending(&self) -> bool {
+        self.shutdown_requested() || self.reload.load(Ordering::SeqCst)
+    }
+}
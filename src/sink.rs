@@ -0,0 +1,253 @@
+use crate::{
+    config::{ConfigError, SinksConfig},
+    error::Error,
+    postgres::ConnectionState,
+    *,
+};
+use std::{collections::BTreeMap, fmt::Debug, thread, time::Duration};
+
+
+/// Graphite plaintext sink
+pub mod graphite;
+/// InfluxDB line protocol sink
+pub mod influxdb;
+/// JSON Lines file sink
+pub mod jsonl;
+/// MQTT sink
+pub mod mqtt;
+/// OpenTelemetry (OTLP/HTTP) sink
+pub mod otlp;
+/// PostgreSQL (TimescaleDB) sink
+pub mod postgres;
+/// Prometheus (OpenMetrics) scrape endpoint
+pub mod prometheus;
+/// Prometheus remote_write sink
+pub mod remote_write;
+/// StatsD sink
+pub mod statsd;
+
+
+pub use graphite::GraphiteSink;
+pub use influxdb::InfluxDbSink;
+pub use jsonl::JsonlSink;
+pub use mqtt::MqttSink;
+pub use otlp::OtlpSink;
+pub use postgres::PostgresSink;
+pub use prometheus::PrometheusSink;
+pub use remote_write::RemoteWriteSink;
+pub use statsd::StatsdSink;
+
+
+/// Common trait of all outputs of the agent
+pub trait Sink: Debug {
+    /// Unique name of the sink, used in configuration and logs
+    fn name(&self) -> &'static str;
+
+    /// Write the entries of a single snapshot. Failures the sink recovers from on its own
+    /// (like spooling the entries for later) are still reported
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error>;
+
+    /// Prepare the output before the first collection, like verifying the database schema.
+    /// The sinks not prepared at startup prepare on their writes
+    fn prepare(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Write out anything buffered by the sink. Called before the agent stops
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Observe the health of the agent before each write.
+    /// Used by the sinks exposing the metrics of the agent itself
+    fn observe(&mut self, _health: &AgentHealth) {}
+
+    /// State of the database connection, for the sinks keeping one
+    fn connection_state(&self) -> Option<ConnectionState> {
+        None
+    }
+}
+
+
+/// Health of the agent, as of the previous write
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgentHealth {
+    /// Amounts of ticks missed by each collector
+    pub missed_ticks: BTreeMap<&'static str, u64>,
+    /// Amounts of failed writes of each sink
+    pub sink_failures: BTreeMap<&'static str, u64>,
+    /// States of the database connections of the sinks keeping one
+    pub connections: BTreeMap<&'static str, ConnectionState>,
+}
+
+
+/// All active sinks. Each snapshot is written to every one of them,
+/// and a failing sink doesn't affect the others
+#[derive(Debug, Default)]
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+    health: AgentHealth,
+}
+
+
+impl Sinks {
+    /// Sinks enabled in the configuration, writing the entries of the host
+    pub fn from_config(config: &SinksConfig, host_name: &str) -> Result<Self, ConfigError> {
+        let mut sinks = Self::default();
+        if config.postgres.enabled {
+            sinks.add(Box::new(PostgresSink::new(&config.postgres)?));
+        }
+        if config.jsonl.enabled {
+            sinks.add(Box::new(JsonlSink::new(&config.jsonl)));
+        }
+        if config.influxdb.enabled {
+            sinks.add(Box::new(InfluxDbSink::new(&config.influxdb)));
+        }
+        if config.prometheus.enabled {
+            sinks.add(Box::new(PrometheusSink::new(&config.prometheus)));
+        }
+        if config.remote_write.enabled {
+            sinks.add(Box::new(RemoteWriteSink::new(&config.remote_write)));
+        }
+        if config.otlp.enabled {
+            sinks.add(Box::new(OtlpSink::new(&config.otlp)));
+        }
+        if config.mqtt.enabled {
+            sinks.add(Box::new(MqttSink::new(&config.mqtt, host_name)));
+        }
+        if config.graphite.enabled {
+            sinks.add(Box::new(GraphiteSink::new(&config.graphite)));
+        }
+        if config.statsd.enabled {
+            sinks.add(Box::new(StatsdSink::new(&config.statsd)));
+        }
+        otlp::export_spans(config.otlp.enabled && config.otlp.traces);
+        Ok(sinks)
+    }
+
+
+    /// Add a sink. Sink with the same name gets replaced
+    pub fn add(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.retain(|added| added.name() != sink.name());
+        self.health.sink_failures.insert(sink.name(), 0);
+        self.sinks.push(sink);
+    }
+
+
+    /// Names of the active sinks
+    pub fn names(&self) -> Vec<&'static str> {
+        self.sinks.iter().map(|sink| sink.name()).collect()
+    }
+
+
+    /// True if there are no active sinks
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+
+    /// Set the amounts of ticks missed by each collector, observed on the next write
+    pub fn set_missed_ticks(&mut self, missed_ticks: BTreeMap<&'static str, u64>) {
+        self.health.missed_ticks = missed_ticks;
+    }
+
+
+    /// Prepare all sinks at startup. Returns the first fatal error. Sinks failing otherwise,
+    /// like the ones with an unreachable output, are left to prepare on their writes
+    #[instrument(skip(self))]
+    pub fn prepare(&mut self) -> Result<(), Error> {
+        for sink in self.sinks.iter_mut() {
+            match sink.prepare() {
+                Ok(()) => (),
+                Err(error) if error.is_fatal() => return Err(error),
+                Err(error) => warn!("Sink: {} not prepared yet: {error}", sink.name()),
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Write the entries to all sinks. Returns the amount of sinks that failed,
+    /// or the first fatal error. The entries are written to all sinks even then.
+    /// The failures are counted in the health of the agent
+    #[instrument(skip(self, entries))]
+    pub fn write(&mut self, entries: &[Entries]) -> Result<usize, Error> {
+        let mut failed = 0;
+        let mut fatal = None;
+        for sink in self.sinks.iter() {
+            if let Some(state) = sink.connection_state() {
+                self.health.connections.insert(sink.name(), state);
+            }
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.observe(&self.health);
+        }
+        for sink in self.sinks.iter_mut() {
+            let result = sink.write(entries);
+            if result.is_err() {
+                *self.health.sink_failures.entry(sink.name()).or_default() += 1;
+            }
+            match result {
+                Ok(()) => trace!("Sink: {} written.", sink.name()),
+                // the first fatal error is returned, the others are only logged:
+                Err(error) if error.is_fatal() && fatal.is_some() => {
+                    error!("Sink: {} failed: {error}", sink.name());
+                }
+                Err(error) if error.is_fatal() => fatal = Some(error),
+                Err(error) => {
+                    error!("Sink: {} failed: {error}", sink.name());
+                    failed += 1;
+                }
+            }
+        }
+        match fatal {
+            Some(error) => Err(error),
+            None => Ok(failed),
+        }
+    }
+
+
+    /// Flush all sinks. Returns the amount of sinks that failed
+    #[instrument(skip(self))]
+    pub fn flush(&mut self) -> usize {
+        let mut failed = 0;
+        for sink in self.sinks.iter_mut() {
+            if let Err(error) = sink.flush() {
+                warn!("Sink: {} not flushed: {error}", sink.name());
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+
+/// Sleep for the duration, unless the condition becomes true earlier
+pub(crate) fn wait_or(duration: Duration, condition: impl Fn() -> bool) {
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::ZERO;
+    while waited < duration && !condition() {
+        thread::sleep(step);
+        waited += step;
+    }
+}
+
+
+/// Pack the lines into datagrams of max payload size. A longer line is sent alone
+pub(crate) fn datagrams(lines: &[String], payload: usize) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > payload {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
@@ -0,0 +1,335 @@
+use crate::{
+    config::GraphiteConfig,
+    error::Error,
+    point::{Point, Value},
+    sink::Sink,
+    *,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+
+/// Metric path templates of Graphite and StatsD, like: "dcollector.{host}.{entries}.{id}.{field}".
+/// Placeholders are the host name, the short name of the entries ("sys", "disk", ...),
+/// the identifier of the row (disk name, network interface, process name or collector),
+/// the field name and the tags of the entries. Segments left empty are skipped.
+/// Processes of the same name share the path of "{id}", so the last one wins,
+/// unless the template has the "{pid}" too
+#[derive(Debug, Clone)]
+pub(crate) struct PathTemplates {
+    template: String,
+    templates: BTreeMap<String, String>,
+}
+
+
+impl PathTemplates {
+    /// Default template, and the templates of the entries
+    pub(crate) fn new(template: &str, templates: &BTreeMap<String, String>) -> Self {
+        Self {
+            template: template.to_owned(),
+            templates: templates.to_owned(),
+        }
+    }
+
+
+    /// Paths of the numeric fields of the point, with their values.
+    /// Texts (like the UPS status) aren't metrics, and are skipped
+    pub(crate) fn metrics(&self, point: &Point) -> Vec<(String, String)> {
+        let (entries, id_tag) = match point.measurement {
+            "sys_stats" => ("sys", None),
+            "ups_stats" => ("ups", None),
+            "disk_stats" => ("disk", Some("device")),
+            "net_stats" => ("net", Some("netdev")),
+            "proc_stats" => ("proc", Some("name")),
+            "collector_runs" => ("runs", Some("collector")),
+            "alerts" => ("alerts", Some("rule")),
+            _ => return vec![],
+        };
+        let template = self.templates.get(entries).unwrap_or(&self.template);
+        point
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let value = match &field.value {
+                    Value::Float(value) if value.is_finite() => value.to_string(),
+                    Value::Integer(value) => value.to_string(),
+                    Value::Float(_) | Value::Text(_) => return None,
+                };
+                let path = template
+                    .split('.')
+                    .map(|segment| {
+                        render(segment, |placeholder| {
+                            match placeholder {
+                                "entries" => Some(entries),
+                                "id" => id_tag.and_then(|id_tag| tag(point, id_tag)),
+                                "field" => Some(field.name),
+                                _ => tag(point, placeholder),
+                            }
+                        })
+                    })
+                    .filter(|segment| !segment.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(".");
+                Some((path, value))
+            })
+            .collect()
+    }
+}
+
+
+/// Writes the entries to Graphite, as plaintext protocol over TCP.
+/// The connection is kept open, and made again after a failure
+#[derive(Debug)]
+pub struct GraphiteSink {
+    address: String,
+    timeout: Duration,
+    templates: PathTemplates,
+    stream: Option<TcpStream>,
+}
+
+
+impl GraphiteSink {
+    /// Create the sink using the Graphite settings
+    pub fn new(config: &GraphiteConfig) -> Self {
+        Self {
+            address: config.address.to_owned(),
+            timeout: Duration::from_secs(config.timeout),
+            templates: PathTemplates::new(&config.template, &config.templates),
+            stream: None,
+        }
+    }
+
+
+    /// Connect to the first reachable address of the listener
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    debug!("Connected to Graphite: {address}");
+                    return Ok(stream);
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "address not resolved")
+        }))
+    }
+}
+
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let mut lines = String::new();
+        let mut count = 0;
+        for point in entries.iter().flat_map(Entries::to_points) {
+            let seconds = point
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            for (path, value) in self.templates.metrics(&point) {
+                let _ = writeln!(lines, "{path} {value} {seconds}");
+                count += 1;
+            }
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        // on failure the stream is dropped, and connected again on the next write:
+        stream.write_all(lines.as_bytes())?;
+        stream.flush()?;
+        self.stream = Some(stream);
+        trace!("Metrics sent: {count}");
+        Ok(())
+    }
+}
+
+
+/// Render the segment of the template. Values of the placeholders are made
+/// usable as a single path segment, unknown placeholders are left empty
+fn render<'a>(segment: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut rendered = String::new();
+    let mut rest = segment;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        if let Some(value) = value(&rest[start + 1..start + end]) {
+            rendered.push_str(&path_segment(value));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+
+/// Value usable as a single path segment, without separators, spaces or wildcards
+fn path_segment(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|character| {
+            match character {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => character,
+                _ => '_',
+            }
+        })
+        .collect()
+}
+
+
+/// Value of the tag of the point
+fn tag<'a>(point: &'a Point, name: &str) -> Option<&'a str> {
+    point
+        .tags
+        .iter()
+        .find_map(|(tag_name, value)| (*tag_name == name).then_some(value.as_str()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::{Field, ToPoint};
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+
+    /// Templates with the default template of the config
+    fn templates(templates: &[(&str, &str)]) -> PathTemplates {
+        PathTemplates::new(
+            &GraphiteConfig::default().template,
+            &templates
+                .iter()
+                .map(|(entries, template)| (entries.to_string(), template.to_string()))
+                .collect(),
+        )
+    }
+
+
+    /// Entry of the process
+    fn process(pid: i32, name: &str, rss: i64) -> ProcStat {
+        ProcStat {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            host_name: String::from("nas.local"),
+            pid,
+            name: Some(name.to_string()),
+            rss: Some(rss),
+            ..Default::default()
+        }
+    }
+
+
+    #[test]
+    fn expands_the_templates() {
+        let templates = templates(&[("proc", "{host}.proc.{id}.{pid}.{field}")]);
+        let sys = SysStat {
+            host_name: String::from("nas.local"),
+            load_one: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(
+            templates.metrics(&sys.to_point()),
+            [(
+                String::from("dcollector.nas_local.sys.load_one"),
+                String::from("0.5")
+            )]
+        );
+        assert_eq!(
+            templates.metrics(&process(42, "postgres: writer", 1_024).to_point()),
+            [(
+                String::from("nas_local.proc.postgres__writer.42.rss"),
+                String::from("1024")
+            )]
+        );
+    }
+
+
+    #[test]
+    fn skips_the_texts_and_the_unknown_placeholders() {
+        let point = Point {
+            measurement: "ups_stats",
+            tags: vec![("host", String::from("nas"))],
+            fields: vec![
+                Field {
+                    name: "status",
+                    value: Value::Text(String::from("OL")),
+                    monotonic: false,
+                },
+                Field {
+                    name: "load",
+                    value: Value::Float(f64::NAN),
+                    monotonic: false,
+                },
+                Field {
+                    name: "battery_charge",
+                    value: Value::Integer(-5),
+                    monotonic: false,
+                },
+            ],
+            time: UNIX_EPOCH,
+        };
+        assert_eq!(
+            templates(&[("ups", "x{unknown}.{entries}.{model}.{field}")]).metrics(&point),
+            [(String::from("x.ups.battery_charge"), String::from("-5"))]
+        );
+        let unknown = Point {
+            measurement: "unknown_stats",
+            ..point
+        };
+        assert!(templates(&[]).metrics(&unknown).is_empty());
+    }
+
+
+    #[test]
+    fn sends_the_plaintext_lines() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut sink = GraphiteSink::new(&GraphiteConfig {
+            enabled: true,
+            address: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        });
+        // processes of the same name share the default path:
+        sink.write(&[Entries::Proc(vec![
+            process(1, "nginx", 1_024),
+            process(2, "nginx", 2_048),
+        ])])
+        .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines = BufReader::new(stream)
+            .lines()
+            .take(2)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            [
+                "dcollector.nas_local.proc.nginx.rss 1024 1700000000",
+                "dcollector.nas_local.proc.nginx.rss 2048 1700000000",
+            ]
+        );
+    }
+}
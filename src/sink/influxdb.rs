@@ -0,0 +1,377 @@
+use crate::{
+    config::{InfluxDbConfig, InfluxDbTransport},
+    error::Error,
+    point::{Point, Value},
+    sink::{datagrams, Sink},
+    *,
+};
+use std::{fmt::Write as _, net::UdpSocket, time::Duration};
+
+
+/// Where the lines are sent
+#[derive(Debug)]
+enum Output {
+    /// InfluxDB v2 write API
+    Http {
+        agent: ureq::Agent,
+        url: String,
+        org: String,
+        bucket: String,
+        token: Option<String>,
+        batch_lines: usize,
+    },
+    /// UDP listener. Lines are packed into datagrams of max payload size
+    Udp {
+        address: String,
+        payload: usize,
+        socket: Option<UdpSocket>,
+    },
+}
+
+
+/// Writes the entries to InfluxDB, as line protocol. Each table is a measurement,
+/// identifying values (host, device, process, interface) are tags,
+/// the measured values are fields, timestamps are in nanoseconds
+#[derive(Debug)]
+pub struct InfluxDbSink {
+    output: Output,
+}
+
+
+impl InfluxDbSink {
+    /// Create the sink using the InfluxDB settings
+    pub fn new(config: &InfluxDbConfig) -> Self {
+        let output = match config.transport {
+            InfluxDbTransport::Http => {
+                Output::Http {
+                    agent: ureq::AgentBuilder::new()
+                        .timeout(Duration::from_secs(config.timeout))
+                        .build(),
+                    url: format!("{}/api/v2/write", config.url.trim_end_matches('/')),
+                    org: config.org.to_owned(),
+                    bucket: config.bucket.to_owned(),
+                    token: config.token.to_owned(),
+                    batch_lines: config.batch_lines,
+                }
+            }
+            InfluxDbTransport::Udp => {
+                Output::Udp {
+                    address: config.address.to_owned(),
+                    payload: config.udp_payload,
+                    socket: None,
+                }
+            }
+        };
+        Self {
+            output,
+        }
+    }
+}
+
+
+impl Sink for InfluxDbSink {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let lines = entries
+            .iter()
+            .flat_map(Entries::to_points)
+            .filter_map(|point| line(&point))
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        match &mut self.output {
+            Output::Http {
+                agent,
+                url,
+                org,
+                bucket,
+                token,
+                batch_lines,
+            } => {
+                for batch in lines.chunks(*batch_lines) {
+                    let mut request = agent
+                        .post(url)
+                        .query("org", org)
+                        .query("bucket", bucket)
+                        .query("precision", "ns")
+                        .set("Content-Type", "text/plain; charset=utf-8");
+                    if let Some(token) = token {
+                        request = request.set("Authorization", &format!("Token {token}"));
+                    }
+                    request.send_string(&batch.join("\n"))?;
+                    trace!("Lines sent: {}", batch.len());
+                }
+            }
+            Output::Udp {
+                address,
+                payload,
+                socket,
+            } => {
+                let udp_socket = match socket.take() {
+                    Some(udp_socket) => udp_socket,
+                    None => {
+                        let udp_socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                        udp_socket.connect(address.as_str())?;
+                        udp_socket
+                    }
+                };
+                // on failure the socket is dropped, and the address resolved again:
+                for datagram in datagrams(&lines, *payload) {
+                    udp_socket.send(datagram.as_bytes())?;
+                }
+                *socket = Some(udp_socket);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Escape the measurement name, tag key, tag value or field key.
+/// Line protocol doesn't allow new lines in any of them, so they become (escaped) spaces
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        let character = if matches!(character, '\n' | '\r') {
+            ' '
+        } else {
+            character
+        };
+        if special.contains(&character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+
+/// Render the point as a line of the line protocol.
+/// None if the point has no fields to write
+fn line(point: &Point) -> Option<String> {
+    let mut fields = point
+        .fields
+        .iter()
+        .filter_map(|field| {
+            let value = match &field.value {
+                Value::Float(value) if value.is_finite() => value.to_string(),
+                Value::Float(_) => return None,
+                Value::Integer(value) => format!("{value}i"),
+                Value::Text(text) => format!("\"{}\"", escape(text, &['\\', '"'])),
+            };
+            Some(format!("{}={value}", escape(field.name, &[',', '=', ' '])))
+        })
+        .peekable();
+    fields.peek()?;
+
+    let mut line = escape(point.measurement, &[',', ' ']);
+    // sorted tags are faster to index for InfluxDB:
+    let mut tags = point.tags.iter().collect::<Vec<_>>();
+    tags.sort();
+    for (key, value) in tags {
+        let _ = write!(
+            line,
+            ",{}={}",
+            escape(key, &[',', '=', ' ']),
+            escape(value, &[',', '=', ' '])
+        );
+    }
+    line.push(' ');
+    line.push_str(&fields.collect::<Vec<_>>().join(","));
+    let nanos = point
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let _ = write!(line, " {nanos}");
+    Some(line)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::{Field, ToPoint};
+    use std::thread;
+    use tiny_http::{Response, Server};
+
+
+    /// Gauge field of the value
+    fn field(name: &'static str, value: Value) -> Field {
+        Field {
+            name,
+            value,
+            monotonic: false,
+        }
+    }
+
+
+    /// Disk entry of the device, collected a nanosecond past the second
+    fn disk(device: &str, temperature: f64) -> DiskStat {
+        DiskStat {
+            time: UNIX_EPOCH + Duration::new(1_700_000_000, 1),
+            host_name: String::from("nas"),
+            name: device.to_string(),
+            temperature: Some(temperature),
+            crc_errors: Some(3),
+            ..Default::default()
+        }
+    }
+
+
+    #[test]
+    fn escapes_the_special_characters() {
+        let names = [',', '=', ' '];
+        assert_eq!(escape("a,b=c d", &names), "a\\,b\\=c\\ d");
+        assert_eq!(escape("plain_name", &names), "plain_name");
+        assert_eq!(escape("two\nlines\r", &names), "two\\ lines\\ ");
+        assert_eq!(
+            escape("say \"hi\" \\o/", &['\\', '"']),
+            "say \\\"hi\\\" \\\\o/"
+        );
+    }
+
+
+    #[test]
+    fn renders_the_line() {
+        let point = Point {
+            measurement: "ups stats",
+            tags: vec![
+                ("model", String::from("Back-UPS, 700")),
+                ("host", String::from("my host")),
+            ],
+            fields: vec![
+                field("load", Value::Integer(42)),
+                field("input_voltage", Value::Float(229.5)),
+                field("battery_charge", Value::Float(f64::NAN)),
+                field("battery_voltage", Value::Float(f64::INFINITY)),
+                field("status", Value::Text(String::from("OB \"LB\""))),
+            ],
+            time: UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+        };
+        assert_eq!(
+            line(&point).unwrap(),
+            "ups\\ stats,host=my\\ host,model=Back-UPS\\,\\ 700 \
+             load=42i,input_voltage=229.5,status=\"OB \\\"LB\\\"\" 1700000000123456789"
+        );
+    }
+
+
+    #[test]
+    fn renders_the_entries() {
+        let point = disk("sda", 41.5).to_point();
+        assert_eq!(
+            line(&point).unwrap(),
+            "disk_stats,device=sda,host=nas temperature=41.5,crc_errors=3i 1700000000000000001"
+        );
+    }
+
+
+    #[test]
+    fn skips_the_points_without_finite_fields() {
+        let point = Point {
+            measurement: "sys_stats",
+            tags: vec![("host", String::from("nas"))],
+            fields: vec![field("load_one", Value::Float(f64::NAN))],
+            time: UNIX_EPOCH,
+        };
+        assert_eq!(line(&point), None);
+    }
+
+
+    #[test]
+    fn sends_the_datagrams() {
+        let listener = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sink = InfluxDbSink::new(&InfluxDbConfig {
+            enabled: true,
+            transport: InfluxDbTransport::Udp,
+            address: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        });
+        sink.write(&[Entries::Disk(vec![disk("sda", 41.5), disk("sdb", 38.0)])])
+            .unwrap();
+
+        let mut datagram = [0; 1_500];
+        let length = listener.recv(&mut datagram).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&datagram[..length]).unwrap(),
+            "disk_stats,device=sda,host=nas temperature=41.5,crc_errors=3i 1700000000000000001\n\
+             disk_stats,device=sdb,host=nas temperature=38,crc_errors=3i 1700000000000000001"
+        );
+    }
+
+
+    #[test]
+    fn posts_the_batches() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", server.server_addr());
+        // stand-in of the write API, answering the requests with the status:
+        let stand_in = thread::spawn(move || {
+            [204, 204, 401]
+                .map(|status| {
+                    let mut request = server.recv().unwrap();
+                    let authorization = request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv("Authorization"))
+                        .map(|header| header.value.to_string());
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    let url = request.url().to_string();
+                    request.respond(Response::empty(status)).unwrap();
+                    (url, authorization, body)
+                })
+                .to_vec()
+        });
+
+        let mut sink = InfluxDbSink::new(&InfluxDbConfig {
+            enabled: true,
+            url,
+            org: String::from("home"),
+            bucket: String::from("metrics"),
+            token: Some(String::from("secret")),
+            batch_lines: 2,
+            ..Default::default()
+        });
+        let disks = [("sda", 41.5), ("sdb", 38.0), ("sdc", 35.0)]
+            .map(|(device, temperature)| disk(device, temperature))
+            .to_vec();
+        sink.write(&[Entries::Disk(disks)]).unwrap();
+        assert!(sink
+            .write(&[Entries::Disk(vec![disk("sda", 42.0)])])
+            .is_err());
+
+        let requests = stand_in.join().unwrap();
+        let query = "/api/v2/write?org=home&bucket=metrics&precision=ns";
+        let authorization = Some(String::from("Token secret"));
+        assert_eq!(
+            requests[..2],
+            [
+                (
+                    query.to_string(),
+                    authorization.to_owned(),
+                    String::from(
+                        "disk_stats,device=sda,host=nas temperature=41.5,crc_errors=3i 1700000000000000001\n\
+                         disk_stats,device=sdb,host=nas temperature=38,crc_errors=3i 1700000000000000001"
+                    )
+                ),
+                (
+                    query.to_string(),
+                    authorization,
+                    String::from(
+                        "disk_stats,device=sdc,host=nas temperature=35,crc_errors=3i 1700000000000000001"
+                    )
+                ),
+            ]
+        );
+    }
+}
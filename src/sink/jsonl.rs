@@ -0,0 +1,298 @@
+use crate::{config::JsonlConfig, error::Error, sink::Sink, *};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+
+/// Sequence number, distinguishing files rotated within the same nanosecond
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+
+/// File being written, with its size and the time it was started
+#[derive(Debug)]
+struct OpenFile {
+    file: File,
+    size: u64,
+    started: SystemTime,
+}
+
+
+/// Writes the entries to a file, as one JSON line per collector entries.
+/// The file is rotated by size and age. Rotated files are complete, so they can be shipped
+#[derive(Debug)]
+pub struct JsonlSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    keep: usize,
+    file: Option<OpenFile>,
+}
+
+
+impl JsonlSink {
+    /// Create the sink using the file settings. The file is opened on the first write
+    pub fn new(config: &JsonlConfig) -> Self {
+        Self {
+            path: config.path.to_owned(),
+            max_bytes: config.max_bytes,
+            max_age: Duration::from_secs(config.max_age),
+            keep: config.keep,
+            file: None,
+        }
+    }
+
+
+    /// Open the file for appending, creating it with its directory if necessary
+    fn open(&self) -> Result<OpenFile, Error> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let metadata = file.metadata()?;
+        Ok(OpenFile {
+            size: metadata.len(),
+            started: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+
+
+    /// True if the open file has to be rotated before writing more
+    fn needs_rotation(&self, open_file: &OpenFile) -> bool {
+        let too_big = self.max_bytes > 0 && open_file.size >= self.max_bytes;
+        let too_old = !self.max_age.is_zero()
+            && SystemTime::now()
+                .duration_since(open_file.started)
+                .unwrap_or_default()
+                >= self.max_age;
+        open_file.size > 0 && (too_big || too_old)
+    }
+
+
+    /// Rename the file, appending the rotation time to its name, then remove the oldest
+    /// rotated files above the amount to keep
+    #[instrument(skip(self))]
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file = None;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1_000_000;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{since_epoch:020}-{sequence:06}"));
+        fs::rename(&self.path, &rotated)?;
+        info!("Rotated: {}", PathBuf::from(rotated).display());
+
+        if self.keep == 0 {
+            return Ok(());
+        }
+        let (Some(dir), Some(file_name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.to_path_buf()
+        };
+        let prefix = format!("{}.", file_name.to_string_lossy());
+        let mut rotated_files = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+            })
+            .collect::<Vec<_>>();
+        rotated_files.sort();
+        let excess = rotated_files.len().saturating_sub(self.keep);
+        for old_file in rotated_files.into_iter().take(excess) {
+            debug!("Removing the old rotated file: {}", old_file.display());
+            fs::remove_file(&old_file)?;
+        }
+        Ok(())
+    }
+}
+
+
+impl Sink for JsonlSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let mut lines = vec![];
+        for an_entries in entries.iter().filter(|entries| !entries.is_empty()) {
+            serde_json::to_writer(&mut lines, an_entries).map_err(std::io::Error::from)?;
+            lines.push(b'\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        if self
+            .file
+            .as_ref()
+            .is_some_and(|open_file| self.needs_rotation(open_file))
+        {
+            self.rotate()?;
+        }
+        let open_file = match self.file.take() {
+            Some(open_file) => open_file,
+            None => {
+                let open_file = self.open()?;
+                if self.needs_rotation(&open_file) {
+                    self.rotate()?;
+                    self.open()?
+                } else {
+                    open_file
+                }
+            }
+        };
+        let open_file = self.file.insert(open_file);
+        if let Err(error) = open_file.file.write_all(&lines) {
+            // reopened on the next write:
+            self.file = None;
+            return Err(error.into());
+        }
+        open_file.size += lines.len() as u64;
+        Ok(())
+    }
+
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(open_file) = self.file.as_mut() {
+            open_file.file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SysStat;
+    use std::{env, path::Path, process};
+
+
+    /// Sink writing to the file in its own temporary directory
+    fn sink(name: &str, max_bytes: u64, keep: usize) -> JsonlSink {
+        let dir = env::temp_dir().join(format!("dcollector-jsonl-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        JsonlSink::new(&JsonlConfig {
+            enabled: true,
+            path: dir.join("dcollector.jsonl"),
+            max_bytes,
+            max_age: 0,
+            keep,
+        })
+    }
+
+
+    /// Entries of the tick
+    fn entries(tick: u64) -> Vec<Entries> {
+        vec![Entries::Sys(vec![SysStat {
+            time: UNIX_EPOCH + Duration::from_secs(tick),
+            host_name: String::from("nas"),
+            ..Default::default()
+        }])]
+    }
+
+
+    /// Files of the directory, sorted by name, with the ticks of their entries
+    fn files(dir: &Path) -> Vec<(String, Vec<u64>)> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| {
+                let ticks = fs::read_to_string(&path)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        match serde_json::from_str(line).unwrap() {
+                            Entries::Sys(entries) => {
+                                entries[0]
+                                    .time
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap()
+                                    .as_secs()
+                            }
+                            other => panic!("unexpected entries: {other:?}"),
+                        }
+                    })
+                    .collect();
+                (
+                    path.file_name().unwrap().to_string_lossy().to_string(),
+                    ticks,
+                )
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+
+    #[test]
+    fn rotates_without_losing_the_entries() {
+        let mut sink = sink("rotation", 1, 0);
+        // rotated within the same second:
+        for tick in 1..=5 {
+            sink.write(&entries(tick)).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let files = files(sink.path.parent().unwrap());
+        assert_eq!(files.len(), 5);
+        assert_eq!(files[0], (String::from("dcollector.jsonl"), vec![5]));
+        for (tick, (name, ticks)) in (1..=4).zip(&files[1..]) {
+            assert!(name.starts_with("dcollector.jsonl.0"), "{name}");
+            assert_eq!(ticks, &[tick]);
+        }
+        fs::remove_dir_all(sink.path.parent().unwrap()).unwrap();
+    }
+
+
+    #[test]
+    fn keeps_the_newest_rotated_files() {
+        let mut sink = sink("keep", 1, 2);
+        let dir = sink.path.parent().unwrap().to_path_buf();
+        fs::create_dir_all(&dir).unwrap();
+        // rotated by an older version, named after the rotation second:
+        fs::write(dir.join("dcollector.jsonl.001700000000"), "").unwrap();
+        for tick in 1..=5 {
+            sink.write(&entries(tick)).unwrap();
+        }
+
+        let ticks = files(&dir)
+            .into_iter()
+            .map(|(_, ticks)| ticks)
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, [vec![5], vec![3], vec![4]]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn appends_to_the_file_below_the_limits() {
+        let mut sink = sink("append", 1_024 * 1_024, 1);
+        sink.write(&entries(1)).unwrap();
+        sink.write(&[]).unwrap();
+        // reopened by the next instance of the sink:
+        let mut sink = JsonlSink {
+            file: None,
+            ..sink
+        };
+        sink.write(&entries(2)).unwrap();
+        let dir = sink.path.parent().unwrap();
+        assert_eq!(files(dir), [(String::from("dcollector.jsonl"), vec![1, 2])]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
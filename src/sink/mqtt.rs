@@ -0,0 +1,749 @@
+use crate::{
+    config::MqttConfig,
+    error::Error,
+    point::{Point, Value},
+    sink::{wait_or, Sink},
+    *,
+};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Map, Value as Json};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+
+/// Capacity of the queue of the messages waiting for the connection
+const QUEUE_CAPACITY: usize = 1_000;
+
+/// Delay between the reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Payload of the availability topic while the agent runs
+const ONLINE: &str = "online";
+
+/// Payload of the availability topic when the agent stops, or its connection is lost
+const OFFLINE: &str = "offline";
+
+
+/// Home Assistant sensor of an entries field
+#[derive(Debug, Clone, Copy)]
+struct Sensor {
+    /// Published entries, like: "ups"
+    entries: &'static str,
+    /// Field of the entries
+    field: &'static str,
+    /// Name of the sensor, shown by Home Assistant
+    name: &'static str,
+    /// Unit of the value
+    unit: Option<&'static str>,
+    /// Home Assistant device class
+    device_class: Option<&'static str>,
+}
+
+
+/// Sensors announced by the Home Assistant discovery
+const SENSORS: [Sensor; 17] = [
+    Sensor {
+        entries: "ups",
+        field: "status",
+        name: "UPS status",
+        unit: None,
+        device_class: None,
+    },
+    Sensor {
+        entries: "ups",
+        field: "load",
+        name: "UPS load",
+        unit: Some("%"),
+        device_class: None,
+    },
+    Sensor {
+        entries: "ups",
+        field: "battery_charge",
+        name: "UPS battery charge",
+        unit: Some("%"),
+        device_class: Some("battery"),
+    },
+    Sensor {
+        entries: "ups",
+        field: "battery_voltage",
+        name: "UPS battery voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+    },
+    Sensor {
+        entries: "ups",
+        field: "input_voltage",
+        name: "UPS input voltage",
+        unit: Some("V"),
+        device_class: Some("voltage"),
+    },
+    Sensor {
+        entries: "ups",
+        field: "input_frequency",
+        name: "UPS input frequency",
+        unit: Some("Hz"),
+        device_class: Some("frequency"),
+    },
+    Sensor {
+        entries: "sys",
+        field: "load_one",
+        name: "Load (1m)",
+        unit: None,
+        device_class: None,
+    },
+    Sensor {
+        entries: "sys",
+        field: "load_five",
+        name: "Load (5m)",
+        unit: None,
+        device_class: None,
+    },
+    Sensor {
+        entries: "sys",
+        field: "load_fifteen",
+        name: "Load (15m)",
+        unit: None,
+        device_class: None,
+    },
+    Sensor {
+        entries: "sys",
+        field: "cpu_usage",
+        name: "CPU usage",
+        unit: Some("%"),
+        device_class: None,
+    },
+    Sensor {
+        entries: "sys",
+        field: "processors",
+        name: "Processors",
+        unit: None,
+        device_class: None,
+    },
+    Sensor {
+        entries: "sys",
+        field: "used_memory",
+        name: "Used memory",
+        unit: Some("B"),
+        device_class: Some("data_size"),
+    },
+    Sensor {
+        entries: "sys",
+        field: "total_memory",
+        name: "Total memory",
+        unit: Some("B"),
+        device_class: Some("data_size"),
+    },
+    Sensor {
+        entries: "sys",
+        field: "used_swap",
+        name: "Used swap",
+        unit: Some("B"),
+        device_class: Some("data_size"),
+    },
+    Sensor {
+        entries: "sys",
+        field: "total_swap",
+        name: "Total swap",
+        unit: Some("B"),
+        device_class: Some("data_size"),
+    },
+    Sensor {
+        entries: "sys",
+        field: "kernel_version",
+        name: "Kernel version",
+        unit: None,
+        device_class: None,
+    },
+    // host temperatures come from the disks:
+    Sensor {
+        entries: "disk",
+        field: "temperature",
+        name: "temperature",
+        unit: Some("°C"),
+        device_class: Some("temperature"),
+    },
+];
+
+
+/// Connection to the broker, progressed by its own thread.
+/// The thread publishes the availability on each (re)connection
+struct Connection {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    reconnected: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("connected", &self.connected)
+            .finish()
+    }
+}
+
+
+impl Connection {
+    /// Start connecting to the broker
+    fn start(options: MqttOptions, availability: String) -> Result<Self, Error> {
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        let reconnected = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new().name(String::from("mqtt")).spawn({
+            let client = client.clone();
+            let connected = connected.clone();
+            let reconnected = reconnected.clone();
+            let stopping = stopping.clone();
+            move || {
+                let mut connected_before = false;
+                for event in connection.iter() {
+                    match event {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("Connected to the MQTT broker.");
+                            connected.store(true, Ordering::Relaxed);
+                            reconnected.store(connected_before, Ordering::Relaxed);
+                            connected_before = true;
+                            if let Err(error) = client.try_publish(
+                                &availability,
+                                QoS::AtLeastOnce,
+                                true,
+                                ONLINE,
+                            ) {
+                                warn!("Can't publish the availability: {error}");
+                            }
+                        }
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(_) if stopping.load(Ordering::Relaxed) => break,
+                        Err(error) => {
+                            if connected.swap(false, Ordering::Relaxed) {
+                                warn!("MQTT connection lost: {error}");
+                            } else {
+                                debug!("MQTT connection failed: {error}");
+                            }
+                            wait_or(RECONNECT_DELAY, || stopping.load(Ordering::Relaxed));
+                        }
+                    }
+                }
+                connected.store(false, Ordering::Relaxed);
+            }
+        })?;
+        Ok(Self {
+            client,
+            connected,
+            reconnected,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+}
+
+
+/// Publishes the entries to an MQTT broker, as JSON states of one topic per entry.
+/// Agent availability is kept in its own topic, set to offline by the broker (as the last will)
+/// if the agent is gone. UPS, system and disk temperature sensors are announced to
+/// Home Assistant using its MQTT discovery. The connection starts on the first write
+#[derive(Debug)]
+pub struct MqttSink {
+    options: MqttOptions,
+    host_name: String,
+    host_topic: String,
+    availability: String,
+    publish: Vec<String>,
+    qos: QoS,
+    retain: bool,
+    discovery_prefix: Option<String>,
+    discovered: HashSet<String>,
+    connection: Option<Connection>,
+}
+
+
+impl MqttSink {
+    /// Create the sink using the MQTT settings, for the host identity
+    pub fn new(config: &MqttConfig, host_name: &str) -> Self {
+        let host_topic = format!(
+            "{}/{}",
+            config.topic_prefix.trim_end_matches('/'),
+            topic_level(host_name)
+        );
+        let availability = format!("{host_topic}/availability");
+        let client_id = config
+            .client_id
+            .to_owned()
+            .unwrap_or_else(|| format!("dcollector-{}", topic_level(host_name)));
+        let mut options = MqttOptions::new(client_id, &config.host, config.port);
+        options
+            .set_keep_alive(Duration::from_secs(config.keep_alive))
+            .set_last_will(LastWill::new(
+                &availability,
+                OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        Self {
+            options,
+            host_name: host_name.to_string(),
+            host_topic,
+            availability,
+            publish: config.publish.clone(),
+            qos: match config.qos {
+                0 => QoS::AtMostOnce,
+                _ => QoS::AtLeastOnce,
+            },
+            retain: config.retain,
+            discovery_prefix: config
+                .discovery
+                .then(|| config.discovery_prefix.trim_end_matches('/').to_string()),
+            discovered: HashSet::new(),
+            connection: None,
+        }
+    }
+
+
+    /// Topic of the entry state, and the short name of its entries
+    fn state_topic(&self, point: &Point) -> Option<(String, &'static str)> {
+        let (entries, id_tag) = match point.measurement {
+            "sys_stats" => ("sys", None),
+            "ups_stats" => ("ups", None),
+            "disk_stats" => ("disk", Some("device")),
+            "net_stats" => ("net", Some("netdev")),
+            "proc_stats" => ("proc", Some("pid")),
+            "collector_runs" => ("runs", Some("collector")),
+            "alerts" => ("alerts", Some("rule")),
+            _ => return None,
+        };
+        if !self.publish.iter().any(|name| name == entries) {
+            return None;
+        }
+        let mut topic = format!("{}/{entries}", self.host_topic);
+        if let Some(id_tag) = id_tag {
+            let id = tag(point, id_tag)?;
+            topic.push('/');
+            topic.push_str(&topic_level(id));
+        }
+        // alerts of the rule for each entry, like of each disk, get their own topics:
+        if let Some(instance) = tag(point, "instance").filter(|_| entries == "alerts") {
+            topic.push('/');
+            topic.push_str(&topic_level(instance));
+        }
+        Some((topic, entries))
+    }
+
+
+    /// Home Assistant discovery configs of the sensors of the entry, not announced yet
+    fn discovery_configs(
+        &mut self,
+        point: &Point,
+        entries: &str,
+        state_topic: &str,
+    ) -> Vec<(String, Json)> {
+        let Some(discovery_prefix) = &self.discovery_prefix else {
+            return vec![];
+        };
+        let node_id = format!("dcollector_{}", object_id(&self.host_name));
+        let device = json!({
+            "identifiers": [node_id],
+            "name": self.host_name,
+            "manufacturer": "dcollector",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let mut configs = vec![];
+        for sensor in SENSORS.iter().filter(|sensor| sensor.entries == entries) {
+            // per device sensors, like the disk temperatures, are named after the device:
+            let (object, name) = match entries {
+                "disk" => {
+                    let device_name = tag(point, "device").unwrap_or_default();
+                    (
+                        format!("{entries}_{}_{}", object_id(device_name), sensor.field),
+                        format!("{device_name} {}", sensor.name),
+                    )
+                }
+                _ => {
+                    (
+                        format!("{entries}_{}", sensor.field),
+                        sensor.name.to_string(),
+                    )
+                }
+            };
+            let topic = format!("{discovery_prefix}/sensor/{node_id}/{object}/config");
+            if !self.discovered.insert(topic.clone()) {
+                continue;
+            }
+            let mut config = json!({
+                "name": name,
+                "unique_id": format!("{node_id}_{object}"),
+                "object_id": format!("{node_id}_{object}"),
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{} }}}}", sensor.field),
+                "availability_topic": self.availability,
+                "device": device,
+            });
+            if let Some(unit) = sensor.unit {
+                config["unit_of_measurement"] = json!(unit);
+                config["state_class"] = json!("measurement");
+            }
+            if let Some(device_class) = sensor.device_class {
+                config["device_class"] = json!(device_class);
+            }
+            configs.push((topic, config));
+        }
+        configs
+    }
+
+
+    /// Queue the message for publishing
+    fn publish(
+        connection: &Connection,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: &Json,
+    ) -> Result<(), Error> {
+        connection
+            .client
+            .try_publish(topic, qos, retain, payload.to_string())
+            .map_err(|error| Error::Unavailable(format!("MQTT publish failed: {error}")))
+    }
+}
+
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let connection = match self.connection.take() {
+            Some(connection) => {
+                if !connection.connected.load(Ordering::Relaxed) {
+                    self.connection = Some(connection);
+                    return Err(Error::Unavailable(String::from(
+                        "not connected to the MQTT broker",
+                    )));
+                }
+                connection
+            }
+            // queued until connected:
+            None => Connection::start(self.options.clone(), self.availability.clone())?,
+        };
+        // the broker may have lost the retained configs:
+        if connection.reconnected.swap(false, Ordering::Relaxed) {
+            self.discovered.clear();
+        }
+
+        let mut messages = vec![];
+        for point in entries.iter().flat_map(Entries::to_points) {
+            let Some((topic, short_name)) = self.state_topic(&point) else {
+                continue;
+            };
+            for (config_topic, config) in self.discovery_configs(&point, short_name, &topic) {
+                messages.push((config_topic, QoS::AtLeastOnce, true, config));
+            }
+            messages.push((topic, self.qos, self.retain, state(&point)));
+        }
+        let published = messages
+            .into_iter()
+            .try_for_each(|(topic, qos, retain, payload)| {
+                Self::publish(&connection, topic, qos, retain, &payload)
+            });
+        if published.is_err() {
+            // announced again with the next entries:
+            self.discovered.clear();
+        }
+        self.connection = Some(connection);
+        published
+    }
+}
+
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        let Some(mut connection) = self.connection.take() else {
+            return;
+        };
+        // stopped on purpose, so the last will isn't sent by the broker:
+        connection.stopping.store(true, Ordering::Relaxed);
+        let _ =
+            connection
+                .client
+                .try_publish(&self.availability, QoS::AtLeastOnce, true, OFFLINE);
+        let _ = connection.client.try_disconnect();
+        if let Some(thread) = connection.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// State of the entry: its tags and fields, with the time in seconds since UNIX_EPOCH
+fn state(point: &Point) -> Json {
+    let mut state = Map::new();
+    state.insert(
+        String::from("time"),
+        json!(point
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()),
+    );
+    for (name, value) in &point.tags {
+        state.insert(name.to_string(), json!(value));
+    }
+    for field in &point.fields {
+        let value = match &field.value {
+            Value::Float(value) => json!(value),
+            Value::Integer(value) => json!(value),
+            Value::Text(text) => json!(text),
+        };
+        state.insert(field.name.to_string(), value);
+    }
+    Json::Object(state)
+}
+
+
+/// Value of the point tag
+fn tag<'a>(point: &'a Point, name: &str) -> Option<&'a str> {
+    point
+        .tags
+        .iter()
+        .find_map(|(tag_name, value)| (*tag_name == name).then_some(value.as_str()))
+}
+
+
+/// Name usable as a single topic level, without the separators and wildcards
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+
+/// Name usable as a Home Assistant object id
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() {
+                character.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::ToPoint;
+
+
+    /// Sink of the host, publishing the entries
+    fn sink(publish: &[&str]) -> MqttSink {
+        let config = MqttConfig {
+            topic_prefix: String::from("dcollector/"),
+            publish: publish.iter().map(|name| name.to_string()).collect(),
+            discovery_prefix: String::from("homeassistant/"),
+            ..Default::default()
+        };
+        MqttSink::new(&config, "nas/1")
+    }
+
+
+    /// Disk entry of the device
+    fn disk(device: &str) -> DiskStat {
+        DiskStat {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            host_name: String::from("nas/1"),
+            name: device.to_string(),
+            temperature: Some(41.5),
+            crc_errors: Some(3),
+            ..Default::default()
+        }
+    }
+
+
+    /// Alert of the rule, for the alerting entry
+    fn alert(rule: &str, instance: &str) -> Alert {
+        Alert {
+            time: UNIX_EPOCH,
+            host_name: String::from("nas/1"),
+            rule: rule.to_string(),
+            instance: instance.to_string(),
+            state: String::from("firing"),
+            severity: String::from("warning"),
+            value: None,
+            message: String::new(),
+        }
+    }
+
+
+    #[test]
+    fn sanitizes_the_topic_levels() {
+        assert_eq!(topic_level("nas"), "nas");
+        assert_eq!(topic_level("a/b+c#d"), "a_b_c_d");
+        assert_eq!(topic_level("/dev/sda"), "_dev_sda");
+        assert_eq!(object_id("My-NAS.local"), "my_nas_local");
+    }
+
+
+    #[test]
+    fn names_the_state_topics() {
+        let sink = sink(&["sys", "disk", "proc", "alerts"]);
+        let topic = |point: Point| sink.state_topic(&point);
+        let sys = SysStat {
+            host_name: String::from("nas/1"),
+            ..Default::default()
+        };
+        assert_eq!(
+            topic(sys.to_point()),
+            Some((String::from("dcollector/nas_1/sys"), "sys"))
+        );
+        assert_eq!(
+            topic(disk("sda").to_point()),
+            Some((String::from("dcollector/nas_1/disk/sda"), "disk"))
+        );
+        let process = ProcStat {
+            pid: 42,
+            name: Some(String::from("postgres")),
+            ..Default::default()
+        };
+        assert_eq!(
+            topic(process.to_point()),
+            Some((String::from("dcollector/nas_1/proc/42"), "proc"))
+        );
+        assert_eq!(
+            topic(alert("disk_hot", "device=sda").to_point()),
+            Some((
+                String::from("dcollector/nas_1/alerts/disk_hot/device=sda"),
+                "alerts"
+            ))
+        );
+        assert_eq!(
+            topic(alert("swapping", "").to_point()),
+            Some((String::from("dcollector/nas_1/alerts/swapping"), "alerts"))
+        );
+        // not published:
+        assert_eq!(topic(UpsStat::default().to_point()), None);
+    }
+
+
+    #[test]
+    fn states_hold_the_tags_and_fields() {
+        assert_eq!(
+            state(&disk("sda").to_point()),
+            json!({
+                "time": 1_700_000_000,
+                "host": "nas/1",
+                "device": "sda",
+                "temperature": 41.5,
+                "crc_errors": 3,
+            })
+        );
+    }
+
+
+    #[test]
+    fn announces_the_sensors_once() {
+        let mut sink = sink(&["ups"]);
+        let point = UpsStat::default().to_point();
+        let configs = sink.discovery_configs(&point, "ups", "dcollector/nas_1/ups");
+        let topics = configs
+            .iter()
+            .map(|(topic, _)| topic.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/dcollector_nas_1/ups_status/config",
+                "homeassistant/sensor/dcollector_nas_1/ups_load/config",
+                "homeassistant/sensor/dcollector_nas_1/ups_battery_charge/config",
+                "homeassistant/sensor/dcollector_nas_1/ups_battery_voltage/config",
+                "homeassistant/sensor/dcollector_nas_1/ups_input_voltage/config",
+                "homeassistant/sensor/dcollector_nas_1/ups_input_frequency/config",
+            ]
+        );
+        assert_eq!(
+            configs[2].1,
+            json!({
+                "name": "UPS battery charge",
+                "unique_id": "dcollector_nas_1_ups_battery_charge",
+                "object_id": "dcollector_nas_1_ups_battery_charge",
+                "state_topic": "dcollector/nas_1/ups",
+                "value_template": "{{ value_json.battery_charge }}",
+                "availability_topic": "dcollector/nas_1/availability",
+                "device": {
+                    "identifiers": ["dcollector_nas_1"],
+                    "name": "nas/1",
+                    "manufacturer": "dcollector",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+                "unit_of_measurement": "%",
+                "state_class": "measurement",
+                "device_class": "battery",
+            })
+        );
+        // without a unit, it's not a measurement:
+        assert_eq!(configs[0].1.get("state_class"), None);
+        assert!(sink
+            .discovery_configs(&point, "ups", "dcollector/nas_1/ups")
+            .is_empty());
+    }
+
+
+    #[test]
+    fn names_the_sensors_of_the_devices() {
+        let mut sink = sink(&["disk"]);
+        let configs = sink.discovery_configs(
+            &disk("sda").to_point(),
+            "disk",
+            "dcollector/nas_1/disk/sda",
+        );
+        assert_eq!(configs.len(), 1);
+        let (topic, config) = &configs[0];
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/dcollector_nas_1/disk_sda_temperature/config"
+        );
+        assert_eq!(config["name"], "sda temperature");
+        assert_eq!(config["state_topic"], "dcollector/nas_1/disk/sda");
+        assert_eq!(config["unit_of_measurement"], "°C");
+        assert_eq!(config["device_class"], "temperature");
+        // each device has its own sensor:
+        let configs = sink.discovery_configs(
+            &disk("sdb").to_point(),
+            "disk",
+            "dcollector/nas_1/disk/sdb",
+        );
+        assert_eq!(configs.len(), 1);
+    }
+
+
+    #[test]
+    fn announces_nothing_without_the_discovery() {
+        let mut sink = MqttSink::new(
+            &MqttConfig {
+                discovery: false,
+                ..Default::default()
+            },
+            "nas",
+        );
+        let point = UpsStat::default().to_point();
+        assert!(sink
+            .discovery_configs(&point, "ups", "dcollector/nas/ups")
+            .is_empty());
+    }
+}
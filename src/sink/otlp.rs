@@ -0,0 +1,601 @@
+use crate::{
+    config::OtlpConfig,
+    error::Error,
+    point::{Point, Value},
+    sink::Sink,
+    *,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, VecDeque},
+    fmt::Debug,
+    hash::{BuildHasher, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime},
+};
+use sysinfo::{System, SystemExt};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+
+/// Name and version of the instrumentation scope
+const SCOPE: &str = "dcollector";
+
+/// Spans kept until the next export. The oldest ones are dropped above it
+const MAX_BUFFERED_SPANS: usize = 10_000;
+
+/// Aggregation temporality of the sums: cumulative
+const CUMULATIVE: u8 = 2;
+
+/// Kind of the spans: internal
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+/// Status code of the spans with an error event
+const STATUS_CODE_ERROR: u8 = 2;
+
+
+/// True if the spans are exported
+static EXPORT_SPANS: AtomicBool = AtomicBool::new(false);
+
+/// Closed spans, waiting for the export
+static SPANS: Mutex<VecDeque<SpanData>> = Mutex::new(VecDeque::new());
+
+
+/// Start or stop buffering the spans for the export
+pub fn export_spans(enabled: bool) {
+    EXPORT_SPANS.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        SPANS.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+
+/// Recorded span
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    name: &'static str,
+    target: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+
+/// Collects the span and event fields as text
+struct FieldsVisitor<'a>(&'a mut Vec<(String, String)>);
+
+
+impl Visit for FieldsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .push((field.name().to_string(), format!("{value:?}")));
+    }
+}
+
+
+/// Tracing layer recording the spans for the OTLP sink,
+/// while it has the trace export enabled
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpanLayer;
+
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !EXPORT_SPANS.load(Ordering::Relaxed) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+        let mut attributes = vec![];
+        attrs.record(&mut FieldsVisitor(&mut attributes));
+        span.extensions_mut().insert(SpanData {
+            trace_id: parent
+                .map(|(trace_id, _)| trace_id)
+                .unwrap_or_else(|| (random_id() as u128) << 64 | random_id() as u128),
+            span_id: random_id(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            target: attrs.metadata().target(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes,
+            error: None,
+        });
+    }
+
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut FieldsVisitor(&mut data.attributes));
+            }
+        }
+    }
+
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        // errors logged inside of the span mark it as failed:
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                let mut fields = vec![];
+                event.record(&mut FieldsVisitor(&mut fields));
+                data.error = fields
+                    .into_iter()
+                    .find_map(|(name, value)| (name == "message").then_some(value));
+            }
+        }
+    }
+
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        if !EXPORT_SPANS.load(Ordering::Relaxed) {
+            return;
+        }
+        data.end = SystemTime::now();
+        let mut spans = SPANS.lock().unwrap_or_else(PoisonError::into_inner);
+        if spans.len() >= MAX_BUFFERED_SPANS {
+            spans.pop_front();
+        }
+        spans.push_back(data);
+    }
+}
+
+
+/// Exports the entries as OTLP metrics over HTTP (JSON encoded), with the spans of the agent
+/// if enabled. Measured values are gauges, counters are cumulative monotonic sums,
+/// starting at the host boot (or the process start). Each host is a resource
+#[derive(Debug)]
+pub struct OtlpSink {
+    agent: ureq::Agent,
+    endpoint: String,
+    traces: bool,
+    service_name: String,
+    headers: BTreeMap<String, String>,
+    booted: SystemTime,
+}
+
+
+impl OtlpSink {
+    /// Create the sink using the OTLP settings
+    pub fn new(config: &OtlpConfig) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout))
+                .build(),
+            endpoint: config.endpoint.trim_end_matches('/').to_string(),
+            traces: config.traces,
+            service_name: config.service_name.to_owned(),
+            headers: config.headers.clone(),
+            booted: UNIX_EPOCH + Duration::from_secs(System::new().boot_time()),
+        }
+    }
+
+
+    /// Post the JSON encoded request to the signal path, like: "/v1/metrics"
+    fn post(&self, path: &str, request: &Json) -> Result<(), Error> {
+        let mut post = self
+            .agent
+            .post(&format!("{}{path}", self.endpoint))
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            post = post.set(name, value);
+        }
+        post.send_string(&request.to_string())?;
+        Ok(())
+    }
+
+
+    /// Resource attributes of the agent on the host
+    fn resource(&self, host_name: &str) -> Json {
+        let mut attributes = vec![
+            attribute("service.name", &self.service_name),
+            attribute("service.version", env!("CARGO_PKG_VERSION")),
+        ];
+        if !host_name.is_empty() {
+            attributes.push(attribute("host.name", host_name));
+        }
+        json!({ "attributes": attributes })
+    }
+
+
+    /// Metrics request of the points, grouped by host and metric name
+    fn metrics_request(&self, points: &[Point]) -> Json {
+        let mut hosts: BTreeMap<&str, BTreeMap<String, (bool, Vec<Json>)>> = BTreeMap::new();
+        for point in points {
+            let host_name = point
+                .tags
+                .iter()
+                .find_map(|(name, value)| (*name == "host").then_some(value.as_str()))
+                .unwrap_or_default();
+            let attributes = point
+                .tags
+                .iter()
+                .filter(|(name, _)| *name != "host")
+                .map(|(name, value)| attribute(name, value))
+                .collect::<Vec<_>>();
+            let subsystem = point.measurement.trim_end_matches("_stats");
+            let time = nanos(point.time);
+            // process counters are cumulative since the process start, others since the boot:
+            let start = point
+                .fields
+                .iter()
+                .find_map(|field| {
+                    match (field.name, &field.value) {
+                        ("start_time", Value::Integer(seconds)) => {
+                            Some(UNIX_EPOCH + Duration::from_secs(*seconds as u64))
+                        }
+                        _ => None,
+                    }
+                })
+                .unwrap_or(self.booted);
+            let start = nanos(start);
+
+            for field in &point.fields {
+                let mut attributes = attributes.clone();
+                let value = match &field.value {
+                    Value::Float(value) => json!({ "asDouble": value }),
+                    Value::Integer(value) => json!({ "asInt": value.to_string() }),
+                    // collector run errors and alert messages aren't worth a series each:
+                    Value::Text(_)
+                        if matches!(point.measurement, "collector_runs" | "alerts") =>
+                    {
+                        continue
+                    }
+                    // text values, like the UPS status, become an attribute of the constant:
+                    Value::Text(text) => {
+                        attributes.push(attribute(field.name, text));
+                        json!({ "asInt": "1" })
+                    }
+                };
+                let mut data_point = json!({
+                    "attributes": attributes,
+                    "timeUnixNano": time,
+                });
+                if field.monotonic {
+                    data_point["startTimeUnixNano"] = json!(start);
+                }
+                if let (Json::Object(data_point), Json::Object(value)) =
+                    (&mut data_point, value)
+                {
+                    data_point.extend(value);
+                }
+                hosts
+                    .entry(host_name)
+                    .or_default()
+                    .entry(format!("{SCOPE}.{subsystem}.{}", field.name))
+                    .or_insert_with(|| (field.monotonic, vec![]))
+                    .1
+                    .push(data_point);
+            }
+        }
+
+        let resource_metrics = hosts
+            .into_iter()
+            .map(|(host_name, metrics)| {
+                let metrics = metrics
+                    .into_iter()
+                    .map(|(name, (monotonic, data_points))| {
+                        if monotonic {
+                            json!({
+                                "name": name,
+                                "sum": {
+                                    "aggregationTemporality": CUMULATIVE,
+                                    "isMonotonic": true,
+                                    "dataPoints": data_points,
+                                },
+                            })
+                        } else {
+                            json!({ "name": name, "gauge": { "dataPoints": data_points } })
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                json!({
+                    "resource": self.resource(host_name),
+                    "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "resourceMetrics": resource_metrics })
+    }
+
+
+    /// Traces request of the spans
+    fn traces_request(&self, spans: &[SpanData]) -> Json {
+        let spans = spans
+            .iter()
+            .map(|span| {
+                let mut attributes = vec![attribute("code.namespace", span.target)];
+                attributes.extend(
+                    span.attributes
+                        .iter()
+                        .map(|(name, value)| attribute(name, value)),
+                );
+                let mut json = json!({
+                    "traceId": format!("{:032x}", span.trace_id),
+                    "spanId": format!("{:016x}", span.span_id),
+                    "name": span.name,
+                    "kind": SPAN_KIND_INTERNAL,
+                    "startTimeUnixNano": nanos(span.start),
+                    "endTimeUnixNano": nanos(span.end),
+                    "attributes": attributes,
+                });
+                if let Some(parent_span_id) = span.parent_span_id {
+                    json["parentSpanId"] = json!(format!("{parent_span_id:016x}"));
+                }
+                if let Some(error) = &span.error {
+                    json["status"] = json!({ "code": STATUS_CODE_ERROR, "message": error });
+                }
+                json
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "resourceSpans": [{
+                "resource": self.resource(""),
+                "scopeSpans": [{ "scope": scope(), "spans": spans }],
+            }],
+        })
+    }
+
+
+    /// Export the buffered spans. Spans are dropped if the export fails
+    fn export_spans(&self) -> Result<(), Error> {
+        if !self.traces {
+            return Ok(());
+        }
+        let spans = Vec::from(mem::take(
+            &mut *SPANS.lock().unwrap_or_else(PoisonError::into_inner),
+        ));
+        if spans.is_empty() {
+            return Ok(());
+        }
+        self.post("/v1/traces", &self.traces_request(&spans))
+    }
+}
+
+
+impl Sink for OtlpSink {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let points = entries
+            .iter()
+            .flat_map(Entries::to_points)
+            .collect::<Vec<_>>();
+        let metrics = if points.is_empty() {
+            Ok(())
+        } else {
+            self.post("/v1/metrics", &self.metrics_request(&points))
+        };
+        // spans are exported even if the metrics fail:
+        let spans = self.export_spans();
+        metrics.and(spans)
+    }
+
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.export_spans()
+    }
+}
+
+
+/// Instrumentation scope of the agent
+fn scope() -> Json {
+    json!({ "name": SCOPE, "version": env!("CARGO_PKG_VERSION") })
+}
+
+
+/// Key-value attribute with the string value
+fn attribute(key: &str, value: &str) -> Json {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+
+/// Nanoseconds since UNIX_EPOCH, as text. JSON numbers can't hold 64 bit integers
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+
+/// Random, non-zero identifier of a trace or a span
+fn random_id() -> u64 {
+    // each RandomState has new random keys:
+    RandomState::new().build_hasher().finish().max(1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{DiskStat, ProcStat},
+        point::ToPoint,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+
+    /// Seconds since UNIX_EPOCH as the time
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+
+    /// Sink of the host booted at the second 1000
+    fn sink() -> OtlpSink {
+        let mut sink = OtlpSink::new(&OtlpConfig {
+            service_name: String::from("agent"),
+            ..Default::default()
+        });
+        sink.booted = at(1_000);
+        sink
+    }
+
+
+    #[test]
+    fn renders_the_gauges_and_the_sums() {
+        let disk = DiskStat {
+            time: at(2_000),
+            host_name: String::from("nas"),
+            name: String::from("sda"),
+            temperature: Some(41.5),
+            crc_errors: Some(3),
+            ..Default::default()
+        };
+        let process = ProcStat {
+            time: at(2_000),
+            host_name: String::from("nuc"),
+            pid: 7,
+            start_time: Some(at(1_500)),
+            disk_read_total: Some(42),
+            ..Default::default()
+        };
+        let request = sink().metrics_request(&[disk.to_point(), process.to_point()]);
+        let resources = request["resourceMetrics"].as_array().unwrap();
+        assert_eq!(resources.len(), 2);
+        assert_eq!(
+            resources[0]["resource"],
+            json!({ "attributes": [
+                attribute("service.name", "agent"),
+                attribute("service.version", env!("CARGO_PKG_VERSION")),
+                attribute("host.name", "nas"),
+            ]})
+        );
+        assert_eq!(resources[0]["scopeMetrics"][0]["scope"], scope());
+
+        // counters are cumulative since the boot, measured values are gauges:
+        assert_eq!(
+            resources[0]["scopeMetrics"][0]["metrics"],
+            json!([
+                {
+                    "name": "dcollector.disk.crc_errors",
+                    "sum": {
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                        "dataPoints": [{
+                            "attributes": [attribute("device", "sda")],
+                            "timeUnixNano": "2000000000000",
+                            "startTimeUnixNano": "1000000000000",
+                            "asInt": "3",
+                        }],
+                    },
+                },
+                {
+                    "name": "dcollector.disk.temperature",
+                    "gauge": {
+                        "dataPoints": [{
+                            "attributes": [attribute("device", "sda")],
+                            "timeUnixNano": "2000000000000",
+                            "asDouble": 41.5,
+                        }],
+                    },
+                },
+            ])
+        );
+
+        // process counters are cumulative since the process start:
+        let metrics = resources[1]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+        let disk_read = metrics
+            .iter()
+            .find(|metric| metric["name"] == "dcollector.proc.disk_read_total")
+            .unwrap();
+        assert_eq!(disk_read["sum"]["isMonotonic"], true);
+        assert_eq!(
+            disk_read["sum"]["dataPoints"][0]["startTimeUnixNano"],
+            "1500000000000"
+        );
+        let start_time = metrics
+            .iter()
+            .find(|metric| metric["name"] == "dcollector.proc.start_time")
+            .unwrap();
+        assert_eq!(start_time["gauge"]["dataPoints"][0]["asInt"], "1500");
+    }
+
+
+    #[test]
+    fn buffers_the_newest_spans() {
+        export_spans(true);
+        let subscriber = tracing_subscriber::registry().with(SpanLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _tick = tracing::info_span!("tick").entered();
+            for index in 0..MAX_BUFFERED_SPANS + 5 {
+                let _collect = tracing::info_span!("collect", index).entered();
+                if index == MAX_BUFFERED_SPANS {
+                    tracing::error!("failed");
+                }
+            }
+        });
+        let spans = Vec::from(mem::take(&mut *SPANS.lock().unwrap()));
+        export_spans(false);
+
+        // the oldest spans are dropped, the tick is closed last:
+        assert_eq!(spans.len(), MAX_BUFFERED_SPANS);
+        assert_eq!(
+            spans[0].attributes,
+            [(String::from("index"), String::from("6"))]
+        );
+        let tick = spans.last().unwrap();
+        assert_eq!((tick.name, tick.parent_span_id), ("tick", None));
+
+        let request = sink().traces_request(&spans);
+        let exported = request["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let failed = &exported[MAX_BUFFERED_SPANS - 6];
+        assert_eq!(
+            failed["attributes"][1],
+            attribute("index", &MAX_BUFFERED_SPANS.to_string())
+        );
+        assert_eq!(
+            failed["status"],
+            json!({ "code": STATUS_CODE_ERROR, "message": "failed" })
+        );
+        assert_eq!(failed["traceId"], json!(format!("{:032x}", tick.trace_id)));
+        assert_eq!(
+            failed["parentSpanId"],
+            json!(format!("{:016x}", tick.span_id))
+        );
+        assert!(exported[0].get("status").is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    aggregates::create_percentiles,
+    config::{ConfigError, MigrationsMode, PoliciesConfig, PostgresConfig},
+    error::Error,
+    policies::apply_policies,
+    postgres::{
+        prepare_schema, store_entries, BulkWrite, ConnectionState, Database, SchemaError,
+    },
+    sink::Sink,
+    spool::Spool,
+    *,
+};
+
+
+/// Writes the entries to PostgreSQL (TimescaleDB). The connection is kept between writes,
+/// and re-established when lost. Entries that couldn't be written are spooled,
+/// and replayed before the next write
+#[derive(Debug)]
+pub struct PostgresSink {
+    database: Database,
+    spool: Option<Spool>,
+    bulk: BulkWrite,
+    migrations: MigrationsMode,
+    policies: Option<PoliciesConfig>,
+    percentiles: bool,
+    schema_ready: bool,
+}
+
+
+impl PostgresSink {
+    /// Create the sink using the database settings
+    pub fn new(config: &PostgresConfig) -> Result<Self, ConfigError> {
+        Ok(Self {
+            database: config.database()?,
+            spool: config.spool.open(),
+            bulk: config.bulk_write(),
+            migrations: config.migrations,
+            policies: config.policies.apply.then(|| config.policies.to_owned()),
+            percentiles: config.percentiles,
+            schema_ready: false,
+        })
+    }
+
+
+    /// Spool the entries that couldn't be stored, recording the error in the collector runs
+    fn spool(&self, entries: &[Entries], error: &str) {
+        let Some(spool) = &self.spool else {
+            warn!("Spool is disabled. Entries are lost.");
+            return;
+        };
+        let mut entries = entries.to_vec();
+        for an_entries in entries.iter_mut() {
+            an_entries.set_write_error(error);
+        }
+        match spool.push(&entries) {
+            Ok(()) => info!("Entries spooled: {error}"),
+            Err(error) => error!("Failed to spool the entries. They're lost: {error}"),
+        }
+    }
+}
+
+
+impl Sink for PostgresSink {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        if let Err(error) = self.prepare() {
+            self.spool(entries, &error.to_string());
+            return Err(error);
+        }
+        // The connection is kept between writes, and re-established when lost:
+        let Some(pg_conn) = self.database.connection() else {
+            let error = String::from("no TimescaleDB connection");
+            self.spool(entries, &error);
+            return Err(Error::Unavailable(error));
+        };
+
+        // spooled entries go first, to keep the order:
+        let stored = match &self.spool {
+            Some(spool) => spool.replay(pg_conn).map(|_| ()),
+            None => Ok(()),
+        }
+        .and_then(|()| store_entries(entries, pg_conn, self.bulk));
+        if let Err(error) = stored {
+            self.spool(entries, &error.to_string());
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+
+    fn prepare(&mut self) -> Result<(), Error> {
+        // schema is verified once, at startup or on the first connection:
+        if self.schema_ready {
+            return Ok(());
+        }
+        let Some(pg_conn) = self.database.connection() else {
+            return Err(Error::Unavailable(String::from(
+                "no TimescaleDB connection",
+            )));
+        };
+        let versions = match prepare_schema(pg_conn, self.migrations == MigrationsMode::Apply)
+        {
+            Ok(versions) => versions,
+            // reading the migrations fails on a flaky connection too, so it's retried:
+            Err(SchemaError::Migration(error)) => {
+                return Err(Error::Unavailable(format!("Migrations failed: {error}")));
+            }
+            Err(error) => return Err(error.into()),
+        };
+        if !versions.is_empty() {
+            info!("Applied migrations: {}", versions.join(", "));
+        }
+        // the percentiles are optional, so the entries are written anyway:
+        if self.percentiles {
+            match create_percentiles(pg_conn) {
+                Ok(created) => {
+                    for view in created {
+                        info!("Continuous aggregate created: {view}");
+                    }
+                }
+                Err(error) => error!("Percentile aggregates not created: {error}"),
+            }
+        }
+        // policies only manage the disk usage, so the entries are written anyway:
+        if let Some(policies) = &self.policies {
+            match apply_policies(pg_conn, policies) {
+                Ok(changes) => {
+                    for change in changes {
+                        info!("Policy changed: {change}");
+                    }
+                }
+                Err(error) => error!("TimescaleDB policies not applied: {error}"),
+            }
+        }
+        self.schema_ready = true;
+        Ok(())
+    }
+
+
+    fn flush(&mut self) -> Result<(), Error> {
+        // replaying to an unverified schema could break it:
+        let Some(spool) = self
+            .spool
+            .as_ref()
+            .filter(|spool| self.schema_ready && !spool.is_empty())
+        else {
+            return Ok(());
+        };
+        let Some(pg_conn) = self.database.connection() else {
+            return Err(Error::Unavailable(String::from(
+                "no TimescaleDB connection. Spooled entries left for the next start",
+            )));
+        };
+        spool.replay(pg_conn)?;
+        Ok(())
+    }
+
+
+    fn connection_state(&self) -> Option<ConnectionState> {
+        Some(self.database.state().clone())
+    }
+}
@@ -0,0 +1,725 @@
+use crate::{
+    config::PrometheusConfig,
+    error::Error,
+    point::{Point, Value},
+    sink::{AgentHealth, Sink},
+    *,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::{Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+use tiny_http::{Header, Method, Response, Server};
+
+
+/// Content type of the OpenMetrics text format
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Prefix of all metric names
+const PREFIX: &str = "dcollector_";
+
+
+/// Type of a metric family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// Value that goes up and down
+    Gauge,
+    /// Value that only grows
+    Counter,
+    /// Constant 1, with the information in the labels
+    Info,
+}
+
+
+impl Kind {
+    /// Name of the type, used in the TYPE line
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+            Kind::Info => "info",
+        }
+    }
+
+
+    /// Suffix of the sample names, required by OpenMetrics
+    fn suffix(self) -> &'static str {
+        match self {
+            Kind::Gauge => "",
+            Kind::Counter => "_total",
+            Kind::Info => "_info",
+        }
+    }
+}
+
+
+/// Sample of a metric, named and labeled the same way by all Prometheus sinks
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Sample {
+    /// Name of the metric family, like: "dcollector_load_one"
+    pub(crate) family: String,
+    /// Type of the metric
+    pub(crate) kind: Kind,
+    /// Labels, in the order of the tags
+    pub(crate) labels: Vec<(String, String)>,
+    /// Value of the sample
+    pub(crate) value: f64,
+}
+
+
+impl Sample {
+    /// Create the sample
+    fn new(family: String, kind: Kind, labels: &[(&str, &str)], value: f64) -> Self {
+        Self {
+            family,
+            kind,
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            value,
+        }
+    }
+
+
+    /// Name of the sample, the family name with the suffix of its type
+    pub(crate) fn name(&self) -> String {
+        format!("{}{}", self.family, self.kind.suffix())
+    }
+}
+
+
+/// Samples of the fields of the point, labeled with its tags.
+/// Counters are sampled without the per-collection differences,
+/// as Prometheus computes the rates on its own.
+/// Collector runs are sampled by [AgentMetrics] instead
+pub(crate) fn point_samples(point: &Point) -> Vec<Sample> {
+    let subsystem = match point.measurement {
+        "sys_stats" => "",
+        "ups_stats" => "ups_",
+        "disk_stats" => "disk_",
+        "proc_stats" => "proc_",
+        "net_stats" => "net_",
+        _ => return vec![],
+    };
+    let labels = point
+        .tags
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect::<Vec<_>>();
+    let counters = point
+        .fields
+        .iter()
+        .filter(|field| field.monotonic)
+        .map(|field| counter_name(field.name))
+        .collect::<Vec<_>>();
+
+    let family = |name: &str| format!("{PREFIX}{subsystem}{name}");
+    let mut samples = vec![];
+    for field in &point.fields {
+        match &field.value {
+            _ if field.monotonic => {
+                if let Some(value) = field.value.as_f64() {
+                    samples.push(Sample::new(
+                        family(&counter_name(field.name)),
+                        Kind::Counter,
+                        &labels,
+                        value,
+                    ));
+                }
+            }
+            _ if counters.iter().any(|counter| counter == field.name) => {}
+            // text values, like the UPS status, become a label of the constant sample:
+            Value::Text(text) => {
+                let mut labels = labels.clone();
+                labels.push((field.name, text));
+                samples.push(Sample::new(family(field.name), Kind::Gauge, &labels, 1.0));
+            }
+            value => {
+                if let Some(value) = value.as_f64() {
+                    samples.push(Sample::new(family(field.name), Kind::Gauge, &labels, value));
+                }
+            }
+        }
+    }
+    samples
+}
+
+
+/// Name of the counter family, without the "total" parts of the field name,
+/// like: "received" for "total_received", or "received_errors" for "received_total_errors"
+fn counter_name(field: &str) -> String {
+    let name = field.replace("total_", "");
+    name.strip_suffix("_total").unwrap_or(&name).to_string()
+}
+
+
+/// Latest run of a collector, with the counts of all runs
+#[derive(Debug, Clone, Default)]
+struct CollectorStats {
+    runs: u64,
+    errors: u64,
+    duration_ms: f64,
+    rows: i64,
+    last_run: f64,
+}
+
+
+/// Metrics of the agent itself: its version, start time, the collector runs,
+/// and the health of the agent
+#[derive(Debug)]
+pub(crate) struct AgentMetrics {
+    started: SystemTime,
+    snapshots: u64,
+    collectors: BTreeMap<(String, String), CollectorStats>,
+    health: AgentHealth,
+}
+
+
+impl AgentMetrics {
+    /// Metrics of the agent started now
+    pub(crate) fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+            snapshots: 0,
+            collectors: BTreeMap::new(),
+            health: AgentHealth::default(),
+        }
+    }
+
+
+    /// Keep the health of the agent for the samples
+    pub(crate) fn observe(&mut self, health: &AgentHealth) {
+        self.health = health.clone();
+    }
+
+
+    /// Record the snapshot, with the collector runs it holds
+    pub(crate) fn record(&mut self, entries: &[Entries]) {
+        self.snapshots += 1;
+        let runs = entries.iter().filter_map(|an_entries| {
+            match an_entries {
+                Entries::Runs(runs) => Some(runs),
+                _ => None,
+            }
+        });
+        for run in runs.flatten() {
+            let stats = self
+                .collectors
+                .entry((run.host_name.to_owned(), run.collector.to_owned()))
+                .or_default();
+            stats.runs += 1;
+            stats.errors += run.error.is_some() as u64;
+            stats.duration_ms = run.duration_ms;
+            stats.rows = run.rows_produced as i64;
+            stats.last_run = epoch_seconds(run.time);
+        }
+    }
+
+
+    /// Samples of the agent metrics
+    pub(crate) fn samples(&self) -> Vec<Sample> {
+        let mut samples = vec![
+            Sample::new(
+                format!("{PREFIX}build"),
+                Kind::Info,
+                &[("version", env!("CARGO_PKG_VERSION"))],
+                1.0,
+            ),
+            Sample::new(
+                format!("{PREFIX}start_time_seconds"),
+                Kind::Gauge,
+                &[],
+                epoch_seconds(self.started),
+            ),
+            Sample::new(
+                format!("{PREFIX}snapshots"),
+                Kind::Counter,
+                &[],
+                self.snapshots as f64,
+            ),
+        ];
+        for ((host_name, collector), stats) in &self.collectors {
+            let labels = [
+                ("host", host_name.as_str()),
+                ("collector", collector.as_str()),
+            ];
+            let sample = |name: &str, kind, value| {
+                Sample::new(format!("{PREFIX}collector_{name}"), kind, &labels, value)
+            };
+            samples.extend([
+                sample("runs", Kind::Counter, stats.runs as f64),
+                sample("errors", Kind::Counter, stats.errors as f64),
+                sample("duration_seconds", Kind::Gauge, stats.duration_ms / 1000.0),
+                sample("rows", Kind::Gauge, stats.rows as f64),
+                sample("last_run_timestamp_seconds", Kind::Gauge, stats.last_run),
+            ]);
+        }
+        for (collector, missed_ticks) in &self.health.missed_ticks {
+            samples.push(Sample::new(
+                format!("{PREFIX}collector_missed_ticks"),
+                Kind::Counter,
+                &[("collector", collector)],
+                *missed_ticks as f64,
+            ));
+        }
+        for (sink, failures) in &self.health.sink_failures {
+            samples.push(Sample::new(
+                format!("{PREFIX}sink_failures"),
+                Kind::Counter,
+                &[("sink", sink)],
+                *failures as f64,
+            ));
+        }
+        for (sink, state) in &self.health.connections {
+            let labels = [("sink", *sink)];
+            let sample = |name: &str, kind, value| {
+                Sample::new(format!("{PREFIX}database_{name}"), kind, &labels, value)
+            };
+            samples.extend([
+                sample("connected", Kind::Gauge, state.connected as u8 as f64),
+                sample("reconnects", Kind::Counter, state.reconnects as f64),
+                sample("failures", Kind::Gauge, state.failures as f64),
+            ]);
+        }
+        samples
+    }
+}
+
+
+/// Metric families of an exposition. Samples of a family have to be grouped together
+#[derive(Debug, Default)]
+struct Families {
+    families: BTreeMap<String, (Kind, Vec<String>)>,
+}
+
+
+impl Families {
+    /// Add the sample to its family
+    fn add(&mut self, sample: Sample) {
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>();
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        };
+        let line = format!("{}{labels} {}", sample.name(), format_float(sample.value));
+        self.families
+            .entry(sample.family)
+            .or_insert_with(|| (sample.kind, vec![]))
+            .1
+            .push(line);
+    }
+
+
+    /// Render the families in the OpenMetrics text format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for (family, (kind, samples)) in &self.families {
+            text.push_str(&format!("# TYPE {family} {}\n", kind.name()));
+            for sample in samples {
+                text.push_str(sample);
+                text.push('\n');
+            }
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+
+/// HTTP listener serving the latest exposition, in its own thread
+struct Listener {
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Listener")
+            .field("address", &self.server.server_addr())
+            .finish()
+    }
+}
+
+
+impl Listener {
+    /// Start listening on the address
+    fn start(listen: &str, path: &str, exposition: Arc<Mutex<String>>) -> Result<Self, Error> {
+        let server = Server::http(listen).map_err(|error| {
+            io::Error::other(format!("Can't listen on: {listen}: {error}"))
+        })?;
+        let server = Arc::new(server);
+        info!("Metrics exposed on: http://{}{path}", server.server_addr());
+        let thread = thread::Builder::new()
+            .name(String::from("prometheus"))
+            .spawn({
+                let server = server.clone();
+                let path = path.to_string();
+                move || serve(&server, &path, &exposition)
+            })?;
+        Ok(Self {
+            server,
+            thread: Some(thread),
+        })
+    }
+}
+
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // the address has to be free for the sink of a reloaded configuration:
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// Answer the requests with the latest exposition, until the server is unblocked
+fn serve(server: &Server, path: &str, exposition: &Mutex<String>) {
+    for request in server.incoming_requests() {
+        let response = if request.url().split('?').next() != Some(path) {
+            Response::from_string("Not Found").with_status_code(404)
+        } else if ![Method::Get, Method::Head].contains(request.method()) {
+            Response::from_string("Method Not Allowed").with_status_code(405)
+        } else {
+            let text = exposition
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            let response = Response::from_string(text);
+            match Header::from_bytes("Content-Type", CONTENT_TYPE) {
+                Ok(header) => response.with_header(header),
+                Err(()) => response,
+            }
+        };
+        if let Err(error) = request.respond(response) {
+            debug!("Scrape response failed: {error}");
+        }
+    }
+}
+
+
+/// Exposes the latest entries of each collector to Prometheus scrapes,
+/// in the OpenMetrics text format, with the metrics of the agent itself.
+/// The exposition is updated on each write. The listener starts on the first write
+#[derive(Debug)]
+pub struct PrometheusSink {
+    listen: String,
+    path: String,
+    agent: AgentMetrics,
+    latest: BTreeMap<&'static str, Vec<Point>>,
+    exposition: Arc<Mutex<String>>,
+    listener: Option<Listener>,
+}
+
+
+impl PrometheusSink {
+    /// Create the sink using the scrape endpoint settings
+    pub fn new(config: &PrometheusConfig) -> Self {
+        Self {
+            listen: config.listen.to_owned(),
+            path: config.path.to_owned(),
+            agent: AgentMetrics::new(),
+            latest: BTreeMap::new(),
+            exposition: Arc::new(Mutex::new(String::new())),
+            listener: None,
+        }
+    }
+
+
+    /// Render the latest entries and the agent metrics
+    fn render(&self) -> String {
+        let mut families = Families::default();
+        for sample in self.agent.samples() {
+            families.add(sample);
+        }
+        for point in self.latest.values().flatten() {
+            for sample in point_samples(point) {
+                families.add(sample);
+            }
+        }
+        families.render()
+    }
+}
+
+
+impl Sink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        self.agent.record(entries);
+        // tables of the collectors that ran without producing entries are dropped,
+        // so the last values of a failed collector don't look current:
+        let tables = entries.iter().map(Entries::table).collect::<Vec<_>>();
+        let runs = entries.iter().filter_map(|an_entries| {
+            match an_entries {
+                Entries::Runs(runs) => Some(runs),
+                _ => None,
+            }
+        });
+        for run in runs.flatten() {
+            self.latest.retain(|table, _| {
+                tables.contains(table) || table.trim_end_matches("_stats") != run.collector
+            });
+        }
+        // the latest entries replace the previous ones, so gone processes disappear:
+        for an_entries in entries {
+            if !matches!(an_entries, Entries::Runs(_) | Entries::Alerts(_)) {
+                self.latest
+                    .insert(an_entries.table(), an_entries.to_points());
+            }
+        }
+        let text = self.render();
+        *self
+            .exposition
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = text;
+
+        if self.listener.is_none() {
+            self.listener = Some(Listener::start(
+                &self.listen,
+                &self.path,
+                self.exposition.clone(),
+            )?);
+        }
+        Ok(())
+    }
+
+
+    fn observe(&mut self, health: &AgentHealth) {
+        self.agent.observe(health);
+    }
+}
+
+
+/// Escape the label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+
+/// Format the float the way OpenMetrics expects
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+
+/// Seconds since UNIX_EPOCH, with the fraction
+fn epoch_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CollectorRun, DiskStat, ProcStat, SysStat},
+        point::ToPoint,
+    };
+    use std::time::Duration;
+
+
+    /// Sink listening on a free port
+    fn sink() -> PrometheusSink {
+        PrometheusSink::new(&PrometheusConfig {
+            enabled: true,
+            listen: String::from("127.0.0.1:0"),
+            ..Default::default()
+        })
+    }
+
+
+    /// Entry of the process
+    fn process(pid: i32, name: &str) -> ProcStat {
+        ProcStat {
+            host_name: String::from("nas"),
+            pid,
+            name: Some(name.to_string()),
+            rss: Some(1_024),
+            disk_read_total: Some(42),
+            ..Default::default()
+        }
+    }
+
+
+    /// Run of the collector, failed with the error if any
+    fn run(collector: &str, error: Option<&str>) -> CollectorRun {
+        CollectorRun {
+            time: UNIX_EPOCH,
+            host_name: String::from("nas"),
+            collector: collector.to_string(),
+            duration_ms: 1.5,
+            rows_produced: error.is_none() as i32,
+            rows_written: None,
+            error: error.map(ToString::to_string),
+        }
+    }
+
+
+    /// Sample lines of the exposition, without the agent metrics
+    fn lines(sink: &PrometheusSink) -> Vec<String> {
+        let agent = sink.agent.samples();
+        sink.exposition
+            .lock()
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter(|line| !agent.iter().any(|sample| line.starts_with(&sample.name())))
+            .map(ToString::to_string)
+            .collect()
+    }
+
+
+    #[test]
+    fn names_the_families_and_the_counters() {
+        let point = process(7, "postgres").to_point();
+        let samples = point_samples(&point);
+        let names = samples.iter().map(Sample::name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["dcollector_proc_rss", "dcollector_proc_disk_read_total"]
+        );
+        assert_eq!(samples[1].kind, Kind::Counter);
+        assert_eq!(
+            samples[0].labels,
+            [
+                (String::from("host"), String::from("nas")),
+                (String::from("name"), String::from("postgres")),
+                (String::from("pid"), String::from("7")),
+            ]
+        );
+        assert_eq!(counter_name("total_received"), "received");
+        assert_eq!(counter_name("received_total_errors"), "received_errors");
+        assert_eq!(counter_name("disk_read_total"), "disk_read");
+    }
+
+
+    #[test]
+    fn renders_the_open_metrics_text() {
+        let mut families = Families::default();
+        let disk = DiskStat {
+            host_name: String::from("nas"),
+            name: String::from("disk \"a\"\\b\nc"),
+            temperature: Some(41.5),
+            crc_errors: Some(3),
+            ..Default::default()
+        };
+        for sample in point_samples(&disk.to_point()) {
+            families.add(sample);
+        }
+        families.add(Sample::new(
+            format!("{PREFIX}build"),
+            Kind::Info,
+            &[("version", "1.0")],
+            1.0,
+        ));
+        assert_eq!(
+            families.render(),
+            "# TYPE dcollector_build info\n\
+             dcollector_build_info{version=\"1.0\"} 1\n\
+             # TYPE dcollector_disk_crc_errors counter\n\
+             dcollector_disk_crc_errors_total{host=\"nas\",device=\"disk \\\"a\\\"\\\\b\\nc\"} 3\n\
+             # TYPE dcollector_disk_temperature gauge\n\
+             dcollector_disk_temperature{host=\"nas\",device=\"disk \\\"a\\\"\\\\b\\nc\"} 41.5\n\
+             # EOF\n"
+        );
+        assert_eq!(format_float(f64::NAN), "NaN");
+        assert_eq!(format_float(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_float(0.25), "0.25");
+    }
+
+
+    #[test]
+    fn drops_the_gone_processes() {
+        let mut sink = sink();
+        sink.write(&[Entries::Proc(vec![process(1, "a"), process(2, "b")])])
+            .unwrap();
+        assert_eq!(lines(&sink).len(), 4);
+        sink.write(&[Entries::Proc(vec![process(2, "b")])]).unwrap();
+        let lines = lines(&sink);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.contains("pid=\"2\"")));
+    }
+
+
+    #[test]
+    fn drops_the_tables_of_the_failed_collectors() {
+        let mut sink = sink();
+        let sys = SysStat {
+            host_name: String::from("nas"),
+            load_one: Some(0.5),
+            ..Default::default()
+        };
+        sink.write(&[
+            Entries::Sys(vec![sys.clone()]),
+            Entries::Proc(vec![process(1, "a")]),
+            Entries::Runs(vec![run("sys", None), run("proc", None)]),
+        ])
+        .unwrap();
+        assert_eq!(lines(&sink).len(), 3);
+
+        // the other collectors keep their latest entries until they run again:
+        sink.write(&[Entries::Runs(vec![run("proc", Some("failed"))])])
+            .unwrap();
+        assert_eq!(lines(&sink), ["dcollector_load_one{host=\"nas\"} 0.5"]);
+        sink.write(&[Entries::Sys(vec![]), Entries::Runs(vec![run("sys", None)])])
+            .unwrap();
+        assert!(lines(&sink).is_empty());
+        let exposition = sink.exposition.lock().unwrap().clone();
+        assert!(exposition.contains(
+            "dcollector_collector_errors_total{host=\"nas\",collector=\"proc\"} 1\n"
+        ));
+        assert!(exposition.ends_with("# EOF\n"));
+    }
+
+
+    #[test]
+    fn serves_the_scrapes() {
+        let mut sink = sink();
+        sink.write(&[Entries::Proc(vec![process(1, "a")])]).unwrap();
+        let address = sink
+            .listener
+            .as_ref()
+            .and_then(|listener| listener.server.server_addr().to_ip())
+            .unwrap();
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .build();
+        let response = agent
+            .get(&format!("http://{address}/metrics"))
+            .call()
+            .unwrap();
+        assert_eq!(response.header("Content-Type"), Some(CONTENT_TYPE));
+        let text = response.into_string().unwrap();
+        assert!(text.contains("dcollector_proc_rss{host=\"nas\",name=\"a\",pid=\"1\"} 1024\n"));
+        assert!(text.ends_with("# EOF\n"));
+        let missing = agent.get(&format!("http://{address}/other")).call();
+        assert!(matches!(missing, Err(ureq::Error::Status(404, _))));
+    }
+}
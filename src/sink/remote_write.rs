@@ -0,0 +1,516 @@
+use crate::{
+    config::RemoteWriteConfig,
+    error::Error,
+    sink::{
+        prometheus::{point_samples, AgentMetrics, Sample},
+        wait_or, AgentHealth, Sink,
+    },
+    *,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prost::Message;
+use std::{
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+
+/// Messages of the remote_write protocol, as defined by prometheus/prompb
+mod prompb {
+    /// Request holding the series to write
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct WriteRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub(super) timeseries: Vec<TimeSeries>,
+    }
+
+
+    /// Samples of a single series, identified by its labels
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct TimeSeries {
+        #[prost(message, repeated, tag = "1")]
+        pub(super) labels: Vec<Label>,
+        #[prost(message, repeated, tag = "2")]
+        pub(super) samples: Vec<Sample>,
+    }
+
+
+    /// Label of a series. The metric name is the "__name__" label
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct Label {
+        #[prost(string, tag = "1")]
+        pub(super) name: String,
+        #[prost(string, tag = "2")]
+        pub(super) value: String,
+    }
+
+
+    /// Value of a series at the time (in milliseconds)
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct Sample {
+        #[prost(double, tag = "1")]
+        pub(super) value: f64,
+        #[prost(int64, tag = "2")]
+        pub(super) timestamp: i64,
+    }
+}
+
+
+/// Amount of requests waiting for the sender thread. Newer requests are dropped when it's full
+const QUEUE_CAPACITY: usize = 16;
+
+
+/// Pushes the entries to a Prometheus remote_write endpoint, as snappy compressed protobuf.
+/// Metrics are named and labeled the same way as by the scrape endpoint.
+/// Requests are sent by its own thread, so the retries don't delay the collection.
+/// Failed requests are retried with a growing delay, unless rejected by the endpoint.
+/// The failures are reported by the next write. When stopping, the queued requests
+/// are sent within a single request timeout in total
+#[derive(Debug)]
+pub struct RemoteWriteSink {
+    client: Arc<Client>,
+    metrics: AgentMetrics,
+    sender: Option<Sender>,
+}
+
+
+impl RemoteWriteSink {
+    /// Create the sink using the remote_write settings. The thread starts on the first write
+    pub fn new(config: &RemoteWriteConfig) -> Self {
+        let authorization = match (&config.bearer_token, &config.username) {
+            (Some(token), _) => Some(format!("Bearer {token}")),
+            (None, Some(username)) => {
+                let password = config.password.as_deref().unwrap_or_default();
+                Some(format!(
+                    "Basic {}",
+                    BASE64.encode(format!("{username}:{password}"))
+                ))
+            }
+            (None, None) => None,
+        };
+        let timeout = Duration::from_secs(config.timeout);
+        let client = Client {
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            url: config.url.to_owned(),
+            timeout,
+            authorization,
+            retries: config.retries,
+            retry_delay: Duration::from_secs(config.retry_delay),
+        };
+        Self {
+            client: Arc::new(client),
+            metrics: AgentMetrics::new(),
+            sender: None,
+        }
+    }
+
+
+    /// Request of the series of the entries, and of the agent metrics
+    fn request(&mut self, entries: &[Entries]) -> prompb::WriteRequest {
+        self.metrics.record(entries);
+        let mut timeseries = vec![];
+        for point in entries.iter().flat_map(Entries::to_points) {
+            for sample in point_samples(&point) {
+                timeseries.push(series(sample, point.time));
+            }
+        }
+        let now = SystemTime::now();
+        for sample in self.metrics.samples() {
+            timeseries.push(series(sample, now));
+        }
+        prompb::WriteRequest {
+            timeseries,
+        }
+    }
+}
+
+
+impl Sink for RemoteWriteSink {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let request = self.request(entries);
+        let body = encode(&request)?;
+
+        let sender = match self.sender.take() {
+            Some(sender) => sender,
+            None => Sender::start(self.client.clone())?,
+        };
+        let queued = sender.send(body, request.timeseries.len());
+        // failures of the previous requests:
+        let failure = sender.failure();
+        self.sender = Some(sender);
+        queued?;
+        match failure {
+            Some(reason) => Err(Error::Unavailable(reason)),
+            None => Ok(()),
+        }
+    }
+
+
+    fn observe(&mut self, health: &AgentHealth) {
+        self.metrics.observe(health);
+    }
+}
+
+
+/// Client of the remote_write endpoint
+#[derive(Debug)]
+struct Client {
+    agent: ureq::Agent,
+    url: String,
+    timeout: Duration,
+    authorization: Option<String>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+
+impl Client {
+    /// Request with the headers of the protocol
+    fn request(&self) -> ureq::Request {
+        let request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Encoding", "snappy")
+            .set("Content-Type", "application/x-protobuf")
+            .set(
+                "User-Agent",
+                concat!("dcollector/", env!("CARGO_PKG_VERSION")),
+            )
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0");
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+
+    /// Send the body, retrying the failed requests. Stopping cancels the retries,
+    /// and its deadline limits the timeout of the request
+    fn send(&self, body: &[u8], stopping: &OnceLock<Instant>) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.request();
+            if let Some(deadline) = stopping.get() {
+                request = request.timeout(deadline.saturating_duration_since(Instant::now()));
+            }
+            let error = match request.send_bytes(body) {
+                Ok(_) => return Ok(()),
+                Err(error) => error,
+            };
+            // rejected requests would be rejected again:
+            let retryable = match &error {
+                ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+                ureq::Error::Transport(_) => true,
+            };
+            if !retryable || attempt >= self.retries || stopping.get().is_some() {
+                return Err(error.into());
+            }
+            let delay = self.retry_delay(attempt);
+            attempt += 1;
+            warn!(
+                "Remote write failed, retry #{attempt} in {}s: {}",
+                delay.as_secs(),
+                Error::from(error)
+            );
+            wait_or(delay, || stopping.get().is_some());
+        }
+    }
+
+
+    /// Delay before the retry after the attempt, doubled on each next one
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+
+/// Sends the queued request bodies (with the amounts of their series) in its own thread.
+/// The last failure is kept until it's reported. Stopping sets the deadline of the draining
+#[derive(Debug)]
+struct Sender {
+    sender: Option<SyncSender<(Vec<u8>, usize)>>,
+    failure: Arc<Mutex<Option<String>>>,
+    stopping: Arc<OnceLock<Instant>>,
+    drain_timeout: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl Sender {
+    /// Start the thread sending the requests
+    fn start(client: Arc<Client>) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::sync_channel::<(Vec<u8>, usize)>(QUEUE_CAPACITY);
+        let failure = Arc::new(Mutex::new(None));
+        let stopping = Arc::new(OnceLock::new());
+        let drain_timeout = client.timeout;
+        let thread = thread::Builder::new()
+            .name(String::from("remote_write"))
+            .spawn({
+                let failure = failure.clone();
+                let stopping = stopping.clone();
+                move || {
+                    for (body, series) in receiver {
+                        if stopping
+                            .get()
+                            .is_some_and(|deadline| Instant::now() >= *deadline)
+                        {
+                            error!("Remote write timed out, queued requests are dropped");
+                            break;
+                        }
+                        match client.send(&body, &stopping) {
+                            Ok(()) => trace!("Series sent: {series}"),
+                            // not reported anymore, and the rest would likely fail too:
+                            Err(error) if stopping.get().is_some() => {
+                                error!("Remote write failed, queued requests are dropped: {error}");
+                                break;
+                            }
+                            Err(error) => {
+                                *failure.lock().unwrap_or_else(PoisonError::into_inner) =
+                                    Some(format!("Remote write failed: {error}"));
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            failure,
+            stopping,
+            drain_timeout,
+            thread: Some(thread),
+        })
+    }
+
+
+    /// Queue the request body. Fails if the queue is full, or the thread stopped
+    fn send(&self, body: Vec<u8>, series: usize) -> Result<(), Error> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        match sender.try_send((body, series)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                Err(Error::Unavailable(format!(
+                    "Remote write queue is full, series dropped: {series}"
+                )))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err(Error::Unavailable(format!(
+                    "Remote write stopped, series dropped: {series}"
+                )))
+            }
+        }
+    }
+
+
+    /// Take the last failure of the sent requests
+    fn failure(&self) -> Option<String> {
+        self.failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        // queued requests are sent before the thread stops, without the retries, until the deadline:
+        let _ = self.stopping.set(Instant::now() + self.drain_timeout);
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            // the request sent before stopping may still take its whole timeout:
+            wait_or(self.drain_timeout, || thread.is_finished());
+            if thread.is_finished() {
+                let _ = thread.join();
+            } else {
+                warn!("Remote write still sending, the request is abandoned");
+            }
+        }
+    }
+}
+
+
+/// Protobuf encoding of the request, snappy compressed (the block format, not framed)
+fn encode(request: &prompb::WriteRequest) -> Result<Vec<u8>, Error> {
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .map_err(std::io::Error::from)?;
+    Ok(body)
+}
+
+
+/// Series of the single sample. Labels are sorted by name, as the protocol requires
+fn series(sample: Sample, time: SystemTime) -> prompb::TimeSeries {
+    let mut labels = vec![prompb::Label {
+        name: String::from("__name__"),
+        value: sample.name(),
+    }];
+    labels.extend(sample.labels.into_iter().map(|(name, value)| {
+        prompb::Label {
+            name,
+            value,
+        }
+    }));
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    let timestamp = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    prompb::TimeSeries {
+        labels,
+        samples: vec![prompb::Sample {
+            value: sample.value,
+            timestamp,
+        }],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DiskStat;
+    use std::net::TcpListener;
+    use tiny_http::{Response, Server};
+
+
+    /// Client of the endpoint, retrying at once
+    fn client(url: String, timeout: Duration) -> Client {
+        Client {
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            url,
+            timeout,
+            authorization: Some(String::from("Bearer secret")),
+            retries: 2,
+            retry_delay: Duration::ZERO,
+        }
+    }
+
+
+    #[test]
+    fn encodes_the_sorted_series() {
+        let mut sink = RemoteWriteSink::new(&RemoteWriteConfig::default());
+        let disk = DiskStat {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+            host_name: String::from("nas"),
+            name: String::from("sda"),
+            temperature: Some(41.5),
+            ..Default::default()
+        };
+        let request = sink.request(&[Entries::Disk(vec![disk])]);
+        let body = encode(&request).unwrap();
+        let decoded = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(
+            prompb::WriteRequest::decode(decoded.as_slice()).unwrap(),
+            request
+        );
+
+        let series = &request.timeseries[0];
+        let label = |name: &str, value: &str| {
+            prompb::Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        };
+        assert_eq!(
+            series.labels,
+            [
+                label("__name__", "dcollector_disk_temperature"),
+                label("device", "sda"),
+                label("host", "nas"),
+            ]
+        );
+        assert_eq!(
+            series.samples,
+            [prompb::Sample {
+                value: 41.5,
+                timestamp: 1_700_000_000_500,
+            }]
+        );
+        // the agent metrics follow, with their labels sorted too:
+        assert!(request.timeseries[1..].iter().all(|series| {
+            series.labels[0].name == "__name__"
+                && series
+                    .labels
+                    .windows(2)
+                    .all(|pair| pair[0].name < pair[1].name)
+        }));
+    }
+
+
+    #[test]
+    fn retries_the_failed_requests() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let client = client(
+            format!("http://{}/api/v1/write", server.server_addr()),
+            Duration::from_secs(5),
+        );
+        // stand-in of the endpoint, answering the requests with the status:
+        let stand_in = thread::spawn(move || {
+            [500, 429, 204, 400, 503, 503, 503].map(|status| {
+                let request = server.recv().unwrap();
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                };
+                let headers = (header("Content-Encoding"), header("Authorization"));
+                request.respond(Response::empty(status)).unwrap();
+                headers
+            })
+        });
+        let stopping = OnceLock::new();
+        client.send(b"first", &stopping).unwrap();
+        // rejected, so not retried:
+        assert!(client.send(b"second", &stopping).is_err());
+        // retries are exhausted:
+        assert!(client.send(b"third", &stopping).is_err());
+        for headers in stand_in.join().unwrap() {
+            assert_eq!(
+                headers,
+                (
+                    Some(String::from("snappy")),
+                    Some(String::from("Bearer secret"))
+                )
+            );
+        }
+
+        let client = Client {
+            retry_delay: Duration::from_secs(1),
+            ..client
+        };
+        let delays = (0..4).map(|attempt| client.retry_delay(attempt).as_secs());
+        assert_eq!(delays.collect::<Vec<_>>(), [1, 2, 4, 8]);
+    }
+
+
+    #[test]
+    fn bounds_the_draining() {
+        // endpoint accepting the connections, but never answering:
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(
+            format!("http://{}/", listener.local_addr().unwrap()),
+            Duration::from_secs(1),
+        );
+        let sender = Sender::start(Arc::new(client)).unwrap();
+        for _ in 0..3 {
+            sender.send(b"series".to_vec(), 1).unwrap();
+        }
+        let stopped = Instant::now();
+        drop(sender);
+        assert!(stopped.elapsed() < Duration::from_millis(1_500));
+    }
+}
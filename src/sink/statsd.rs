@@ -0,0 +1,166 @@
+use crate::{
+    config::StatsdConfig,
+    error::Error,
+    sink::{datagrams, graphite::PathTemplates, Sink},
+    *,
+};
+use std::net::UdpSocket;
+
+
+/// Sends the entries to StatsD, as gauges over UDP.
+/// Metric paths are made the same way as for Graphite
+#[derive(Debug)]
+pub struct StatsdSink {
+    address: String,
+    payload: usize,
+    templates: PathTemplates,
+    socket: Option<UdpSocket>,
+}
+
+
+impl StatsdSink {
+    /// Create the sink using the StatsD settings
+    pub fn new(config: &StatsdConfig) -> Self {
+        Self {
+            address: config.address.to_owned(),
+            payload: config.udp_payload,
+            templates: PathTemplates::new(&config.template, &config.templates),
+            socket: None,
+        }
+    }
+
+
+    /// Gauge lines of the entries. Lines of a negative gauge are kept together
+    fn lines(&self, entries: &[Entries]) -> Vec<String> {
+        let mut lines = vec![];
+        for point in entries.iter().flat_map(Entries::to_points) {
+            for (path, value) in self.templates.metrics(&point) {
+                // signed gauge values are changes of the gauge, so it's zeroed first.
+                // In the same datagram, so the reset can't be lost or reordered alone:
+                if value.starts_with('-') {
+                    lines.push(format!("{path}:0|g\n{path}:{value}|g"));
+                } else {
+                    lines.push(format!("{path}:{value}|g"));
+                }
+            }
+        }
+        lines
+    }
+}
+
+
+impl Sink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+
+    fn write(&mut self, entries: &[Entries]) -> Result<(), Error> {
+        let lines = self.lines(entries);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(self.address.as_str())?;
+                socket
+            }
+        };
+        // on failure the socket is dropped, and the address resolved again:
+        for datagram in datagrams(&lines, self.payload) {
+            socket.send(datagram.as_bytes())?;
+        }
+        self.socket = Some(socket);
+        trace!("Gauges sent: {}", lines.len());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+
+    /// Sink sending to the address, in datagrams of the payload
+    fn sink(address: &str, payload: usize) -> StatsdSink {
+        StatsdSink::new(&StatsdConfig {
+            enabled: true,
+            address: address.to_string(),
+            udp_payload: payload,
+            ..Default::default()
+        })
+    }
+
+
+    /// Disk entry of the device
+    fn disk(device: &str, temperature: f64) -> DiskStat {
+        DiskStat {
+            host_name: String::from("nas"),
+            name: device.to_string(),
+            temperature: Some(temperature),
+            ..Default::default()
+        }
+    }
+
+
+    #[test]
+    fn renders_the_gauges() {
+        let sink = sink("localhost:8125", 1_432);
+        let sys = SysStat {
+            host_name: String::from("nas"),
+            load_one: Some(0.5),
+            used_memory: Some(1_024),
+            ..Default::default()
+        };
+        assert_eq!(
+            sink.lines(&[
+                Entries::Sys(vec![sys]),
+                Entries::Disk(vec![disk("sda", -3.5)]),
+            ]),
+            [
+                "dcollector.nas.sys.used_memory:1024|g",
+                "dcollector.nas.sys.load_one:0.5|g",
+                "dcollector.nas.disk.sda.temperature:0|g\n\
+                 dcollector.nas.disk.sda.temperature:-3.5|g",
+            ]
+        );
+    }
+
+
+    #[test]
+    fn sends_the_reset_with_the_negative_gauge() {
+        let listener = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // room for a single line:
+        let mut sink = sink(&listener.local_addr().unwrap().to_string(), 48);
+        sink.write(&[Entries::Disk(vec![
+            disk("sda", 30.0),
+            disk("sdb", -3.5),
+            disk("sdc", 31.0),
+        ])])
+        .unwrap();
+
+        let mut datagrams = vec![];
+        for _ in 0..3 {
+            let mut datagram = [0; 1_500];
+            let length = listener.recv(&mut datagram).unwrap();
+            datagrams.push(String::from_utf8_lossy(&datagram[..length]).to_string());
+        }
+        assert_eq!(
+            datagrams,
+            [
+                "dcollector.nas.disk.sda.temperature:30|g",
+                "dcollector.nas.disk.sdb.temperature:0|g\n\
+                 dcollector.nas.disk.sdb.temperature:-3.5|g",
+                "dcollector.nas.disk.sdc.temperature:31|g",
+            ]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use crate::{postgres::replay_entries, *};
+use diesel::{pg::PgConnection, result::Error, RunQueryDsl};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+
+/// Sequence number, distinguishing batches spooled within the same nanosecond
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+
+/// Extension of the spooled batch files
+const BATCH_EXTENSION: &str = "jsonl";
+
+
+/// Extension of the batch files rejected by the database, kept for inspection
+const REJECTED_EXTENSION: &str = "rejected";
+
+
+/// True if the connection still works, so the failure was caused by the entries
+fn connection_alive(pg_connection: &mut PgConnection) -> bool {
+    diesel::sql_query("SELECT 1").execute(pg_connection).is_ok()
+}
+
+
+/// Disk-backed spool of the entries that couldn't be written to the database.
+/// Each batch is a file with one JSON line per collector entries,
+/// named after the spool time, so the batches are replayed in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+}
+
+
+impl Spool {
+    /// Open the spool in given directory, creating it if necessary
+    pub fn open(dir: &Path, max_bytes: u64, max_age: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_age,
+        })
+    }
+
+
+    /// Spooled batch files, the oldest first
+    pub fn batches(&self) -> io::Result<Vec<PathBuf>> {
+        let mut batches = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == BATCH_EXTENSION))
+            .collect::<Vec<_>>();
+        batches.sort();
+        Ok(batches)
+    }
+
+
+    /// True if there is nothing spooled
+    pub fn is_empty(&self) -> bool {
+        self.batches()
+            .map(|batches| batches.is_empty())
+            .unwrap_or(true)
+    }
+
+
+    /// Write the entries as a new batch. Written to a temporary file first,
+    /// so a crash never leaves a partial batch behind
+    #[instrument(skip(self, entries))]
+    pub fn push(&self, entries: &[Entries]) -> io::Result<()> {
+        let entries = entries
+            .iter()
+            .filter(|entries| !entries.is_empty())
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1_000_000;
+        let name = format!("{since_epoch:020}-{sequence:06}");
+        let temporary = self.dir.join(format!("{name}.tmp"));
+        let batch = self.dir.join(format!("{name}.{BATCH_EXTENSION}"));
+
+        let mut file = File::create(&temporary)?;
+        for an_entries in &entries {
+            serde_json::to_writer(&mut file, an_entries)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, &batch)?;
+
+        debug!(
+            "Spooled {} entries to: {}",
+            entries.iter().map(|entries| entries.len()).sum::<usize>(),
+            batch.display()
+        );
+        self.prune()
+    }
+
+
+    /// Read the entries of the batch
+    pub fn read(batch: &Path) -> io::Result<Vec<Entries>> {
+        BufReader::new(File::open(batch)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+
+    /// Remove batches older than the max age, then the oldest ones above the max size
+    #[instrument(skip(self))]
+    pub fn prune(&self) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut batches = vec![];
+        for batch in self.batches()? {
+            let metadata = fs::metadata(&batch)?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if age > self.max_age {
+                warn!(
+                    "Dropping spooled batch older than max age: {}",
+                    batch.display()
+                );
+                fs::remove_file(&batch)?;
+            } else {
+                batches.push((batch, metadata.len()));
+            }
+        }
+
+        let mut total_bytes = batches.iter().map(|(_, size)| size).sum::<u64>();
+        for (batch, size) in batches {
+            if total_bytes <= self.max_bytes {
+                break;
+            }
+            warn!(
+                "Spool is full. Dropping the oldest batch: {}",
+                batch.display()
+            );
+            fs::remove_file(&batch)?;
+            total_bytes -= size;
+        }
+        Ok(())
+    }
+
+
+    /// Write spooled batches to the database, the oldest first.
+    /// Each batch is removed after it's committed. Replaying is idempotent,
+    /// so a crash between the commit and the removal doesn't duplicate rows.
+    /// Returns the amount of replayed batches
+    #[instrument(skip(self, pg_connection))]
+    pub fn replay(&self, pg_connection: &mut PgConnection) -> Result<usize, Error> {
+        let batches = self.batches().unwrap_or_default();
+        let mut replayed = 0;
+        for batch in batches {
+            let entries = match Self::read(&batch) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    error!(
+                        "Dropping unreadable spooled batch: {}: {error}",
+                        batch.display()
+                    );
+                    fs::remove_file(&batch).unwrap_or_default();
+                    continue;
+                }
+            };
+            match replay_entries(&entries, pg_connection) {
+                Ok(()) => (),
+                Err(error) if !connection_alive(pg_connection) => return Err(error),
+                Err(error) => {
+                    // the batch would block the spool forever. Put it aside:
+                    error!(
+                        "Spooled batch rejected by the database: {}: {error}",
+                        batch.display()
+                    );
+                    fs::rename(&batch, batch.with_extension(REJECTED_EXTENSION))
+                        .unwrap_or_default();
+                    continue;
+                }
+            }
+            if let Err(error) = fs::remove_file(&batch) {
+                error!("Can't remove replayed batch: {}: {error}", batch.display());
+            }
+            replayed += 1;
+        }
+        if replayed > 0 {
+            info!("Replayed {replayed} spooled batch(es).");
+        }
+        Ok(replayed)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{ProcStat, SysStat},
+        postgres::establish_postgres_connection,
+        schema::{proc_stats, sys_stats},
+    };
+    use diesel::{prelude::*, Connection};
+    use std::{env, fs::FileTimes, process};
+
+
+    /// Empty spool in its own temporary directory
+    fn spool(name: &str, max_bytes: u64, max_age: Duration) -> Spool {
+        let dir = env::temp_dir().join(format!("dcollector-spool-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        Spool::open(&dir, max_bytes, max_age).unwrap()
+    }
+
+
+    /// Entries of the host, collected at the time
+    fn entries(host_name: &str, time: SystemTime) -> Vec<Entries> {
+        vec![
+            Entries::Sys(vec![SysStat {
+                time,
+                host_name: host_name.to_string(),
+                load_one: Some(0.5),
+                ..Default::default()
+            }]),
+            Entries::Proc(
+                [1, 2]
+                    .map(|pid| {
+                        ProcStat {
+                            time,
+                            host_name: host_name.to_string(),
+                            pid,
+                            rss: Some(1_024),
+                            ..Default::default()
+                        }
+                    })
+                    .to_vec(),
+            ),
+        ]
+    }
+
+
+    #[test]
+    fn pushes_the_batches_the_oldest_first() {
+        let spool = spool("order", u64::MAX, Duration::from_secs(3_600));
+        assert!(spool.is_empty());
+        for second in 1..=3 {
+            let time = UNIX_EPOCH + Duration::from_secs(second);
+            spool.push(&entries("nas", time)).unwrap();
+        }
+        // empty entries aren't spooled:
+        spool.push(&[Entries::Sys(vec![])]).unwrap();
+
+        let batches = spool.batches().unwrap();
+        assert_eq!(batches.len(), 3);
+        for (second, batch) in (1..=3).zip(&batches) {
+            let time = UNIX_EPOCH + Duration::from_secs(second);
+            assert_eq!(Spool::read(batch).unwrap(), entries("nas", time));
+        }
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+
+    #[test]
+    fn ignores_the_unfinished_batches() {
+        let spool = spool("temporary", u64::MAX, Duration::from_secs(3_600));
+        // left by a crash before the rename:
+        fs::write(spool.dir.join("00000000000000000001-000000.tmp"), "{").unwrap();
+        assert!(spool.is_empty());
+        spool.push(&entries("nas", UNIX_EPOCH)).unwrap();
+        let batches = spool.batches().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0]
+            .extension()
+            .is_some_and(|ext| ext == BATCH_EXTENSION));
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+
+    #[test]
+    fn prunes_the_oldest_batches_above_the_max_size() {
+        let spool = spool("size", u64::MAX, Duration::from_secs(3_600));
+        for second in 1..=4 {
+            spool
+                .push(&entries("nas", UNIX_EPOCH + Duration::from_secs(second)))
+                .unwrap();
+        }
+        let batches = spool.batches().unwrap();
+        let size = fs::metadata(&batches[0]).unwrap().len();
+
+        let spool = Spool {
+            max_bytes: size * 2,
+            ..spool
+        };
+        spool.prune().unwrap();
+        assert_eq!(spool.batches().unwrap(), batches[2..]);
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+
+    #[test]
+    fn prunes_the_batches_above_the_max_age() {
+        let spool = spool("age", u64::MAX, Duration::from_secs(3_600));
+        spool.push(&entries("nas", UNIX_EPOCH)).unwrap();
+        spool
+            .push(&entries("nas", UNIX_EPOCH + Duration::from_secs(1)))
+            .unwrap();
+        let batches = spool.batches().unwrap();
+        let old = SystemTime::now() - Duration::from_secs(7_200);
+        File::options()
+            .write(true)
+            .open(&batches[0])
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(old))
+            .unwrap();
+
+        spool.prune().unwrap();
+        assert_eq!(spool.batches().unwrap(), batches[1..]);
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+
+    /// Replaying the batch again, as after a crash between the commit and the removal,
+    /// leaves a single set of rows. Needs a migrated database, like:
+    /// DCOLLECTOR_TEST_DATABASE_URL="postgres://postgres@localhost/dcollector_test"
+    #[test]
+    fn replays_the_batches_once() {
+        let Ok(database_url) = env::var("DCOLLECTOR_TEST_DATABASE_URL") else {
+            eprintln!("DCOLLECTOR_TEST_DATABASE_URL isn't set. Skipped");
+            return;
+        };
+        let mut pg_connection = establish_postgres_connection(&database_url).unwrap();
+        // nothing is left in the database:
+        pg_connection.begin_test_transaction().unwrap();
+
+        let spool = spool("replay", u64::MAX, Duration::from_secs(3_600));
+        let host_name = format!("spool-test-{}", process::id());
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        spool.push(&entries(&host_name, time)).unwrap();
+        let batch = spool.batches().unwrap().remove(0);
+        let copy = fs::read(&batch).unwrap();
+
+        assert_eq!(spool.replay(&mut pg_connection), Ok(1));
+        assert!(spool.is_empty());
+        fs::write(&batch, copy).unwrap();
+        assert_eq!(spool.replay(&mut pg_connection), Ok(1));
+        assert!(spool.is_empty());
+
+        let sys_rows = sys_stats::table
+            .filter(sys_stats::host_name.eq(&host_name))
+            .count()
+            .get_result::<i64>(&mut pg_connection)
+            .unwrap();
+        let proc_rows = proc_stats::table
+            .filter(proc_stats::host_name.eq(&host_name))
+            .count()
+            .get_result::<i64>(&mut pg_connection)
+            .unwrap();
+        assert_eq!((sys_rows, proc_rows), (1, 2));
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+}
@@ -1,36 +1,34 @@
-use crate::*;
+use crate::{error::Error, *};
 use serde_json::Value;
 use std::{
     process::{Command, Stdio},
-    thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use sysinfo::{CpuExt, NetworkExt, NetworksExt, ProcessExt, System, SystemExt};
+use sysinfo::{CpuExt, NetworkExt, NetworksExt, PidExt, ProcessExt, System, SystemExt};
 
 
-/// Read and fill NetStat entry
-#[instrument]
-pub fn net_stats_entries(sys: &System) -> Vec<NetStat> {
+/// Read and fill NetStat entry. All entries share the collection time
+#[instrument(skip(sys, time))]
+pub fn net_stats_entries(sys: &System, host_name: &str, time: SystemTime) -> Vec<NetStat> {
     sys.networks()
         .iter()
         .map(|(interface_name, network)| {
-            // Sleep 10ms to avoid time PK duplication with a lot of network devices running in the system:
-            thread::sleep(Duration::from_millis(10));
             NetStat {
-                time: SystemTime::now(),
-                netdev: Some(String::from(interface_name)),
-                packets_received: Some(network.packets_received() as i64),
-                total_packets_received: Some(network.total_packets_received() as i64),
-                packets_transmitted: Some(network.packets_transmitted() as i64),
-                total_packets_transmitted: Some(network.total_packets_transmitted() as i64),
-                received: Some(network.received() as i64),
-                total_received: Some(network.total_received() as i64),
-                transmitted: Some(network.transmitted() as i64),
-                total_transmitted: Some(network.total_transmitted() as i64),
-                transmitted_errors: Some(network.errors_on_transmitted() as i64),
-                transmitted_total_errors: Some(network.total_errors_on_transmitted() as i64),
-                received_errors: Some(network.errors_on_received() as i64),
-                received_total_errors: Some(network.total_errors_on_received() as i64),
+                time,
+                host_name: host_name.to_string(),
+                netdev: String::from(interface_name),
+                packets_received: network.packets_received() as i64,
+                total_packets_received: network.total_packets_received() as i64,
+                packets_transmitted: network.packets_transmitted() as i64,
+                total_packets_transmitted: network.total_packets_transmitted() as i64,
+                received: network.received() as i64,
+                total_received: network.total_received() as i64,
+                transmitted: network.transmitted() as i64,
+                total_transmitted: network.total_transmitted() as i64,
+                transmitted_errors: network.errors_on_transmitted() as i64,
+                transmitted_total_errors: network.total_errors_on_transmitted() as i64,
+                received_errors: network.errors_on_received() as i64,
+                received_total_errors: network.total_errors_on_received() as i64,
             }
         })
         .collect()
@@ -38,19 +36,19 @@ pub fn net_stats_entries(sys: &System) -> Vec<NetStat> {
 
 
 /// Read and fill SysStat entry with system stats
-#[instrument]
-pub fn sys_stats_entry(sys: &System) -> SysStat {
+#[instrument(skip(sys, time))]
+pub fn sys_stats_entry(sys: &System, host_name: &str, time: SystemTime) -> SysStat {
     let cpu_cores = sys.physical_core_count().unwrap_or(1);
     let cpu_usage =
         sys.cpus().iter().map(|cpu| cpu.cpu_usage()).sum::<f32>() / cpu_cores as f32;
     let load_avg = sys.load_average();
 
     SysStat {
-        time: SystemTime::now(),
+        time,
         name: sys.name(),
         kernel_version: sys.kernel_version(),
         os_version: sys.os_version(),
-        host_name: sys.host_name(),
+        host_name: host_name.to_string(),
 
         cpu_usage: Some(cpu_usage),
         load_one: Some(load_avg.one),
@@ -67,15 +65,13 @@ pub fn sys_stats_entry(sys: &System) -> SysStat {
 }
 
 
-/// Read and fill SysStat entry with stats from user processes
-#[instrument]
-pub fn sys_process_entries(sys: &System) -> Vec<ProcStat> {
+/// Read and fill SysStat entry with stats from user processes.
+/// All entries share the collection time
+#[instrument(skip(sys, time))]
+pub fn sys_process_entries(sys: &System, host_name: &str, time: SystemTime) -> Vec<ProcStat> {
     sys.processes()
         .values()
         .map(|process| {
-            // Sleep 10ms to avoid time PK duplication with a lot of processes running in the system:
-            thread::sleep(Duration::from_millis(10));
-
             let maybe_time = UNIX_EPOCH + Duration::from_secs(process.start_time());
             let start_time = if maybe_time == UNIX_EPOCH {
                 // if the time is the same as UNIX_EPOCH it means that the process is short lived
@@ -94,8 +90,9 @@ pub fn sys_process_entries(sys: &System) -> Vec<ProcStat> {
             let disk_usage = process.disk_usage();
 
             ProcStat {
-                time: SystemTime::now(),
-                host_name: sys.host_name(),
+                time,
+                host_name: host_name.to_string(),
+                pid: process.pid().as_u32() as i32,
                 exe: Some(exe),
                 cmd: Some(cmd),
                 name: Some(name),
@@ -115,104 +112,137 @@ pub fn sys_process_entries(sys: &System) -> Vec<ProcStat> {
 
 #[instrument]
 /// Reads disks from sysctl on FreeBSD
-fn read_devices_list() -> Vec<String> {
-    match Command::new("sysctl")
+fn read_devices_list() -> Result<Vec<String>, Error> {
+    let output = Command::new("sysctl")
         .args(["-n", "kern.disks"])
         .stdin(Stdio::null())
         .output()
-    {
-        Ok(output) => {
-            let sysctl_disks_raw = String::from_utf8_lossy(&output.stdout).to_string();
-            sysctl_disks_raw
-                .split_whitespace()
-                .filter_map(|dsk| {
-                    if !dsk.starts_with("flash") && !dsk.starts_with("mmc") {
-                        Some(format!("/dev/{dsk}"))
-                    } else {
-                        None
-                    }
-                })
-                .collect()
+        .map_err(|error| Error::DiskList(format!("sysctl: {error}")))?;
+    if !output.status.success() {
+        return Err(Error::DiskList(format!(
+            "sysctl: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .filter_map(|dsk| {
+            if !dsk.starts_with("flash") && !dsk.starts_with("mmc") {
+                Some(format!("/dev/{dsk}"))
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+
+/// Messages reported by smartctl in its JSON output
+fn smartctl_messages(smartctl_obj: &Value) -> String {
+    smartctl_obj["smartctl"]["messages"]
+        .as_array()
+        .map(|messages| {
+            messages
+                .iter()
+                .filter_map(|message| message["string"].as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
+
+
+#[instrument(skip(time))]
+/// Read and fill DiskStat entry with stats of the disk device.
+/// Devices without ATA SMART attributes (like NVMe) only report the temperature
+fn disk_stat(disk_device: &str, host_name: &str, time: SystemTime) -> Result<DiskStat, Error> {
+    let smartctl_error = |reason: String| {
+        Error::Smartctl {
+            device: disk_device.to_string(),
+            reason,
+        }
+    };
+    let output = Command::new("smartctl")
+        .args(["-j", "-f", "brief", "-A", disk_device])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| smartctl_error(error.to_string()))?;
+
+    let smartctl_obj: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|error| smartctl_error(format!("invalid JSON output: {error}")))?;
+    trace!("smartctl command successful, the parsed object: {smartctl_obj:#?}");
+
+    // bits 0 and 1 of the exit status: invalid command line, or the device can't be opened.
+    // Other bits report the disk health, and don't prevent reading the attributes
+    let exit_status = output.status.code().unwrap_or(1);
+    if exit_status & 0b11 != 0 {
+        return Err(smartctl_error(format!(
+            "exit status {exit_status}: {}",
+            smartctl_messages(&smartctl_obj)
+        )));
+    }
+
+    let mut disk_stat = DiskStat {
+        time,
+        name: disk_device.to_string(),
+        host_name: host_name.to_string(),
+        temperature: smartctl_obj["temperature"]["current"].as_f64(),
+        ..DiskStat::default()
+    };
+
+    let attributes = smartctl_obj["ata_smart_attributes"]["table"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for attr in attributes {
+        let value = Some(attr["raw"]["value"].as_i64().unwrap_or(0));
+        if attr["name"] == "Seek_Error_Rate" {
+            // seek_error_rate => Seek_Error_Rate
+            disk_stat.seek_error_rate = value;
         }
-        Err(_er) => {
-            vec![]
+
+        if attr["name"] == "Throughput_Performance" {
+            // throughput => Throughput_Performance
+            disk_stat.throughput = value;
+        }
+
+        if attr["name"] == "Raw_Read_Error_Rate" {
+            // read_error_rate => Raw_Read_Error_Rate
+            disk_stat.read_error_rate = value;
+        }
+
+        if attr["name"] == "UDMA_CRC_Error_Count" {
+            // crc_errors => UDMA_CRC_Error_Count
+            disk_stat.crc_errors = value;
+        }
+
+        if attr["name"] == "Seek_Time_Performance" {
+            // seek_time => Seek_Time_Performance
+            disk_stat.seek_time = value;
         }
     }
+    Ok(disk_stat)
 }
 
 
-#[instrument]
-/// Read and fill DiskStat entry with stats from the disks
-pub fn disk_stats_entry(sys: &System) -> Vec<DiskStat> {
-    read_devices_list()
-        .into_iter()
-        .filter_map(|disk_device| {
-            let the_command = Command::new("smartctl")
-                .args(["-j", "-f", "brief", "-A", &disk_device])
-                .stdin(Stdio::null())
-                .output();
-
-            match the_command {
-                Ok(output) => {
-                    let data = String::from_utf8_lossy(&output.stdout).to_string();
-                    let smartctl_obj: Value =
-                        serde_json::from_str(data.as_str()).unwrap_or_default();
-                    trace!(
-                        "smartctl command successful, the parsed object: {smartctl_obj:#?}"
-                    );
-
-                    let mut disk_stat = DiskStat {
-                        name: Some(disk_device),
-                        host_name: sys.host_name(),
-                        temperature: Some(
-                            smartctl_obj["temperature"]["current"]
-                                .as_f64()
-                                .unwrap_or(0.0),
-                        ),
-                        ..DiskStat::default()
-                    };
-
-                    for attr in smartctl_obj["ata_smart_attributes"]["table"]
-                        .as_array()
-                        .expect("ata_smart_attributes.table should be retrievable")
-                    {
-                        if attr["name"] == "Seek_Error_Rate" {
-                            // seek_error_rate => Seek_Error_Rate
-                            disk_stat.seek_error_rate =
-                                Some(attr["raw"]["value"].as_i64().unwrap_or(0));
-                        }
-
-                        if attr["name"] == "Throughput_Performance" {
-                            // throughput => Throughput_Performance
-                            disk_stat.throughput =
-                                Some(attr["raw"]["value"].as_i64().unwrap_or(0));
-                        }
-
-                        if attr["name"] == "Raw_Read_Error_Rate" {
-                            // read_error_rate => Raw_Read_Error_Rate
-                            disk_stat.read_error_rate =
-                                Some(attr["raw"]["value"].as_i64().unwrap_or(0));
-                        }
-
-                        if attr["name"] == "UDMA_CRC_Error_Count" {
-                            // crc_errors => UDMA_CRC_Error_Count
-                            disk_stat.crc_errors =
-                                Some(attr["raw"]["value"].as_i64().unwrap_or(0));
-                        }
-
-                        if attr["name"] == "Seek_Time_Performance" {
-                            // seek_time => Seek_Time_Performance
-                            disk_stat.seek_time =
-                                Some(attr["raw"]["value"].as_i64().unwrap_or(0));
-                        }
-                    }
-                    Some(disk_stat)
-                }
-                Err(err) => {
-                    error!("smartctl failed with: {err}");
-                    None
-                }
-            }
+#[instrument(skip(time))]
+/// Read and fill DiskStat entry with stats from the disks.
+/// All entries share the collection time. A failing device doesn't prevent reading the others
+pub fn disk_stats_entry(host_name: &str, time: SystemTime) -> Result<Vec<DiskStat>, Error> {
+    let mut entries = vec![];
+    let mut errors = vec![];
+    for disk_device in read_devices_list()? {
+        match disk_stat(&disk_device, host_name, time) {
+            Ok(disk_stat) => entries.push(disk_stat),
+            Err(error) => errors.push(error),
+        }
+    }
+    if errors.is_empty() {
+        Ok(entries)
+    } else {
+        Err(Error::Partial {
+            entries: Entries::Disk(entries),
+            errors,
         })
-        .collect()
+    }
 }
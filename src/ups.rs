@@ -1,89 +1,85 @@
-use crate::*;
-use nut_client::{blocking::Connection as NutConnection, ConfigBuilder, Variable};
-use std::{convert::TryInto, env, time::SystemTime};
+use crate::{config::UpsConfig, error::Error, models::DefaultWithTime, *};
+use nut_client::{blocking::Connection as NutConnection, ConfigBuilder};
+use std::{convert::TryInto, time::SystemTime};
 
 
-/// Read and fill NutStat entry with UPS stats
-pub fn ups_stats_entry() -> UpsStat {
-    let nut_host = env::var("NUT_HOST").unwrap_or_else(|_| "vks0".to_string());
-    let nut_ups = env::var("NUT_UPS").unwrap_or_else(|_| "eta".to_string());
+/// Read and fill NutStat entry with UPS stats.
+/// Variables missing on the NUT server are reported as a partial failure,
+/// along with the entry holding the remaining ones
+#[instrument(skip(time))]
+pub fn ups_stats_entry(
+    config: &UpsConfig,
+    host_name: &str,
+    time: SystemTime,
+) -> Result<UpsStat, Error> {
+    let nut_host = &config.host;
+    let nut_ups = &config.name;
+    let ups = format!("{nut_ups}@{nut_host}:{}", config.port);
+    let host = (nut_host.clone(), config.port)
+        .try_into()
+        .map_err(|source| {
+            Error::NutConnection {
+                ups: ups.to_owned(),
+                source,
+            }
+        })?;
     let nut_config = ConfigBuilder::new()
-        .with_host((nut_host.clone(), 3493).try_into().unwrap_or_default())
+        .with_host(host)
         .with_debug(false) // Turn this on for debugging network chatter
         .build();
 
-    match NutConnection::new(&nut_config) {
-        Ok(mut nut_connection) => {
-            UpsStat {
-                time: SystemTime::now(),
-                model: Some(
-                    nut_connection
-                        .get_var(&nut_ups, "ups.model")
-                        .unwrap_or_else(|err| {
-                            error!("Error: No UPS model available?: {}", err);
-                            Variable::Other((String::from("ups.model"), String::new()))
-                        })
-                        .value(),
-                ),
-                status: Some(
-                    nut_connection
-                        .get_var(&nut_ups, "ups.status")
-                        .unwrap_or_else(|err| {
-                            error!("Error: No UPS status available?: {}", err);
-                            Variable::Other((String::from("ups.status"), String::new()))
-                        })
-                        .value(),
-                ),
-                load: nut_connection
-                    .get_var(&nut_ups, "ups.load")
-                    .unwrap_or_else(|err| {
-                        error!("Error: No UPS load available?: {}", err);
-                        Variable::Other((String::from("ups.load"), String::new()))
-                    })
-                    .value()
-                    .parse::<i32>()
-                    .ok(),
-                input_frequency: nut_connection
-                    .get_var(&nut_ups, "input.frequency")
-                    .unwrap_or_else(|err| {
-                        debug!("Error: No UPS input frequency available?: {}", err);
-                        Variable::Other((String::from("input.frequency"), String::new()))
-                    })
-                    .value()
-                    .parse::<f64>()
-                    .ok(),
-                input_voltage: nut_connection
-                    .get_var(&nut_ups, "input.voltage")
-                    .unwrap_or_else(|err| {
-                        error!("Error: No UPS input voltage available?: {}", err);
-                        Variable::Other((String::from("input.voltage"), String::new()))
-                    })
-                    .value()
-                    .parse::<f64>()
-                    .ok(),
-                battery_charge: nut_connection
-                    .get_var(&nut_ups, "battery.charge")
-                    .unwrap_or_else(|err| {
-                        error!("Error: No UPS battery charge available?: {}", err);
-                        Variable::Other((String::from("battery.charge"), String::new()))
-                    })
-                    .value()
-                    .parse::<i32>()
-                    .ok(),
-                battery_voltage: nut_connection
-                    .get_var(&nut_ups, "battery.voltage")
-                    .unwrap_or_else(|err| {
-                        debug!("Error: No UPS battery voltage available?: {}", err);
-                        Variable::Other((String::from("battery.voltage"), String::new()))
-                    })
-                    .value()
-                    .parse::<f64>()
-                    .ok(),
-            }
+    let mut nut_connection = NutConnection::new(&nut_config).map_err(|source| {
+        Error::NutConnection {
+            ups: ups.to_owned(),
+            source,
         }
-        Err(error) => {
-            debug!("Failed connecting to UPS: {nut_ups}@{nut_host}. Error: {error}");
-            UpsStat::default()
+    })?;
+
+    let mut errors = vec![];
+    // required variables are reported when missing, optional ones are skipped silently:
+    let mut variable = |name: &'static str, required: bool| {
+        match nut_connection.get_var(nut_ups, name) {
+            Ok(variable) => Some(variable.value()),
+            Err(source) => {
+                debug!("No UPS {name} available: {source}");
+                if required {
+                    errors.push(Error::NutVariable {
+                        ups: ups.to_owned(),
+                        variable: name,
+                        source,
+                    });
+                }
+                None
+            }
         }
+    };
+    let entry = UpsStat {
+        time,
+        host_name: host_name.to_string(),
+        model: variable("ups.model", true),
+        status: variable("ups.status", true),
+        load: variable("ups.load", true).and_then(|value| value.parse::<i32>().ok()),
+        input_frequency: variable("input.frequency", false)
+            .and_then(|value| value.parse::<f64>().ok()),
+        input_voltage: variable("input.voltage", true)
+            .and_then(|value| value.parse::<f64>().ok()),
+        battery_charge: variable("battery.charge", true)
+            .and_then(|value| value.parse::<i32>().ok()),
+        battery_voltage: variable("battery.voltage", false)
+            .and_then(|value| value.parse::<f64>().ok()),
+    };
+
+    if errors.is_empty() {
+        return Ok(entry);
     }
+    // an entry without any of the variables isn't worth storing:
+    let entries = if entry == UpsStat::default_skip_time(&entry) {
+        vec![]
+    } else {
+        vec![entry]
+    };
+    Err(Error::Partial {
+        entries: Entries::Ups(entries),
+        errors,
+    })
 }